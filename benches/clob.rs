@@ -0,0 +1,98 @@
+// criterion version of the /bench/clob numbers: cargo bench --bench clob. the book is plain rust with no state or
+// fhe behind it, so it is pulled in by path instead of through the server
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+
+#[allow(dead_code)]
+#[path = "../src/orderbook/clob.rs"]
+mod clob;
+
+use clob::{CLOB, OrderOptions, TimeInForce};
+
+const MID_PRICE: u64 = 100_000;
+const BOOK_LEVELS: u64 = 100;
+const DEPTHS: [usize; 3] = [100, 1_000, 10_000];
+
+fn resting_price(is_buy: bool, rng: &mut impl Rng) -> u64 {
+    let offset = rng.gen_range(1..=BOOK_LEVELS);
+    if is_buy { MID_PRICE - offset } else { MID_PRICE + offset }
+}
+
+fn seeded_book(depth: usize, rng: &mut impl Rng) -> CLOB {
+    let mut book = CLOB::new();
+    for _ in 0..depth {
+        book.add_order(1, Some(resting_price(true, rng)), rng.gen_range(1..1_000), true, OrderOptions::default()).unwrap();
+        book.add_order(2, Some(resting_price(false, rng)), rng.gen_range(1..1_000), false, OrderOptions::default()).unwrap();
+    }
+    book
+}
+
+// every routine puts the book back the way it found it outside the timed part, so each depth is measured at depth
+fn clob(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let ioc = OrderOptions { time_in_force: TimeInForce::Ioc, ..Default::default() };
+
+    let mut group = c.benchmark_group("add");
+    for depth in DEPTHS {
+        let mut book = seeded_book(depth, &mut rng);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for i in 0..iters {
+                    let is_buy = i % 2 == 0;
+                    let price = resting_price(is_buy, &mut rng);
+                    let start = Instant::now();
+                    let result = black_box(book.add_order(3, Some(price), 500, is_buy, OrderOptions::default()).unwrap());
+                    elapsed += start.elapsed();
+                    book.cancel_order(result.order_id);
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("cancel");
+    for depth in DEPTHS {
+        let mut book = seeded_book(depth, &mut rng);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for i in 0..iters {
+                    let is_buy = i % 2 == 0;
+                    let order_id = book.add_order(3, Some(resting_price(is_buy, &mut rng)), 500, is_buy, OrderOptions::default()).unwrap().order_id;
+                    let start = Instant::now();
+                    black_box(book.cancel_order(order_id));
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("match");
+    for depth in DEPTHS {
+        let mut book = seeded_book(depth, &mut rng);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for i in 0..iters {
+                    let start = Instant::now();
+                    let result = black_box(book.add_order(4, None, 500, i % 2 == 0, ioc).unwrap());
+                    elapsed += start.elapsed();
+                    for fill in result.fills.iter().filter(|fill| fill.maker_remaining == 0) {
+                        book.add_order(fill.maker, Some(fill.price), rng.gen_range(1..1_000), !fill.taker_is_buy, OrderOptions::default()).unwrap();
+                    }
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, clob);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+#!/bin/bash
+
+echo "Circuit timings, client key encryption vs plaintext operands..."
+curl -X POST http://localhost:3000/bench/circuits \
+  -H "Content-Type: application/json" \
+  -d '{
+    "iterations": 10
+  }'
+
+echo -e "\n\nDark book matching cost vs depth..."
+curl -X POST http://localhost:3000/bench/dark_book \
+  -H "Content-Type: application/json" \
+  -d '{
+    "depths": [1, 2, 4, 8, 16],
+    "iterations": 3
+  }'
+
+echo -e "\n\nOrder book add/cancel/match throughput vs depth..."
+curl -X POST http://localhost:3000/bench/clob \
+  -H "Content-Type: application/json" \
+  -d '{
+    "depths": [100, 1000, 10000, 100000],
+    "operations": 10000
+  }'
+
+echo -e "\n\nDone!"
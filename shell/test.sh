@@ -1,31 +1,104 @@
 #!/bin/bash
 
+# operator routes (/mark_price, /gc) need the server started with the same ADMIN_TOKEN
+ADMIN_TOKEN=${ADMIN_TOKEN:-test-admin-token}
+
 echo "Creating user..."
-curl -X POST http://localhost:3000/create_user \
+CREATE_USER=$(curl -s -X POST http://localhost:3000/create_user \
   -H "Content-Type: application/json" \
   -d '{
     "user_id": 123   
-  }'
+  }')
+echo "$CREATE_USER"
+USER_TOKEN=$(echo "$CREATE_USER" | sed -n 's/.*"token":"\([^"]*\)".*/\1/p')
 
 echo -e "\n\nDepositing funds..."
 curl -X POST http://localhost:3000/deposit \
   -H "Content-Type: application/json" \
   -d '{
     "user_id": 123,  
-    "amount": 10000,  
-    "key": [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31,32]
+    "amount": 10000
+  }'
+
+echo -e "\n\nCreating a maker..."
+curl -X POST http://localhost:3000/create_user \
+  -H "Content-Type: application/json" \
+  -d '{
+    "user_id": 456
+  }'
+
+echo -e "\n\nDepositing maker funds..."
+curl -X POST http://localhost:3000/deposit \
+  -H "Content-Type: application/json" \
+  -d '{
+    "user_id": 456,
+    "amount": 10000
+  }'
+
+echo -e "\n\nMaker resting a short at 50000..."
+curl -X POST http://localhost:3000/open_position \
+  -H "Content-Type: application/json" \
+  -d '{
+    "user_id": 456,
+    "direction": false,
+    "price": "50000.00",
+    "notional": 1000,
+    "leverage": 10
   }'
 
-echo -e "\n\nOpening position..."
+echo -e "\n\nOpening position (market buy against the maker)..."
 curl -X POST http://localhost:3000/open_position \
   -H "Content-Type: application/json" \
   -d '{
     "user_id": 123,  
     "direction": true,
-    "entry_price": 50000,
     "notional": 1000,
-    "leverage": 10,
-    "initial_margin": 100
+    "leverage": 10
   }'
 
+echo -e "\n\nSetting mark price..."
+curl -X POST http://localhost:3000/mark_price \
+  -H "Content-Type: application/json" \
+  -H "x-admin-token: $ADMIN_TOKEN" \
+  -d '{
+    "market": 0,
+    "price": "50000.00"
+  }'
+
+echo -e "\n\nAttaching stop loss and take profit..."
+curl -X POST http://localhost:3000/set_triggers \
+  -H "Content-Type: application/json" \
+  -d '{
+    "user_id": 123,
+    "position_id": 0,
+    "stop_loss": "45000.00",
+    "take_profit": "60000.00"
+  }'
+
+echo -e "\n\nAccount equity..."
+curl -X POST http://localhost:3000/account_equity \
+  -H "Content-Type: application/json" \
+  -H "x-user-token: $USER_TOKEN" \
+  -d '{
+    "user_id": 123
+  }'
+
+echo -e "\n\nInsurance fund balance..."
+curl http://localhost:3000/insurance_fund
+
+echo -e "\n\nLiquidating position (mark below liquidation price)..."
+curl -X POST http://localhost:3000/liquidate_long \
+  -H "Content-Type: application/json" \
+  -d '{
+    "position_id": 0,
+    "mark_price": 100
+  }'
+
+echo -e "\n\nFHE pool metrics..."
+curl http://localhost:3000/metrics/fhe_pool
+
+echo -e "\n\nCollecting unreferenced ciphertexts..."
+curl -X POST http://localhost:3000/gc \
+  -H "x-admin-token: $ADMIN_TOKEN"
+
 echo -e "\n\nDone!"
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::time::Instant;
+use serde::Serialize;
+use tfhe::FheUint64;
+use tfhe::prelude::*;
+use crate::AppState;
+use crate::fhe::pool::PoolError;
+use crate::orderbook::dark::match_encrypted;
+
+pub const MAX_CIRCUIT_BENCH_ITERATIONS: u32 = 100; // three circuits run twice per iteration on one worker
+pub const MAX_DARK_BOOK_BENCH_ITERATIONS: u32 = 10;
+pub const MAX_DARK_BOOK_BENCH_DEPTH: usize = 64; // every resting order is encrypted up front and compared each iteration
+pub const MAX_BENCH_DEPTHS: usize = 8;
+
+#[derive(Serialize)]
+pub struct CircuitTiming {
+    pub circuit: String,
+    pub iterations: u32,
+    pub client_key_encrypt_ms: f64, // before: public operands encrypted with the ClientKey
+    pub plaintext_operand_ms: f64,  // after: scalar ops / trivial encryptions
+}
+
+#[derive(Serialize)]
+pub struct DarkBookTiming {
+    pub depth: usize,
+    pub iterations: u32,
+    pub match_ms: f64,
+    pub per_order_ms: f64,
+}
+
+fn average_ms<F: FnMut()>(iterations: u32, mut f: F) -> f64 {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed().as_secs_f64() * 1_000.0 / iterations.max(1) as f64
+}
+
+// runs the old and new version of every circuit that used to encrypt public values, on one pool worker so the
+// numbers dont include queueing. the timings are per call averages
+pub async fn circuit_timings(state: &AppState, iterations: u32) -> Result<Vec<CircuitTiming>, PoolError> {
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        let ck = &*client_key;
+        let notional = 1_000u64;
+        let opening_fee = 10u64;
+        let mark_price = 950u64;
+        let delta = 5u64;
+        let margin = FheUint64::encrypt(100u64, ck);
+        let balance = FheUint64::encrypt(10_000u64, ck);
+        let liqudation_price = FheUint64::encrypt(890u64, ck);
+
+        let health_check_long = CircuitTiming {
+            circuit: "health_check_long".to_string(),
+            iterations,
+            client_key_encrypt_ms: average_ms(iterations, || {
+                let mark_ciphertext = FheUint64::encrypt(mark_price, ck);
+                let _: bool = mark_ciphertext.ge(&liqudation_price).decrypt(ck);
+            }),
+            plaintext_operand_ms: average_ms(iterations, || {
+                let _: bool = liqudation_price.le(mark_price).decrypt(ck);
+            }),
+        };
+
+        let open_position = CircuitTiming {
+            circuit: "open_position".to_string(),
+            iterations,
+            client_key_encrypt_ms: average_ms(iterations, || {
+                let opening_fee_ciphertext = FheUint64::encrypt(opening_fee, ck);
+                let notional_ciphertext = FheUint64::encrypt(notional, ck);
+                let _ = &notional_ciphertext - &opening_fee_ciphertext - &margin;
+                let _ = &balance - &margin - &opening_fee_ciphertext;
+            }),
+            plaintext_operand_ms: average_ms(iterations, || {
+                let _ = FheUint64::encrypt_trivial(notional - opening_fee) - &margin;
+                let _ = &balance - &margin - opening_fee;
+            }),
+        };
+
+        let funding_rate_long_pay_short = CircuitTiming {
+            circuit: "funding_rate_long_pay_short".to_string(),
+            iterations,
+            client_key_encrypt_ms: average_ms(iterations, || {
+                let encrypted_delta = FheUint64::encrypt(delta, ck);
+                let _ = &liqudation_price - &encrypted_delta;
+            }),
+            plaintext_operand_ms: average_ms(iterations, || {
+                let _ = &liqudation_price - delta;
+            }),
+        };
+
+        vec![health_check_long, open_position, funding_rate_long_pay_short]
+    }).await
+}
+
+// cost of one dark book match against books of increasing depth. every resting order costs a comparison, a min and
+// a select whether it crosses or not, so this should come out linear in depth
+pub async fn dark_book_timings(state: &AppState, depths: Vec<usize>, iterations: u32) -> Result<Vec<DarkBookTiming>, PoolError> {
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        let ck = &*client_key;
+        let taker_price = FheUint64::encrypt(50_000u64, ck);
+        let taker_size = FheUint64::encrypt(1_000u64, ck);
+        depths.into_iter().map(|depth| {
+            // half the book crosses, so the select has both outcomes to pick from
+            let makers: Vec<(FheUint64, FheUint64)> = (0..depth)
+                .map(|i| (FheUint64::encrypt(49_990u64 + (i as u64 % 2) * 20, ck), FheUint64::encrypt(100u64, ck)))
+                .collect();
+            let match_ms = average_ms(iterations, || {
+                let _ = match_encrypted(true, &taker_price, &taker_size, &makers);
+            });
+            DarkBookTiming { depth, iterations, match_ms, per_order_ms: match_ms / depth.max(1) as f64 }
+        }).collect()
+    }).await
+}
@@ -1,31 +1,69 @@
 use crate::liqudation::users::User;
 use crate::State;
 use tfhe::{
+    ClientKey,
+    FheBool,
+    FheUint8,
     FheUint64,
+    FheUint128,
     CompressedCiphertextListBuilder,
-    set_server_key,
 };
 use tfhe::prelude::*;
 use crate::AppState;
 use crate::liqudation::users::Position;
 use crate::liqudation::handlers::{_encrypt_helper, _encrypt_from_FheUint64};
 use crate::liqudation::cache::Ciphertext;
+use crate::fhe::handle::{CiphertextHandle, FheType};
+use crate::fhe::pool::PoolError;
+use crate::market::decimal::{bps, mul_div, Rounding};
+use crate::orderbook::dark::{EncryptedOrder, DarkFill, match_encrypted};
+use crate::orderbook::sealed::{SealedOrder, sealed_clearing_price, sealed_fills};
+use crate::market::open_interest::Skew;
+use crate::orderbook::engine::settle_dark_fills;
 
-pub async fn deposit_circuit(state: &AppState, user_id: u128, amount: u64, key: [u8;32]) -> Result<(), Box<dyn std::error::Error>> {
-    set_server_key((*state.server_key).clone());
+// amounts are raw collateral units and prices raw units of the market's price scale (see market::decimal).
+// every rounding below goes the protocol's way: fees, penalties and losses round up, profits round down and
+// liquidation prices round towards the entry price
+pub const OPENING_FEE_BPS: u64 = 100; // 1% of notional, goes to the insurance fund
+pub const LIQUIDATION_PENALTY_BPS: u64 = 100; // 1% of notional goes to the insurance fund on liquidation
+pub const MAINTENANCE_MARGIN_BPS: u64 = 50; // equity has to stay above 0.5% of notional
+pub const LIQUIDATION_STEP_BPS: u64 = 2_500; // each partial liquidation step closes 25% of the position
+
+pub struct PositionPnl {
+    pub position_id: u128,
+    pub market: u32,
+    pub mark_price: u64, // the mark of the positions own market it was valued at
+    pub profit: FheUint64,
+    pub loss: FheUint64,
+}
+
+pub enum LiquidationOutcome {
+    Healthy,
+    Partial { closed_notional: u64, remaining_notional: u64 },
+    Full { deleveraged_positions: Vec<u128> },
+}
+
+// the balance handle is always allocated here on the first deposit, never taken from the client, so an account can
+// only ever point at a ciphertext it owns
+pub async fn deposit_circuit(state: &AppState, user_id: u128, amount: u64) -> Result<(), Box<dyn std::error::Error>> {
     println!("Attempting to deposit");
-    let value = FheUint64::encrypt(amount, &*state.client_key); // generates the actual ciphertext
-    if state.user_cache.lock().await.get_user(user_id).unwrap().balance == [0;32] { // if the user has no balance yet 
-        state.ciphertext_cache.lock().await.add_ciphertext(key, user_id, value); //adds this ciphertext 
-        state.user_cache.lock().await.update_balance(user_id, key);
-        // TODO thread to write this to db 
-    } else { // if they already have a balance then we need to add the new amount to the existing balance
-        let current_balance_key = state.user_cache.lock().await.get_user(user_id).unwrap().balance;
-        let current_balance_ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(current_balance_key).unwrap().ciphertext.clone();
-        let new_balance_ciphertext = &current_balance_ciphertext + &value;
+    let client_key = state.client_key.clone();
+    let value = state.fhe_pool.run(move || FheUint64::encrypt(amount, &*client_key)).await?; // generates the actual ciphertext
+    let user = state.user_cache.get_user(user_id).ok_or("User not found")?;
+    let mut user = user.lock().await; // held until the new balance is written so concurrent deposits cant lose an update
+    if let Some(current_balance_key) = user.balance { // if they already have a balance then we need to add the new amount to the existing balance
+        let current_balance_ciphertext = state.ciphertext_cache.get_u64(current_balance_key).ok_or("Balance ciphertext not found")?;
+        let new_balance_ciphertext = state.fhe_pool.run(move || &current_balance_ciphertext + &value).await?;
         
         // Update the ciphertext in the cache
-        state.ciphertext_cache.lock().await.update_ciphertext(current_balance_key, user_id, new_balance_ciphertext);
+        state.ciphertext_cache.update_ciphertext(current_balance_key, user_id, new_balance_ciphertext);
+        // TODO thread to write this to db 
+    } else { // if the user has no balance yet 
+        let key = CiphertextHandle::new(FheType::U64, state.key_set);
+        if !state.ciphertext_cache.add_ciphertext(key, user_id, value) { //adds this ciphertext 
+            return Err("Balance ciphertext could not be stored".into());
+        }
+        user.update_balance(key);
         // TODO thread to write this to db 
     }
     println!("Deposit successful");
@@ -33,34 +71,28 @@ pub async fn deposit_circuit(state: &AppState, user_id: u128, amount: u64, key:
 }   
 
 
-async fn withdraw_circuit(state: &AppState, user_id: u128, amount: u64, key:[u8;32]) -> Result<(), Box<dyn std::error::Error>> {
-    set_server_key((*state.server_key).clone());
+async fn withdraw_circuit(state: &AppState, user_id: u128, amount: u64, key:CiphertextHandle) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
 pub async fn open_position_circuit(
     state: &AppState,
     user_id: u128,
+    market: u32,
     entry_price: u64,
     direction: bool,
     notional: u64,
-    leverage_ciphertext: FheUint64,
+    leverage_ciphertext: FheUint8,
     initial_margin_ciphertext: FheUint64,
-    initial_margin_key: [u8;32],
-    leverage_key: [u8;32],
-) -> Result<(), Box<dyn std::error::Error>> { // chage this back to position after i finish testing ******
+    initial_margin_key: CiphertextHandle,
+    leverage_key: CiphertextHandle,
+) -> Result<u128, Box<dyn std::error::Error>> { // returns the new positions id
     let start_time = std::time::Instant::now();
     println!("[{}ms] Opening position...", start_time.elapsed().as_millis());
+    let user = state.user_cache.get_user(user_id).ok_or("User not found")?;
+    let mut user = user.lock().await; // the whole open is one transaction on the users account
     
-    let opening_fee = (notional as f64 * 0.01).ceil() as u64; // for lets assume this is the same as margin as well, ill use a constant later
-    set_server_key((*state.server_key).clone());
-    println!("[{}ms] Server key set", start_time.elapsed().as_millis());
-    
-    let opening_fee_ciphertext = FheUint64::encrypt(opening_fee, &*state.client_key); // use some sort of looh up later for these to avoid encryption
-    println!("[{}ms] Opening fee encrypted", start_time.elapsed().as_millis());
-    
-    let notional_ciphertext = FheUint64::encrypt(notional, &*state.client_key);
-    println!("[{}ms] Notional encrypted", start_time.elapsed().as_millis());
+    let opening_fee = opening_fee(notional); // for lets assume this is the same as margin as well, ill use a constant later
     
     // let valid_notional = notional_ciphertext.eq(&initial_margin_ciphertext * &leverage_ciphertext);// check for valid notional
     // println!("[{}ms] Notional validation computed", start_time.elapsed().as_millis());
@@ -70,73 +102,878 @@ pub async fn open_position_circuit(
     let notional_decrypted = true;
     
     if notional_decrypted {
-        let liqudation_price_ciphertext = notional_ciphertext - opening_fee_ciphertext - initial_margin_ciphertext.clone(); // prob need to adjust this later  
-        println!("[{}ms] Liquidation price computed", start_time.elapsed().as_millis());
+        let current_balance_key = user.balance.ok_or("No balance")?;
+        let current_balance_ciphertext = state.ciphertext_cache.get_u64(current_balance_key).unwrap();
+        // notional and fee are public, so they go in as clear operands instead of being encrypted with the client key
+        let client_key = state.client_key.clone();
+        let (covered, liqudation_price_ciphertext, new_balance_ciphertext, opening_fee_ciphertext) = state.fhe_pool.run(move || {
+            // the subtraction below wraps if the balance is short, so check it first and only reveal that bit
+            let cost = &initial_margin_ciphertext + opening_fee;
+            let covered: bool = current_balance_ciphertext.ge(&cost).decrypt(&*client_key);
+            let liqudation_price_ciphertext = encrypted_liquidation_price(&initial_margin_ciphertext, direction, entry_price, notional);
+            // need to deduct intiial margin and the opening fee from user balance
+            let new_balance_ciphertext = &current_balance_ciphertext - &cost;
+            (covered, liqudation_price_ciphertext, new_balance_ciphertext, FheUint64::encrypt_trivial(opening_fee))
+        }).await?;
+        if !covered {
+            return Err("Insufficient balance for margin and opening fee".into());
+        }
+        println!("[{}ms] Liquidation price and new balance computed", start_time.elapsed().as_millis());
         
         let liqudation_price_key = _encrypt_from_FheUint64(State(state.clone()), liqudation_price_ciphertext, user_id).await;
         println!("[{}ms] Liquidation price encrypted and stored", start_time.elapsed().as_millis());
         
         // need to create the actual ciphertext for liqudation price 
+        let position_id = state.position_cache.write().await.next_id();
         let hold_position = Position {
-            id: state.position_cache.lock().await.n,
+            id: position_id,
+            owner: user_id,
+            market,
             direction,
             notional,
             entry_price: entry_price,
             leverage: leverage_key,
             initial_margin: initial_margin_key,
             liqudation_price: liqudation_price_key,
+            stop_loss: None,
+            take_profit: None,
         };
         println!("[{}ms] Position object created", start_time.elapsed().as_millis());
         
-        // need to deduct intiial margin from user balance
-        let current_balance_key = state.user_cache.lock().await.get_user(user_id).unwrap().balance;
-        let current_balance_ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(current_balance_key).unwrap().ciphertext.clone();
-        let new_balance_ciphertext = &current_balance_ciphertext - &initial_margin_ciphertext;
-        println!("[{}ms] New balance computed", start_time.elapsed().as_millis());
-        
-        state.ciphertext_cache.lock().await.update_ciphertext(current_balance_key, user_id, new_balance_ciphertext); // update the ciphertext balance
+        state.ciphertext_cache.update_ciphertext(current_balance_key, user_id, new_balance_ciphertext); // update the ciphertext balance
         println!("[{}ms] Balance updated in cache", start_time.elapsed().as_millis());
+
+        credit_insurance_fund(state, &opening_fee_ciphertext).await?; // fees fund the insurance fund
+        println!("[{}ms] Opening fee credited to insurance fund", start_time.elapsed().as_millis());
         
-        state.user_cache.lock().await.add_position(user_id, hold_position.clone()); // add the position to the user_cache array
+        user.add_position(hold_position.clone()); // add the position to the user_cache array
         println!("[{}ms] Position added to user cache", start_time.elapsed().as_millis());
         
-        state.position_cache.lock().await.add_position(hold_position); // add the position to the cache
+        state.position_cache.write().await.add_position(hold_position); // add the position to the cache
         println!("[{}ms] Position added to position cache", start_time.elapsed().as_millis());
 
+        update_open_interest(state, market, direction, notional, true).await?;
+
         println!("[{}ms] Position opened successfully!", start_time.elapsed().as_millis());
-        Ok(())
+        Ok(position_id)
     } else {
         println!("[{}ms] Invalid notional - position opening failed", start_time.elapsed().as_millis());
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Invalid notional")));
     }
 }
 
-pub async fn health_check_long_circuit(state: &AppState, liqdation_price: FheUint64, mark_price: u64) -> bool {
-    set_server_key((*state.server_key).clone());
-    println!("Health check long circuit called");
-    let mark_ciphertext = FheUint64::encrypt(mark_price, &*state.client_key);
-    let status_ciphertext = mark_ciphertext.ge(&liqdation_price);
-    let status_decrypted = status_ciphertext.decrypt(&*state.client_key);
-    status_decrypted
+// adds a fill to an existing position. the entry becomes the volume weighted average of the old entry and the fill
+// price, rounded against the trader. margin for the added notional (notional / leverage, rounded up) and the opening
+// fee come out of the balance and the liquidation price is recomputed from the new margin
+pub async fn extend_position_circuit(state: &AppState, user_id: u128, position_id: u128, price: u64, notional: u64, leverage: u8) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user_cache.get_user(user_id).ok_or("User not found")?;
+    let mut user = user.lock().await;
+    let mut position = user.get_position(position_id).ok_or("Position not found")?;
+    println!("Extending position {} by {} at {}", position.id, notional, price);
+    let balance_key = user.balance.ok_or("No balance")?;
+    let balance = state.ciphertext_cache.get_u64(balance_key).unwrap();
+    let margin = state.ciphertext_cache.get_u64(position.initial_margin).unwrap();
+    let added_margin = mul_div(notional, 1, leverage.max(1) as u64, Rounding::Up);
+    let opening_fee = opening_fee(notional);
+    let total_notional = position.notional + notional;
+    let weighted = position.entry_price as u128 * position.notional as u128 + price as u128 * notional as u128;
+    let entry_price = if position.direction {
+        weighted.div_ceil(total_notional as u128) as u64
+    } else {
+        (weighted / total_notional as u128) as u64
+    };
+    let direction = position.direction;
+    let client_key = state.client_key.clone();
+    let (covered, new_margin, new_balance, liqudation_price) = state.fhe_pool.run(move || {
+        let covered: bool = balance.ge(added_margin + opening_fee).decrypt(&*client_key);
+        let new_margin = &margin + added_margin;
+        let new_balance = &balance - (added_margin + opening_fee);
+        let liqudation_price = encrypted_liquidation_price(&new_margin, direction, entry_price, total_notional);
+        (covered, new_margin, new_balance, liqudation_price)
+    }).await?;
+    if !covered {
+        return Err("Insufficient balance for margin and opening fee".into());
+    }
+    state.ciphertext_cache.update_ciphertext(position.initial_margin, user_id, new_margin);
+    state.ciphertext_cache.update_ciphertext(position.liqudation_price, user_id, liqudation_price);
+    state.ciphertext_cache.update_ciphertext(balance_key, user_id, new_balance);
+    credit_insurance_fund(state, &FheUint64::encrypt_trivial(opening_fee)).await?;
+    position.notional = total_notional;
+    position.entry_price = entry_price;
+    user.update_position(position.clone());
+    state.position_cache.write().await.update_position(position.clone());
+    update_open_interest(state, position.market, direction, notional, true).await?;
+    Ok(())
 }
 
-pub async fn funding_rate_long_pay_short_circuit(state: &AppState, liqudation_price_ciphertext: Ciphertext, delta: u64) -> Result<(), Box<dyn std::error::Error>> {
-    set_server_key((*state.server_key).clone());
-    let encrypted_delta = FheUint64::encrypt(delta, &*state.client_key).clone();
-    println!("trying to do the math for funding rate LPS");
-    
-    let decrypted_delta: u64 = encrypted_delta.decrypt(&*state.client_key);
-    println!("delta decrypted: {}", decrypted_delta);
-    let decrypted_liqdation_price: u64 = liqudation_price_ciphertext.ciphertext.decrypt(&*state.client_key);
-    println!("liqdation price decrypted: {}", decrypted_liqdation_price);
+// closes part of a position at a fill price, for reduce only orders. it settles against the insurance fund like
+// close_position_circuit: the closed share of the margin (rounded down) comes back with the profit on the closed
+// notional, paid out of the fund as far as it goes. a loss is paid into the fund, first out of the closed share,
+// then out of the margin left in the position, which gets a new liquidation price from what remains. a loss bigger
+// than the whole margin is drawn from the fund and whatever it cant cover goes to ADL. returns the ids of any
+// positions that were deleveraged
+pub async fn reduce_position_circuit(state: &AppState, user_id: u128, position_id: u128, price: u64, notional: u64) -> Result<Vec<u128>, Box<dyn std::error::Error>> {
+    let (uncovered, direction) = {
+        let user = state.user_cache.get_user(user_id).ok_or("User not found")?;
+        let mut user = user.lock().await;
+        let mut position = user.get_position(position_id).ok_or("Position not found")?;
+        if notional >= position.notional {
+            return Err("Reduce would close the whole position".into());
+        }
+        println!("Reducing position {} by {} at {}", position.id, notional, price);
+        let mut closed = position.clone();
+        closed.notional = notional;
+        let profit = unrealized_profit(&closed, price);
+        let loss = unrealized_loss(&closed, price);
+        let margin = state.ciphertext_cache.get_u64(position.initial_margin).unwrap();
+        let (position_notional, remaining_notional) = (position.notional, position.notional - notional);
+        let (direction, entry_price) = (position.direction, position.entry_price);
+        let fund = state.user_cache.get_insurance_fund();
+        let mut fund = fund.lock().await;
+        let fund_balance = get_balance_ciphertext(state, &fund);
+        let (returned, new_margin, new_liqudation_price, new_fund_balance, uncovered) = state.fhe_pool.run(move || {
+            let fund_balance = fund_balance.unwrap_or_else(|| FheUint64::encrypt_trivial(0u64));
+            let wide_margin: FheUint128 = margin.clone().cast_into();
+            let released: FheUint64 = ((wide_margin * notional as u128) / position_notional as u128).cast_into();
+            let kept = &margin - &released;
+            // at most one of profit and loss is above 0
+            let profit_paid = fund_balance.min(profit);
+            let paid_from_released = released.min(loss);
+            let excess_loss = FheUint64::encrypt_trivial(loss) - &paid_from_released;
+            let paid_from_kept = kept.min(&excess_loss);
+            let shortfall = &excess_loss - &paid_from_kept;
+            let fund_after_pnl = &fund_balance - &profit_paid + &paid_from_released + &paid_from_kept;
+            let drawn = fund_after_pnl.min(&shortfall);
+            let new_margin = &kept - &paid_from_kept;
+            let new_liqudation_price = encrypted_liquidation_price(&new_margin, direction, entry_price, remaining_notional);
+            (&released - &paid_from_released + &profit_paid, new_margin, new_liqudation_price, &fund_after_pnl - &drawn, &shortfall - &drawn)
+        }).await?;
+        set_balance(state, &mut fund, new_fund_balance).await;
+        drop(fund);
+        add_to_balance(state, &mut user, &returned).await?;
+        state.ciphertext_cache.update_ciphertext(position.initial_margin, user_id, new_margin);
+        state.ciphertext_cache.update_ciphertext(position.liqudation_price, user_id, new_liqudation_price);
+        position.notional = remaining_notional;
+        user.update_position(position.clone());
+        state.position_cache.write().await.update_position(position.clone());
+        update_open_interest(state, position.market, direction, notional, false).await?;
+        (uncovered, direction)
+    };
+    // the owner is unlocked before deleveraging, ADL locks the accounts on the other side
+    Ok(settle_bad_debt(state, uncovered, !direction, price).await?)
+}
 
+// the result stays encrypted, callers decide whether it gets revealed
+pub async fn health_check_long_circuit(state: &AppState, liqdation_price: FheUint64, mark_price: u64) -> Result<FheBool, PoolError> {
+    println!("Health check long circuit called");
+    state.fhe_pool.run(move || {
+        liqdation_price.le(mark_price) // mark >= liquidation price, mark stays in the clear
+    }).await
+}
 
-    let new_liqdation_price_ciphertext = &liqudation_price_ciphertext.ciphertext - &encrypted_delta;
+pub async fn funding_rate_long_pay_short_circuit(state: &AppState, liqudation_price_ciphertext: Ciphertext, delta: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let owner = state.user_cache.get_user(liqudation_price_ciphertext.owner).ok_or("Owner not found")?;
+    let _owner = owner.lock().await; // re-read under the owners lock so we dont overwrite a concurrent update
+    let liqudation_price_ciphertext = state.ciphertext_cache.get_ciphertext(liqudation_price_ciphertext.key).ok_or("Ciphertext not found")?;
+    let liqudation_price = liqudation_price_ciphertext.ciphertext.clone().into_u64().ok_or("Liquidation price is not a FheUint64")?;
+    println!("trying to do the math for funding rate LPS, delta: {}", delta);
+    let new_liqdation_price_ciphertext = state.fhe_pool.run(move || &liqudation_price - delta).await?;
     println!("new liqdation price computed");
-    let update_result = state.ciphertext_cache.lock().await.update_ciphertext(liqudation_price_ciphertext.key, liqudation_price_ciphertext.owner, new_liqdation_price_ciphertext);
+    let update_result = state.ciphertext_cache.update_ciphertext(liqudation_price_ciphertext.key, liqudation_price_ciphertext.owner, new_liqdation_price_ciphertext);
     println!("update_ciphertext result: {}", update_result);
     println!("funding rate LPS circuit finished");
     Ok(())
 }
 
+// sets an accounts encrypted balance, allocating a ciphertext key if it doesnt have one yet. caller holds the account lock
+async fn set_balance(state: &AppState, user: &mut User, value: FheUint64) {
+    match user.balance {
+        Some(key) => { state.ciphertext_cache.update_ciphertext(key, user.id, value); }
+        None => {
+            let key = _encrypt_from_FheUint64(State(state.clone()), value, user.id).await;
+            user.update_balance(key);
+        }
+    }
+}
+
+// reads an accounts encrypted balance, None means the account has never held a balance (zero)
+fn get_balance_ciphertext(state: &AppState, user: &User) -> Option<FheUint64> {
+    user.balance.map(|key| state.ciphertext_cache.get_u64(key).unwrap())
+}
+
+async fn add_to_balance(state: &AppState, user: &mut User, amount: &FheUint64) -> Result<(), PoolError> {
+    let current_balance_ciphertext = get_balance_ciphertext(state, user);
+    let amount = amount.clone();
+    let new_balance_ciphertext = state.fhe_pool.run(move || match current_balance_ciphertext {
+        Some(current_balance_ciphertext) => &current_balance_ciphertext + &amount,
+        None => amount,
+    }).await?;
+    set_balance(state, user, new_balance_ciphertext).await;
+    Ok(())
+}
+
+// the fund is always the last account locked in an operation, see AccountCache
+async fn credit_insurance_fund(state: &AppState, amount: &FheUint64) -> Result<(), PoolError> {
+    let fund = state.user_cache.get_insurance_fund();
+    let mut fund = fund.lock().await;
+    add_to_balance(state, &mut fund, amount).await
+}
+
+// plaintext loss of a position at the mark, notional and entry price are public so this doesnt touch ciphertexts
+pub fn unrealized_loss(position: &Position, mark_price: u64) -> u64 {
+    let adverse_move = if position.direction {
+        position.entry_price.saturating_sub(mark_price)
+    } else {
+        mark_price.saturating_sub(position.entry_price)
+    };
+    mul_div(position.notional, adverse_move, position.entry_price, Rounding::Up)
+}
+
+pub fn unrealized_profit(position: &Position, mark_price: u64) -> u64 {
+    let favourable_move = if position.direction {
+        mark_price.saturating_sub(position.entry_price)
+    } else {
+        position.entry_price.saturating_sub(mark_price)
+    };
+    mul_div(position.notional, favourable_move, position.entry_price, Rounding::Down)
+}
+
+// encrypted leverage times a clear amount by shift and add over the 8 leverage bits. 8 selects and adds instead of
+// casting leverage up to 64 bits and doing a full multiplication
+fn leverage_times(leverage: &FheUint8, amount: u64) -> FheUint64 {
+    let zero = FheUint64::encrypt_trivial(0u64);
+    let mut product = zero.clone();
+    for bit in 0..8u32 {
+        let set = (leverage & (1u8 << bit)).ne(0u8);
+        product = &product + &set.if_then_else(&FheUint64::encrypt_trivial(amount.saturating_mul(1 << bit)), &zero);
+    }
+    product
+}
+
+pub fn opening_fee(notional: u64) -> u64 {
+    bps(notional, OPENING_FEE_BPS, Rounding::Up)
+}
+
+pub fn maintenance_margin(notional: u64) -> u64 {
+    bps(notional, MAINTENANCE_MARGIN_BPS, Rounding::Up)
+}
+
+pub fn liquidation_penalty(notional: u64) -> u64 {
+    bps(notional, LIQUIDATION_PENALTY_BPS, Rounding::Up)
+}
+
+// price at which the loss eats the margin down to maintenance: entry -/+ entry * (margin - maintenance) / notional.
+// the product overflows 64 bits at realistic scales so it is taken in FheUint128. the distance from entry is
+// floored, which moves the liquidation price towards entry for both sides
+pub fn encrypted_liquidation_price(margin: &FheUint64, direction: bool, entry_price: u64, notional: u64) -> FheUint64 {
+    let maintenance = maintenance_margin(notional);
+    let zero = FheUint64::encrypt_trivial(0u64);
+    let buffer = margin.ge(maintenance).if_then_else(&(margin - maintenance), &zero);
+    let wide_buffer: FheUint128 = buffer.cast_into();
+    let distance: FheUint64 = ((wide_buffer * entry_price as u128) / notional.max(1) as u128).cast_into();
+    if direction {
+        FheUint64::encrypt_trivial(entry_price) - &distance.min(entry_price)
+    } else {
+        FheUint64::encrypt_trivial(entry_price) + &distance
+    }
+}
+
+// closes an underwater position at the mark. whatever margin is left after the loss pays the penalty into the
+// insurance fund and the rest goes back to the owner. if the margin doesnt cover the loss the shortfall is drawn
+// from the fund, and if the fund cant cover it either we auto-deleverage the opposing side.
+// returns the ids of any positions that were deleveraged
+pub async fn liquidation_circuit(state: &AppState, position: Position, mark_price: u64) -> Result<Vec<u128>, Box<dyn std::error::Error>> {
+    let uncovered = {
+        let owner = state.user_cache.get_user(position.owner).ok_or("Owner not found")?;
+        let mut owner = owner.lock().await;
+        let position = owner.get_position(position.id).ok_or("Position no longer open")?;
+        close_out_position(state, &mut owner, &position, mark_price).await?
+    };
+    // the owner is unlocked before deleveraging, ADL locks the accounts on the other side
+    Ok(settle_bad_debt(state, uncovered, !position.direction, mark_price).await?)
+}
+
+// full liquidation with the owners account already locked, returns the bad debt the insurance fund couldnt cover
+async fn close_out_position(state: &AppState, owner: &mut User, position: &Position, mark_price: u64) -> Result<FheUint64, PoolError> {
+    println!("Liquidating position {}", position.id);
+    let loss = unrealized_loss(position, mark_price);
+    let penalty = liquidation_penalty(position.notional);
+
+    let margin = state.ciphertext_cache.get_u64(position.initial_margin).unwrap();
+    let (returned, penalty_paid, shortfall) = state.fhe_pool.run(move || {
+        let zero = FheUint64::encrypt_trivial(0u64);
+        let margin_covers_loss = margin.ge(loss);
+        let remaining_margin = margin_covers_loss.if_then_else(&(&margin - loss), &zero);
+        let shortfall = margin_covers_loss.if_then_else(&zero, &(FheUint64::encrypt_trivial(loss) - &margin));
+        let penalty_paid = remaining_margin.min(penalty);
+        (&remaining_margin - &penalty_paid, penalty_paid, shortfall)
+    }).await?;
+    add_to_balance(state, owner, &returned).await?;
+    println!("Remaining margin returned to owner");
+
+    let uncovered = settle_with_insurance_fund(state, penalty_paid, shortfall).await?;
+    println!("Insurance fund updated");
+
+    owner.remove_position(position.id);
+    state.position_cache.write().await.remove_position(position.id, position.direction);
+    update_open_interest(state, position.market, position.direction, position.notional, false).await?;
+    Ok(uncovered)
+}
+
+// credits the fund and draws a shortfall from it in one go under the fund lock, returns what the fund couldnt cover
+async fn settle_with_insurance_fund(state: &AppState, credit: FheUint64, shortfall: FheUint64) -> Result<FheUint64, PoolError> {
+    let fund = state.user_cache.get_insurance_fund();
+    let mut fund = fund.lock().await;
+    let fund_balance = get_balance_ciphertext(state, &fund);
+    let (new_fund_balance, uncovered) = state.fhe_pool.run(move || {
+        let fund_after_credit = match fund_balance {
+            Some(fund_balance) => &fund_balance + &credit,
+            None => credit,
+        };
+        let drawn = fund_after_credit.min(&shortfall);
+        (&fund_after_credit - &drawn, &shortfall - &drawn)
+    }).await?;
+    set_balance(state, &mut fund, new_fund_balance).await;
+    Ok(uncovered)
+}
+
+// closes a position at the mark on the owners behalf, used by /close_position and when a stop loss or take profit
+// fires. there is no matched counterparty at the mark so the insurance fund takes the other side, like it does for
+// liquidations (see close_at_mark). a loss bigger than the margin is bad debt and takes the same fund / ADL path as a
+// liquidation. returns the ids of any positions that were deleveraged
+pub async fn close_position_circuit(state: &AppState, user_id: u128, position_id: u128, mark_price: u64) -> Result<Vec<u128>, Box<dyn std::error::Error>> {
+    let (uncovered, direction) = {
+        let owner = state.user_cache.get_user(user_id).ok_or("User not found")?;
+        let mut owner = owner.lock().await;
+        let position = owner.get_position(position_id).ok_or("Position not found")?;
+        println!("Closing position {}", position.id);
+        let (uncovered, _) = close_at_mark(state, &mut owner, &position, mark_price, FheUint64::encrypt_trivial(0u64)).await?;
+        (uncovered, position.direction)
+    };
+    Ok(settle_bad_debt(state, uncovered, !direction, mark_price).await?)
+}
+
+// settles a whole position at the mark against the insurance fund and takes it off the books, the owner is locked by
+// the caller. a profit is paid out of the fund as far as the fund goes, a loss is paid into it out of the margin and
+// whatever the margin doesnt cover is drawn from it. bad_debt is debt the position is being deleveraged for: its
+// profit pays that down first and only the rest is owed by the fund. returns the loss the fund couldnt cover and
+// the bad debt left
+async fn close_at_mark(state: &AppState, owner: &mut User, position: &Position, mark_price: u64, bad_debt: FheUint64) -> Result<(FheUint64, FheUint64), PoolError> {
+    let profit = unrealized_profit(position, mark_price);
+    let loss = unrealized_loss(position, mark_price);
+    let margin = state.ciphertext_cache.get_u64(position.initial_margin).unwrap();
+    let fund = state.user_cache.get_insurance_fund();
+    let mut fund = fund.lock().await;
+    let fund_balance = get_balance_ciphertext(state, &fund);
+    let (returned, new_fund_balance, uncovered, bad_debt) = state.fhe_pool.run(move || {
+        let fund_balance = fund_balance.unwrap_or_else(|| FheUint64::encrypt_trivial(0u64));
+        // at most one of profit and loss is above 0
+        let haircut = bad_debt.min(profit);
+        let profit_paid = fund_balance.min(&(FheUint64::encrypt_trivial(profit) - &haircut));
+        let loss_paid = margin.min(loss);
+        let shortfall = FheUint64::encrypt_trivial(loss) - &loss_paid;
+        let fund_after_pnl = &fund_balance - &profit_paid + &loss_paid;
+        let drawn = fund_after_pnl.min(&shortfall);
+        (&margin - &loss_paid + &profit_paid, &fund_after_pnl - &drawn, &shortfall - &drawn, &bad_debt - &haircut)
+    }).await?;
+    set_balance(state, &mut fund, new_fund_balance).await;
+    drop(fund);
+    add_to_balance(state, owner, &returned).await?;
+    owner.remove_position(position.id);
+    state.position_cache.write().await.remove_position(position.id, position.direction);
+    update_open_interest(state, position.market, position.direction, position.notional, false).await?;
+    Ok((uncovered, bad_debt))
+}
+
+// stop loss and take profit for one position against the mark. the comparisons run on the encrypted trigger prices
+// and are or'ed together so the only thing ever decrypted is the single "close it" bit
+pub async fn trigger_circuit(state: &AppState, position: &Position, mark_price: u64) -> Result<bool, PoolError> {
+    let stop_loss = position.stop_loss.and_then(|key| state.ciphertext_cache.get_u64(key));
+    let take_profit = position.take_profit.and_then(|key| state.ciphertext_cache.get_u64(key));
+    if stop_loss.is_none() && take_profit.is_none() {
+        return Ok(false);
+    }
+    let direction = position.direction;
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        // long: stop below, take profit above. short the other way round
+        let stop_hit = stop_loss.map(|stop_loss| if direction { stop_loss.ge(mark_price) } else { stop_loss.le(mark_price) });
+        let take_hit = take_profit.map(|take_profit| if direction { take_profit.le(mark_price) } else { take_profit.ge(mark_price) });
+        let triggered = match (stop_hit, take_hit) {
+            (Some(stop_hit), Some(take_hit)) => stop_hit | take_hit,
+            (Some(hit), None) | (None, Some(hit)) => hit,
+            (None, None) => unreachable!(),
+        };
+        triggered.decrypt(&*client_key)
+    }).await
+}
+
+// an order the caps turn away is the clients to fix, anything else going wrong in a match is the servers
+#[derive(Debug)]
+pub enum DarkOrderError {
+    OpenInterestCap(String),
+}
+
+impl std::fmt::Display for DarkOrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DarkOrderError::OpenInterestCap(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DarkOrderError {}
+
+pub struct DarkOrderOutcome {
+    pub order_id: u64,
+    pub fills: Vec<DarkFill>,
+    pub resting: bool,
+    pub position: Option<u128>, // the takers position its fills went into
+}
+
+// matches an encrypted order against the opposite side of the dark book and settles the fills into positions like
+// CLOB fills. dark matches run one at a time under dark_matching, but the book lock is only held to take a snapshot
+// of the makers and to apply the result, never through the fhe work, so cancels and the collector dont wait on it.
+// a maker that got a fill but was cancelled in between means the match is run again against a fresh snapshot.
+// the taker settles before any maker does. if it cant pay for its fills the makers go back on the book the way they
+// were and nothing opens, so no maker is left holding a position without a counterparty. fills go through the
+// markets open interest caps like CLOB fills, an order that would take either side past its cap is turned away
+// with DarkOrderError::OpenInterestCap before the book changes.
+// decrypted: each makers fill size, whether each filled maker and the taker are used up, and the makers price on
+// fills. the price is needed for the positions entry price, it is only reported back when the book reveals clearing
+// prices. prices that didnt trade stay hidden
+pub async fn dark_order_circuit(state: &AppState, owner: u128, market: u32, is_buy: bool, leverage: u8, price: FheUint64, size: FheUint64) -> Result<DarkOrderOutcome, Box<dyn std::error::Error>> {
+    let _matching = state.dark_matching.lock().await;
+    let order_id = state.dark_book.write().await.next_id();
+    loop {
+        let (makers, maker_ciphertexts, reveal_price) = {
+            let book = state.dark_book.read().await;
+            let makers = book.get_makers(market, is_buy, owner);
+            let mut maker_ciphertexts = Vec::with_capacity(makers.len());
+            for maker in makers.iter() {
+                let maker_price = state.ciphertext_cache.get_u64(maker.price).ok_or("Missing maker price")?;
+                let maker_size = state.ciphertext_cache.get_u64(maker.size).ok_or("Missing maker size")?;
+                maker_ciphertexts.push((maker_price, maker_size));
+            }
+            (makers, maker_ciphertexts, book.reveal_clearing_price)
+        };
+        let maker_sizes: Vec<FheUint64> = maker_ciphertexts.iter().map(|(_, maker_size)| maker_size.clone()).collect();
+        let client_key = state.client_key.clone();
+        let (taker_price, taker_size) = (price.clone(), size.clone());
+        let (result, fill_sizes, maker_done, prices, taker_done) = state.fhe_pool.run(move || {
+            let ck = &*client_key;
+            let result = match_encrypted(is_buy, &taker_price, &taker_size, &maker_ciphertexts);
+            let fill_sizes: Vec<u64> = result.fills.iter().map(|fill| fill.decrypt(ck)).collect();
+            let mut maker_done = Vec::with_capacity(fill_sizes.len());
+            let mut prices = Vec::with_capacity(fill_sizes.len());
+            for (i, fill_size) in fill_sizes.iter().enumerate() {
+                if *fill_size == 0 {
+                    maker_done.push(false);
+                    prices.push(0);
+                    continue;
+                }
+                let done: bool = result.maker_remaining[i].eq(0u64).decrypt(ck);
+                maker_done.push(done);
+                prices.push(maker_ciphertexts[i].0.decrypt(ck));
+            }
+            let taker_done: bool = result.taker_remaining.eq(0u64).decrypt(ck);
+            (result, fill_sizes, maker_done, prices, taker_done)
+        }).await?;
+
+        // the filled total is only known after the match, so the caps are checked here, before anything on the book
+        // moves. the books lock is held through settlement like for CLOB fills, so open interest cant move in between
+        let _books = state.order_books.lock().await;
+        let filled: u64 = fill_sizes.iter().sum();
+        if filled > 0 {
+            let cap = state.markets.get(market).ok_or("Unknown market")?.max_open_interest;
+            if !open_interest_cap_circuit(state, market, is_buy, filled, cap).await? {
+                return Err(DarkOrderError::OpenInterestCap("Order would take open interest past the market cap".to_string()).into());
+            }
+            if !open_interest_cap_circuit(state, market, !is_buy, filled, cap).await? {
+                return Err(DarkOrderError::OpenInterestCap("Order would take the other side's open interest past the market cap".to_string()).into());
+            }
+        }
+
+        let mut fills = Vec::new();
+        let mut settlements = Vec::new();
+        {
+            let mut book = state.dark_book.write().await;
+            if makers.iter().zip(fill_sizes.iter()).any(|(maker, fill_size)| *fill_size > 0 && book.get_order(maker.id).is_none()) {
+                println!("Dark order {} matched a maker that was cancelled during the match, matching again", order_id);
+                continue;
+            }
+            for (i, maker) in makers.iter().enumerate() {
+                if fill_sizes[i] == 0 {
+                    continue;
+                }
+                fills.push(DarkFill {
+                    maker_order_id: maker.id,
+                    taker_order_id: order_id,
+                    maker: maker.owner,
+                    taker: owner,
+                    size: fill_sizes[i],
+                    price: reveal_price.then_some(prices[i]),
+                });
+                settlements.push((maker.clone(), prices[i], fill_sizes[i], maker_done[i], maker_sizes[i].clone()));
+                if maker_done[i] {
+                    book.take_filled(maker.id);
+                } else {
+                    state.ciphertext_cache.update_ciphertext(maker.size, maker.owner, result.maker_remaining[i].clone());
+                }
+            }
+        }
+
+        let dark_fills: Vec<(EncryptedOrder, u64, u64)> = settlements.iter()
+            .map(|(maker, price, size, _, _)| (maker.clone(), *price, *size))
+            .collect();
+        let (position, maker_positions) = match settle_dark_fills(state, market, owner, is_buy, leverage, &dark_fills).await {
+            Ok(positions) => positions,
+            Err(e) => {
+                let mut book = state.dark_book.write().await;
+                book.restore_pending();
+                for (maker, _, _, done, original_size) in settlements {
+                    // a partly filled maker cancelled in the meantime is gone for good, there is nothing to put back
+                    if !done && book.get_order(maker.id).is_some() {
+                        state.ciphertext_cache.update_ciphertext(maker.size, maker.owner, original_size);
+                    }
+                }
+                println!("Dark order {} couldnt settle, makers restored: {}", order_id, e);
+                return Err(e.into());
+            }
+        };
+
+        let resting_order = if taker_done {
+            None
+        } else {
+            // rests with the leftover size, under handles the book owns so the clients own ciphertexts arent touched
+            Some(EncryptedOrder {
+                id: order_id,
+                owner,
+                market,
+                is_buy,
+                price: _encrypt_from_FheUint64(State(state.clone()), price, owner).await,
+                size: _encrypt_from_FheUint64(State(state.clone()), result.taker_remaining, owner).await,
+                leverage,
+                position,
+                timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64,
+            })
+        };
+        let mut book = state.dark_book.write().await;
+        book.clear_pending();
+        for ((maker, _, _, done, _), maker_position) in settlements.iter().zip(maker_positions) {
+            if !done {
+                book.set_position(maker.id, maker_position);
+            }
+        }
+        if let Some(resting_order) = resting_order {
+            book.add_order(resting_order);
+        }
+        println!("Dark order {} matched {} makers, resting: {}", order_id, fills.len(), !taker_done);
+        return Ok(DarkOrderOutcome { order_id, fills, resting: !taker_done, position });
+    }
+}
+
+// the (side, price, size) ciphertexts of an auctions orders, in order
+pub fn sealed_ciphertexts(state: &AppState, orders: &[SealedOrder]) -> Result<Vec<(bool, FheUint64, FheUint64)>, String> {
+    let mut ciphertexts = Vec::with_capacity(orders.len());
+    for order in orders {
+        let price = state.ciphertext_cache.get_u64(order.price).ok_or("Missing sealed order price")?;
+        let size = state.ciphertext_cache.get_u64(order.size).ok_or("Missing sealed order size")?;
+        ciphertexts.push((order.is_buy, price, size));
+    }
+    Ok(ciphertexts)
+}
+
+// clears one sealed bid auction from its orders ciphertexts (see sealed_ciphertexts). decrypted: whether anything
+// crossed, the clearing price, and each orders fill. the curves, the volume and every limit price stay encrypted.
+// returns the price and one fill per order, in order
+pub async fn sealed_auction_circuit(state: &AppState, ciphertexts: Vec<(bool, FheUint64, FheUint64)>) -> Result<(Option<u64>, Vec<u64>), PoolError> {
+    let orders = ciphertexts.len();
+    let client_key = state.client_key.clone();
+    let outcome = state.fhe_pool.run(move || {
+        let ck = &*client_key;
+        let (price, crossed) = sealed_clearing_price(&ciphertexts);
+        if !crossed.decrypt(ck) {
+            return None;
+        }
+        let price: u64 = price.decrypt(ck);
+        let fills: Vec<u64> = sealed_fills(&ciphertexts, price).iter().map(|fill| fill.decrypt(ck)).collect();
+        Some((price, fills))
+    }).await?;
+    Ok(match outcome {
+        Some((price, fills)) => (Some(price), fills),
+        None => (None, vec![0; orders]),
+    })
+}
+
+// adds an opened notional to, or takes a closed one off, the markets encrypted total for that side. the notional is
+// public so it goes in as a clear operand. the lock is held over the pool call so two updates cant overwrite each
+// other, it is always the last lock taken
+async fn update_open_interest(state: &AppState, market: u32, is_long: bool, notional: u64, opened: bool) -> Result<(), PoolError> {
+    if notional == 0 {
+        return Ok(());
+    }
+    let mut open_interest = state.open_interest.lock().await;
+    let total = open_interest.get(market, is_long);
+    let new_total = state.fhe_pool.run(move || {
+        let total = total.unwrap_or_else(|| FheUint64::encrypt_trivial(0u64));
+        if opened { &total + notional } else { &total - notional }
+    }).await?;
+    open_interest.set(market, is_long, new_total);
+    Ok(())
+}
+
+// decrypts a markets long and short totals and nothing else, no single position is touched
+pub async fn open_interest_circuit(state: &AppState, market: u32) -> Result<(u64, u64), PoolError> {
+    let open_interest = state.open_interest.lock().await;
+    let (long, short) = (open_interest.get(market, true), open_interest.get(market, false));
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        let decrypt = |total: Option<FheUint64>| total.map(|total| total.decrypt(&*client_key)).unwrap_or(0u64);
+        (decrypt(long), decrypt(short))
+    }).await
+}
+
+// compares the totals under encryption and only decrypts which side is bigger, the sizes stay hidden
+pub async fn skew_circuit(state: &AppState, market: u32) -> Result<Skew, PoolError> {
+    let open_interest = state.open_interest.lock().await;
+    let (long, short) = (open_interest.get(market, true), open_interest.get(market, false));
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        let zero = FheUint64::encrypt_trivial(0u64);
+        let (long, short) = (long.unwrap_or_else(|| zero.clone()), short.unwrap_or(zero));
+        let long_heavy: bool = long.gt(&short).decrypt(&*client_key);
+        let short_heavy: bool = long.lt(&short).decrypt(&*client_key);
+        match (long_heavy, short_heavy) {
+            (true, _) => Skew::Long,
+            (_, true) => Skew::Short,
+            _ => Skew::Balanced,
+        }
+    }).await
+}
+
+// whether an accounts balance covers cost, an account without a balance covers nothing. only that bit is decrypted
+pub async fn balance_covers_circuit(state: &AppState, user_id: u128, cost: u64) -> Result<bool, PoolError> {
+    let balance = match state.user_cache.get_user(user_id) {
+        Some(user) => get_balance_ciphertext(state, &*user.lock().await),
+        None => None,
+    };
+    let Some(balance) = balance else { return Ok(false) };
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || balance.ge(cost).decrypt(&*client_key)).await
+}
 
+// balance_covers_circuit for an order whose size is encrypted: margin (size / leverage, rounded up) plus the opening
+// fee are worked out under encryption, in FheUint128 so a huge size cant wrap, and only the yes/no is decrypted
+pub async fn balance_covers_order_circuit(state: &AppState, user_id: u128, size: FheUint64, leverage: u8) -> Result<bool, PoolError> {
+    let balance = match state.user_cache.get_user(user_id) {
+        Some(user) => get_balance_ciphertext(state, &*user.lock().await),
+        None => None,
+    };
+    let Some(balance) = balance else { return Ok(false) };
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || {
+        let size: FheUint128 = size.cast_into();
+        let leverage = leverage.max(1) as u128;
+        let margin = (&size + (leverage - 1)) / leverage;
+        let fee = (&size * OPENING_FEE_BPS as u128 + 9_999u128) / 10_000u128;
+        let balance: FheUint128 = balance.cast_into();
+        balance.ge(&(margin + fee)).decrypt(&*client_key)
+    }).await
+}
+
+// whether adding notional to a side keeps it within the cap. only that one bit is decrypted
+pub async fn open_interest_cap_circuit(state: &AppState, market: u32, is_long: bool, notional: u64, cap: u64) -> Result<bool, PoolError> {
+    if notional > cap {
+        return Ok(false);
+    }
+    let Some(total) = state.open_interest.lock().await.get(market, is_long) else { return Ok(true) };
+    let client_key = state.client_key.clone();
+    state.fhe_pool.run(move || total.le(cap - notional).decrypt(&*client_key)).await
+}
+
+async fn settle_bad_debt(state: &AppState, uncovered: FheUint64, direction: bool, mark_price: u64) -> Result<Vec<u128>, PoolError> {
+    let client_key = state.client_key.clone();
+    let bad_debt = uncovered.clone();
+    let fund_exhausted: bool = state.fhe_pool.run(move || bad_debt.gt(0u64).decrypt(&*client_key)).await?; // only reveal whether there is bad debt left
+    if fund_exhausted {
+        println!("Insurance fund exhausted, auto-deleveraging");
+        auto_deleverage_circuit(state, uncovered, direction, mark_price).await
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+// ranks profitable positions on the given side by pnl * leverage and closes the top one at the mark until the bad
+// debt is covered. the closed positions profit pays the debt down before the rest of it is paid out (see
+// close_at_mark), and the notional comes off the open interest like any close. the ranking runs over ciphertexts,
+// only the index of the selected position and whether any debt is left get decrypted
+pub async fn auto_deleverage_circuit(state: &AppState, bad_debt: FheUint64, direction: bool, mark_price: u64) -> Result<Vec<u128>, PoolError> {
+    let mut uncovered = bad_debt;
+    let mut candidates: Vec<(Position, u64)> = state.position_cache.read().await.get_positions(direction)
+        .iter()
+        .map(|position| (position.clone(), unrealized_profit(position, mark_price)))
+        .filter(|(_, profit)| *profit > 0)
+        .collect();
+    let mut deleveraged = Vec::new();
+    let mut remaining = true;
+
+    while remaining && !candidates.is_empty() {
+        let scored: Vec<(FheUint8, u64)> = candidates.iter()
+            .map(|(position, profit)| (state.ciphertext_cache.get_u8(position.leverage).unwrap(), *profit))
+            .collect();
+        let client_key = state.client_key.clone();
+        let selected: u64 = state.fhe_pool.run(move || {
+            let mut best_score = FheUint64::encrypt_trivial(0u64);
+            let mut best_index = FheUint64::encrypt_trivial(0u64);
+            for (index, (leverage, profit)) in scored.iter().enumerate() {
+                let score = leverage_times(leverage, *profit);
+                let is_better = score.gt(&best_score);
+                best_score = is_better.if_then_else(&score, &best_score);
+                best_index = is_better.if_then_else(&FheUint64::encrypt_trivial(index as u64), &best_index);
+            }
+            best_index.decrypt(&*client_key)
+        }).await?;
+        let (position, _) = candidates.remove(selected as usize);
+        println!("ADL selected position {}", position.id);
+
+        let owner = match state.user_cache.get_user(position.owner) {
+            Some(owner) => owner,
+            None => continue,
+        };
+        let mut owner = owner.lock().await;
+        let Some(position) = owner.get_position(position.id) else {
+            continue; // closed while we were ranking
+        };
+        // a position in profit at the mark has no loss, so nothing comes back uncovered from the close itself
+        let (_, bad_debt_left) = close_at_mark(state, &mut owner, &position, mark_price, uncovered).await?;
+        drop(owner);
+        let client_key = state.client_key.clone();
+        let check = bad_debt_left.clone();
+        remaining = state.fhe_pool.run(move || check.gt(0u64).decrypt(&*client_key)).await?;
+        uncovered = bad_debt_left;
+        deleveraged.push(position.id);
+    }
+    if remaining {
+        println!("ADL ran out of profitable positions, bad debt left unsocialized");
+    }
+    Ok(deleveraged)
+}
+
+// steps a position back above maintenance margin by closing LIQUIDATION_STEP_BPS of it at a time. each step realizes
+// its share of the loss, pays the penalty on the closed size into the insurance fund and recomputes the liquidation
+// price from what is left. once the position would shrink below the market's min_notional, or the margin cant even cover
+// the loss, we close out the whole position like liquidation_circuit does
+pub async fn partial_liquidation_circuit(state: &AppState, position: Position, mark_price: u64) -> Result<LiquidationOutcome, Box<dyn std::error::Error>> {
+    let owner = state.user_cache.get_user(position.owner).ok_or("Owner not found")?;
+    let mut owner = owner.lock().await;
+    let mut position = owner.get_position(position.id).ok_or("Position no longer open")?;
+    let min_notional = state.markets.get(position.market).ok_or("Unknown market")?.min_notional;
+    let mut closed_notional = 0;
+
+    loop {
+        let loss = unrealized_loss(&position, mark_price);
+        let maintenance = maintenance_margin(position.notional);
+        let margin = state.ciphertext_cache.get_u64(position.initial_margin).unwrap();
+        let client_key = state.client_key.clone();
+        let check_margin = margin.clone();
+        let (healthy, bankrupt): (bool, bool) = state.fhe_pool.run(move || {
+            (check_margin.ge(loss + maintenance).decrypt(&*client_key), check_margin.lt(loss).decrypt(&*client_key))
+        }).await?;
+        if healthy {
+            println!("Position {} back above maintenance margin", position.id);
+            break;
+        }
+        let close = bps(position.notional, LIQUIDATION_STEP_BPS, Rounding::Up);
+        if bankrupt || position.notional.saturating_sub(close) < min_notional {
+            let uncovered = close_out_position(state, &mut owner, &position, mark_price).await?;
+            drop(owner);
+            let deleveraged_positions = settle_bad_debt(state, uncovered, !position.direction, mark_price).await?;
+            return Ok(LiquidationOutcome::Full { deleveraged_positions });
+        }
+
+        let realized_loss = mul_div(loss, close, position.notional, Rounding::Up);
+        let penalty = liquidation_penalty(close);
+        let remaining_notional = position.notional - close;
+        let (direction, entry_price) = (position.direction, position.entry_price);
+        let (new_margin, penalty_paid, new_liqudation_price) = state.fhe_pool.run(move || {
+            let zero = FheUint64::encrypt_trivial(0u64);
+            let after_loss = margin.ge(realized_loss).if_then_else(&(&margin - realized_loss), &zero);
+            let penalty_paid = after_loss.min(penalty);
+            let new_margin = &after_loss - &penalty_paid;
+            let new_liqudation_price = encrypted_liquidation_price(&new_margin, direction, entry_price, remaining_notional);
+            (new_margin, penalty_paid, new_liqudation_price)
+        }).await?;
+        credit_insurance_fund(state, &penalty_paid).await?;
+
+        position.notional = remaining_notional;
+        closed_notional += close;
+        state.ciphertext_cache.update_ciphertext(position.initial_margin, position.owner, new_margin);
+        state.ciphertext_cache.update_ciphertext(position.liqudation_price, position.owner, new_liqudation_price);
+        owner.update_position(position.clone());
+        state.position_cache.write().await.update_position(position.clone());
+        update_open_interest(state, position.market, position.direction, close, false).await?;
+        println!("Position {} reduced by {} to {}", position.id, close, position.notional);
+    }
+
+    if closed_notional == 0 {
+        Ok(LiquidationOutcome::Healthy)
+    } else {
+        Ok(LiquidationOutcome::Partial { closed_notional, remaining_notional: position.notional })
+    }
+}
+
+// unrealized pnl of a position at the mark, kept as a profit/loss pair since FheUint64 cant go negative. the notional
+// goes in as a real encryption and the pnl is worked out from it homomorphically (in FheUint128 so the product cant
+// wrap), so the pnl ciphertexts arent trivial encryptions anyone can read back. rounding matches unrealized_profit /
+// unrealized_loss, which close, liquidation and adl settle from
+fn unrealized_pnl(position: &Position, mark_price: u64, client_key: &ClientKey) -> PositionPnl {
+    let (favourable_move, adverse_move) = if position.direction {
+        (mark_price.saturating_sub(position.entry_price), position.entry_price.saturating_sub(mark_price))
+    } else {
+        (position.entry_price.saturating_sub(mark_price), mark_price.saturating_sub(position.entry_price))
+    };
+    let entry_price = position.entry_price.max(1) as u128;
+    let notional: FheUint128 = FheUint64::encrypt(position.notional, client_key).cast_into();
+    let profit: FheUint64 = (&notional * favourable_move as u128 / entry_price).cast_into();
+    let loss: FheUint64 = ((&notional * adverse_move as u128 + (entry_price - 1)) / entry_price).cast_into();
+    PositionPnl {
+        position_id: position.id,
+        market: position.market,
+        mark_price,
+        profit,
+        loss,
+    }
+}
+
+// equity = balance + margins + pnl, floored at zero. every position is valued at its own markets mark. balance,
+// margins and pnl (see unrealized_pnl) are all ciphertexts, so the whole sum is homomorphic
+pub async fn account_equity_circuit(state: &AppState, user_id: u128) -> Result<(Vec<PositionPnl>, FheUint64), Box<dyn std::error::Error>> {
+    let user = state.user_cache.get_user(user_id).ok_or("User not found")?;
+    let user = user.lock().await; // consistent snapshot of balance and margins
+    let balance = get_balance_ciphertext(state, &user);
+    let mut positions: Vec<(Position, FheUint64, u64)> = Vec::new();
+    for position in user.positions.iter() {
+        let mark_price = state.mark_prices.get(position.market).ok_or(format!("No mark price for market {}", position.market))?;
+        positions.push((position.clone(), state.ciphertext_cache.get_u64(position.initial_margin).unwrap(), mark_price));
+    }
+    let client_key = state.client_key.clone();
+    let result = state.fhe_pool.run(move || {
+        let zero = FheUint64::encrypt_trivial(0u64);
+        let mut total = balance.unwrap_or_else(|| zero.clone());
+        let mut losses = zero.clone();
+        let mut pnls = Vec::new();
+        for (position, margin, mark_price) in positions.iter() {
+            let pnl = unrealized_pnl(position, *mark_price, &client_key);
+            total = &total + margin + &pnl.profit;
+            losses = &losses + &pnl.loss;
+            pnls.push(pnl);
+        }
+        let equity = total.ge(&losses).if_then_else(&(&total - &losses), &zero);
+        (pnls, equity)
+    }).await?;
+    Ok(result)
+}
\ No newline at end of file
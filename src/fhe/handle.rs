@@ -0,0 +1,163 @@
+use std::fmt;
+use std::str::FromStr;
+use axum::{http::StatusCode, response::{IntoResponse, Response}};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// bumped whenever the byte layout below changes, old handles are rejected instead of misread
+const HANDLE_VERSION: u8 = 1;
+const HANDLE_ID_LEN: usize = 16;
+const HANDLE_LEN: usize = 1 + 1 + 4 + HANDLE_ID_LEN; // version | type | key set | id
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FheType {
+    Bool,
+    U8,
+    U64,
+    U128,
+}
+
+impl FheType {
+    fn tag(self) -> u8 {
+        match self {
+            FheType::Bool => 0,
+            FheType::U8 => 1,
+            FheType::U64 => 2,
+            FheType::U128 => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, HandleError> {
+        match tag {
+            0 => Ok(FheType::Bool),
+            1 => Ok(FheType::U8),
+            2 => Ok(FheType::U64),
+            3 => Ok(FheType::U128),
+            _ => Err(HandleError::UnknownType(tag)),
+        }
+    }
+}
+
+// reference to a ciphertext in the cache. it says what kind of value sits behind it and which key set encrypted it
+// so a handle for the wrong thing is turned away before we touch any FHE math. on the wire it is a hex string
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CiphertextHandle {
+    pub fhe_type: FheType,
+    pub key_set: u32,
+    pub id: [u8; HANDLE_ID_LEN],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandleError {
+    InvalidEncoding,
+    InvalidLength(usize),
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    TypeMismatch { expected: FheType, found: FheType },
+    KeySetMismatch { expected: u32, found: u32 },
+    NotFound,
+}
+
+impl CiphertextHandle {
+    pub fn new(fhe_type: FheType, key_set: u32) -> Self {
+        Self { fhe_type, key_set, id: rand::random() }
+    }
+
+    // used for the cache shard so unrelated handles spread out evenly
+    pub fn shard_byte(&self) -> u8 {
+        self.id[0]
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDLE_LEN] {
+        let mut bytes = [0u8; HANDLE_LEN];
+        bytes[0] = HANDLE_VERSION;
+        bytes[1] = self.fhe_type.tag();
+        bytes[2..6].copy_from_slice(&self.key_set.to_be_bytes());
+        bytes[6..].copy_from_slice(&self.id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HandleError> {
+        if bytes.len() != HANDLE_LEN {
+            return Err(HandleError::InvalidLength(bytes.len()));
+        }
+        if bytes[0] != HANDLE_VERSION {
+            return Err(HandleError::UnsupportedVersion(bytes[0]));
+        }
+        let fhe_type = FheType::from_tag(bytes[1])?;
+        let key_set = u32::from_be_bytes(bytes[2..6].try_into().unwrap());
+        let mut id = [0u8; HANDLE_ID_LEN];
+        id.copy_from_slice(&bytes[6..]);
+        Ok(Self { fhe_type, key_set, id })
+    }
+
+    // call this on every handle that comes in from a client before the ciphertext behind it is used
+    pub fn expect(&self, fhe_type: FheType, key_set: u32) -> Result<(), HandleError> {
+        self.expect_key_set(key_set)?;
+        if self.fhe_type != fhe_type {
+            return Err(HandleError::TypeMismatch { expected: fhe_type, found: self.fhe_type });
+        }
+        Ok(())
+    }
+
+    // for endpoints that work on any ciphertext type
+    pub fn expect_key_set(&self, key_set: u32) -> Result<(), HandleError> {
+        if self.key_set != key_set {
+            return Err(HandleError::KeySetMismatch { expected: key_set, found: self.key_set });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CiphertextHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for CiphertextHandle {
+    type Err = HandleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| HandleError::InvalidEncoding)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Serialize for CiphertextHandle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for CiphertextHandle {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleError::InvalidEncoding => write!(f, "ciphertext handle is not valid hex"),
+            HandleError::InvalidLength(len) => write!(f, "ciphertext handle is {} bytes, expected {}", len, HANDLE_LEN),
+            HandleError::UnsupportedVersion(version) => write!(f, "unsupported ciphertext handle version {}", version),
+            HandleError::UnknownType(tag) => write!(f, "unknown ciphertext type tag {}", tag),
+            HandleError::TypeMismatch { expected, found } => write!(f, "expected a {:?} ciphertext, got {:?}", expected, found),
+            HandleError::KeySetMismatch { expected, found } => write!(f, "ciphertext was encrypted under key set {}, server uses {}", found, expected),
+            HandleError::NotFound => write!(f, "ciphertext not found"),
+        }
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+impl IntoResponse for HandleError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            HandleError::NotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -8,6 +8,7 @@ const KEYS_DIR: &str = "keys";
 const CLIENT_KEY_PATH: &str = "keys/client_key.bin";
 const SERVER_KEY_PATH: &str = "keys/server_key.bin";
 const PUBLIC_KEY_PATH: &str = "keys/public_key.bin";
+const KEY_SET_PATH: &str = "keys/key_set_id";
 
 pub fn generate_and_save_keys() -> Result<(), Box<dyn std::error::Error>> {
     println!("checking keys...");
@@ -29,6 +30,7 @@ pub fn generate_and_save_keys() -> Result<(), Box<dyn std::error::Error>> {
         save_client_key(&client_key)?;
         save_server_key(&server_key)?;
         save_public_key(&public_key)?;
+        save_key_set_id(rand::random())?; // new keys, new id, handles from the old keys stop resolving
         println!("Keys generated successfully.");
         Ok(())
     }
@@ -78,3 +80,18 @@ pub fn load_public_key() -> Result<CompactPublicKey, String> {
     bincode::deserialize(&data).map_err(|e| format!("Failed to deserialize public key: {}", e))
 }
 
+fn save_key_set_id(id: u32) -> Result<(), String> {
+    fs::write(KEY_SET_PATH, id.to_string())
+        .map_err(|e| format!("Failed to save key set id: {}", e))
+}
+
+// identifies the key set every ciphertext handle was issued under. keys generated before handles existed
+// get an id assigned on first load
+pub fn load_key_set_id() -> Result<u32, String> {
+    if !Path::new(KEY_SET_PATH).exists() {
+        save_key_set_id(rand::random())?;
+    }
+    let data = fs::read_to_string(KEY_SET_PATH)
+        .map_err(|e| format!("Failed to read key set id: {}", e))?;
+    data.trim().parse().map_err(|e| format!("Failed to parse key set id: {}", e))
+}
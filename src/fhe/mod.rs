@@ -1,2 +1,7 @@
 pub mod key_gen; 
-pub mod circuits;
\ No newline at end of file
+pub mod circuits;
+pub mod pool;
+#[cfg(feature = "bench-endpoints")]
+pub mod bench;
+pub mod handle;
+pub mod serialization;
\ No newline at end of file
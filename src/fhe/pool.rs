@@ -0,0 +1,127 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use serde::Serialize;
+use tfhe::{ServerKey, set_server_key};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// fixed set of OS threads that each install the server key once at startup. circuits hand their FHE work to the
+// pool and await the result, so the heavy math never runs on tokio's async workers and we stop deep cloning the
+// multi-megabyte ServerKey on every request
+pub struct FhePool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    size: usize,
+    metrics: Arc<PoolMetrics>,
+}
+
+#[derive(Default)]
+struct PoolMetrics {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    busy_micros: AtomicU64,
+}
+
+// what a job can fail with. the worker survives a panicking job, the caller gets this instead of the result
+#[derive(Debug)]
+pub enum PoolError {
+    Panicked,
+    ShutDown,
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PoolError::Panicked => write!(f, "FHE job panicked"),
+            PoolError::ShutDown => write!(f, "FHE pool has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+#[derive(Serialize)]
+pub struct PoolMetricsSnapshot {
+    pub size: usize,
+    pub queue_depth: usize,
+    pub running: usize,
+    pub completed: u64,
+    pub panicked: u64,
+    pub busy_ms: u64,
+}
+
+impl FhePool {
+    pub fn new(size: usize, server_key: &ServerKey) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(PoolMetrics::default());
+        for i in 0..size {
+            let receiver = receiver.clone();
+            let metrics = metrics.clone();
+            let server_key = server_key.clone();
+            thread::Builder::new()
+                .name(format!("fhe-worker-{}", i))
+                .spawn(move || {
+                    set_server_key(server_key); // once per thread, the key is thread local in tfhe
+                    loop {
+                        let job = receiver.lock().unwrap().recv();
+                        let Ok(job) = job else { break }; // pool dropped
+                        metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                        metrics.running.fetch_add(1, Ordering::Relaxed);
+                        let start = Instant::now();
+                        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            metrics.panicked.fetch_add(1, Ordering::Relaxed);
+                        }
+                        metrics.busy_micros.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                        metrics.running.fetch_sub(1, Ordering::Relaxed);
+                        metrics.completed.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .expect("failed to spawn fhe worker");
+        }
+        println!("FHE pool started with {} workers", size);
+        Self { sender: Mutex::new(sender), size, metrics }
+    }
+
+    // FHE_POOL_SIZE or one worker per core
+    pub fn from_env(server_key: &ServerKey) -> Self {
+        let size = std::env::var("FHE_POOL_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(4));
+        Self::new(size.max(1), server_key)
+    }
+
+    // runs a job on one of the workers and waits for it without blocking the async runtime. a job that panics drops
+    // its sender, so the caller gets PoolError::Panicked back and the worker moves on to the next job
+    pub async fn run<F, R>(&self, job: F) -> Result<R, PoolError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        let sent = self.sender.lock().unwrap().send(Box::new(move || { let _ = tx.send(job()); }));
+        if sent.is_err() {
+            self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(PoolError::ShutDown);
+        }
+        rx.await.map_err(|_| PoolError::Panicked)
+    }
+
+    pub fn metrics(&self) -> PoolMetricsSnapshot {
+        PoolMetricsSnapshot {
+            size: self.size,
+            queue_depth: self.metrics.queued.load(Ordering::Relaxed),
+            running: self.metrics.running.load(Ordering::Relaxed),
+            completed: self.metrics.completed.load(Ordering::Relaxed),
+            panicked: self.metrics.panicked.load(Ordering::Relaxed),
+            busy_ms: self.metrics.busy_micros.load(Ordering::Relaxed) / 1_000,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::Cursor;
+use tfhe::{CompressedCiphertextList, CompressedCiphertextListBuilder, FheUint64, FheUint64ConformanceParams};
+use crate::liqudation::cache::EncryptedValue;
+use tfhe::conformance::ParameterSetConformant;
+use tfhe::prelude::*;
+use tfhe::safe_serialization::{safe_serialize, safe_deserialize};
+use crate::AppState;
+
+// a compressed FheUint64 is a few tens of KB, these leave plenty of room without letting a client make us allocate
+// anything silly. the import limit is on the raw body, base64 bodies are checked before decoding too
+pub const SERIALIZATION_LIMIT: u64 = 1 << 22;
+pub const MAX_IMPORT_BYTES: usize = 1 << 22;
+
+// compressed list with the single ciphertext in it, written with tfhe's versioned safe serialization
+pub async fn export_ciphertext(state: &AppState, ciphertext: EncryptedValue) -> Result<Vec<u8>, String> {
+    state.fhe_pool.run(move || {
+        let mut builder = CompressedCiphertextListBuilder::new();
+        match ciphertext {
+            EncryptedValue::Bool(value) => builder.push(value),
+            EncryptedValue::U8(value) => builder.push(value),
+            EncryptedValue::U64(value) => builder.push(value),
+            EncryptedValue::U128(value) => builder.push(value),
+        };
+        let compressed = builder
+            .build()
+            .map_err(|e| format!("Failed to compress ciphertext: {}", e))?;
+        let mut buffer = Vec::new();
+        safe_serialize(&compressed, &mut buffer, SERIALIZATION_LIMIT)
+            .map_err(|e| format!("Failed to serialize ciphertext: {}", e))?;
+        Ok(buffer)
+    }).await.map_err(|e| e.to_string())?
+}
+
+// reverse of export. anything that isnt exactly one FheUint64 built with the servers parameters is rejected
+pub async fn import_ciphertext(state: &AppState, bytes: Vec<u8>) -> Result<FheUint64, String> {
+    if bytes.len() > MAX_IMPORT_BYTES {
+        return Err(format!("Ciphertext is {} bytes, limit is {}", bytes.len(), MAX_IMPORT_BYTES));
+    }
+    let params = FheUint64ConformanceParams::from(&*state.server_key);
+    state.fhe_pool.run(move || {
+        let compressed: CompressedCiphertextList = safe_deserialize(Cursor::new(bytes), SERIALIZATION_LIMIT)
+            .map_err(|e| format!("Failed to deserialize ciphertext: {}", e))?;
+        if compressed.len() != 1 {
+            return Err(format!("Expected one ciphertext, got {}", compressed.len()));
+        }
+        let ciphertext: FheUint64 = compressed.get(0)
+            .map_err(|e| format!("Not a FheUint64 ciphertext: {}", e))?
+            .ok_or("Empty ciphertext list")?;
+        if !ciphertext.is_conformant(&params) {
+            return Err("Ciphertext does not match the server parameters".to_string());
+        }
+        Ok(ciphertext)
+    }).await.map_err(|e| e.to_string())?
+}
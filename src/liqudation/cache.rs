@@ -1,23 +1,90 @@
-use crate::liqudation::users::{User, Position};
-use std::collections::HashMap;
-use std::sync::Arc;
+use crate::liqudation::users::{User, Position, create_user};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use serde::Serialize;
 use tokio::sync::Mutex;
-use tfhe::{CompressedCiphertextList, FheUint64};
+use tfhe::{CompressedCiphertextList, FheBool, FheUint8, FheUint64, FheUint128};
+use crate::fhe::handle::{CiphertextHandle, FheType};
 
+pub const INSURANCE_FUND_ID: u128 = u128::MAX; // protocol owned account, funded by fees and liquidation penalties
+const CIPHERTEXT_SHARDS: usize = 16;
+pub const EPHEMERAL_CIPHERTEXT_TTL: Duration = Duration::from_secs(10 * 60); // /encrypt results and other one off values
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60); // unreferenced persistent ciphertexts younger than this are in flight
 
 
+// circuits store the narrowest type that fits: comparison results as FheBool, leverage as FheUint8,
+// money as FheUint64 and anything that can outgrow 64 bits as FheUint128
+#[derive(Clone, Serialize)]
+pub enum EncryptedValue {
+    Bool(FheBool),
+    U8(FheUint8),
+    U64(FheUint64),
+    U128(FheUint128),
+}
+
+impl EncryptedValue {
+    pub fn fhe_type(&self) -> FheType {
+        match self {
+            EncryptedValue::Bool(_) => FheType::Bool,
+            EncryptedValue::U8(_) => FheType::U8,
+            EncryptedValue::U64(_) => FheType::U64,
+            EncryptedValue::U128(_) => FheType::U128,
+        }
+    }
+
+    pub fn into_u8(self) -> Option<FheUint8> {
+        match self { EncryptedValue::U8(value) => Some(value), _ => None }
+    }
+
+    pub fn into_u64(self) -> Option<FheUint64> {
+        match self { EncryptedValue::U64(value) => Some(value), _ => None }
+    }
+}
+
+impl From<FheBool> for EncryptedValue {
+    fn from(value: FheBool) -> Self { EncryptedValue::Bool(value) }
+}
+
+impl From<FheUint8> for EncryptedValue {
+    fn from(value: FheUint8) -> Self { EncryptedValue::U8(value) }
+}
+
+impl From<FheUint64> for EncryptedValue {
+    fn from(value: FheUint64) -> Self { EncryptedValue::U64(value) }
+}
+
+impl From<FheUint128> for EncryptedValue {
+    fn from(value: FheUint128) -> Self { EncryptedValue::U128(value) }
+}
+
 #[derive(Clone)]
 pub struct Ciphertext {
-    pub key: [u8;32],
-    pub ciphertext: FheUint64,
+    pub key: CiphertextHandle,
+    pub ciphertext: EncryptedValue,
     pub owner: u128,
+    pub created_at: Instant,
+    pub expires_at: Option<Instant>, // Some for ephemeral ciphertexts, they are freed after this unless something references them
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Default)]
+pub struct GcReport {
+    pub scanned: usize,
+    pub referenced: usize,
+    pub freed: usize,
+    pub reclaimed_bytes: u64,
+    pub remaining: usize,
+}
+
+// each account sits behind its own lock. holding it for a whole read-modify-write is what makes an account
+// operation atomic, while different users never wait on each other. the outer map is only written on user creation.
+// lock order when an operation touches several accounts: the user first, the insurance fund last
 pub struct AccountCache {
-    users: HashMap<u128, User>,
+    users: RwLock<HashMap<u128, UserHandle>>,
 }
 
+pub type UserHandle = Arc<Mutex<User>>;
+
 #[derive(Clone)]
 pub struct PositionCache {
     pub n: u128,
@@ -27,82 +94,151 @@ pub struct PositionCache {
 
 impl AccountCache {
     pub fn new() -> Self {
+        let mut users = HashMap::new();
+        users.insert(INSURANCE_FUND_ID, Arc::new(Mutex::new(create_user(INSURANCE_FUND_ID))));
         Self {
-            users: HashMap::new(),
+            users: RwLock::new(users),
         }
     }
 
-    pub fn add_user(&mut self, user: User) -> bool {
-        if self.users.contains_key(&user.id) {
+    pub fn add_user(&self, user: User) -> bool {
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(&user.id) {
             false // User already exists
         } else {
-            self.users.insert(user.id, user);
+            users.insert(user.id, Arc::new(Mutex::new(user)));
             true // User added successfully
         }
     }
 
-    pub fn update_balance(&mut self, user_id: u128, key: [u8;32]) {
-        self.users.get_mut(&user_id).unwrap().balance = key;
+    // lock the returned handle for the duration of the account operation
+    pub fn get_user(&self, user_id: u128) -> Option<UserHandle> {
+        self.users.read().unwrap().get(&user_id).cloned()
     }
 
-    pub fn get_user(&mut self, user_id: u128) -> Option<&mut User> {
-        self.users.get_mut(&user_id)
-    }
-
-    pub fn get_balance(&self, user_id: u128) -> Option<&[u8;32]> {
-        self.users.get(&user_id).map(|user| &user.balance)
+    pub fn user_exists(&self, user_id: u128) -> bool {
+        self.users.read().unwrap().contains_key(&user_id)
     }
 
-    pub fn user_exists(&self, user_id: u128) -> bool {
-        self.users.contains_key(&user_id)
+    pub fn get_all_user_ids(&self) -> Vec<u128> {
+        self.users.read().unwrap().keys().copied().collect()
     }
 
-    pub fn get_all_users(&self) -> &HashMap<u128, User> {
-        &self.users
+    pub fn get_insurance_fund(&self) -> UserHandle {
+        self.get_user(INSURANCE_FUND_ID).unwrap()
     }
 
-    pub fn add_position(&mut self, user_id: u128, position: Position) {
-        self.users.get_mut(&user_id).unwrap().positions.push(position);
+    // every ciphertext key reachable from an account, balances and the keys held by open positions
+    pub async fn referenced_ciphertexts(&self) -> HashSet<CiphertextHandle> {
+        let users: Vec<UserHandle> = self.users.read().unwrap().values().cloned().collect();
+        let mut referenced = HashSet::new();
+        for user in users {
+            referenced.extend(user.lock().await.ciphertext_keys());
+        }
+        referenced
     }
     
 }
 
-pub type SharedAccountCache = Arc<Mutex<AccountCache>>;
+pub type SharedAccountCache = Arc<AccountCache>;
 
-#[derive(Clone)]
+// ciphertexts are spread over shards by the first byte of their key so lookups for unrelated users
+// dont contend. the shard locks are only held for the map operation, never across FHE work
 pub struct CiphertextCache {
-    ciphertexts: HashMap<[u8;32], Ciphertext>,
+    shards: Vec<RwLock<HashMap<CiphertextHandle, Ciphertext>>>,
 }
 
 impl CiphertextCache {
     pub fn new() -> Self {
         Self {
-            ciphertexts: HashMap::new(),
+            shards: (0..CIPHERTEXT_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
         }
     }
+
+    fn shard(&self, key: &CiphertextHandle) -> &RwLock<HashMap<CiphertextHandle, Ciphertext>> {
+        &self.shards[key.shard_byte() as usize % CIPHERTEXT_SHARDS]
+    }
     
-    pub fn add_ciphertext(&mut self, key: [u8;32], owner: u128, value: FheUint64) -> bool {
-        if self.ciphertexts.contains_key(&key) {
+    pub fn add_ciphertext(&self, key: CiphertextHandle, owner: u128, value: impl Into<EncryptedValue>) -> bool {
+        let value = value.into();
+        let mut ciphertexts = self.shard(&key).write().unwrap();
+        if ciphertexts.contains_key(&key) || value.fhe_type() != key.fhe_type {
             false // Ciphertext already exists
         } else {
-            self.ciphertexts.insert(key, Ciphertext { key, owner, ciphertext: value });
+            ciphertexts.insert(key, Ciphertext { key, owner, ciphertext: value, created_at: Instant::now(), expires_at: None });
             true // Ciphertext added successfully
         }
     }
 
-    pub fn update_ciphertext(&mut self, key: [u8;32], owner: u128, value: FheUint64) -> bool {
-        if !self.ciphertexts.contains_key(&key) {
-            println!("update attempt failed: Ciphertext does not exist");
-            false // Ciphertext does not exist
+    // stored with a TTL, the collector frees it once it expires unless a user or position has picked it up
+    pub fn add_ephemeral_ciphertext(&self, key: CiphertextHandle, owner: u128, value: impl Into<EncryptedValue>, ttl: Duration) -> bool {
+        let value = value.into();
+        let mut ciphertexts = self.shard(&key).write().unwrap();
+        if ciphertexts.contains_key(&key) || value.fhe_type() != key.fhe_type {
+            false
         } else {
-            self.ciphertexts.insert(key, Ciphertext { key, owner, ciphertext: value });   
+            let created_at = Instant::now();
+            ciphertexts.insert(key, Ciphertext { key, owner, ciphertext: value, created_at, expires_at: Some(created_at + ttl) });
+            true
+        }
+    }
+
+    pub fn update_ciphertext(&self, key: CiphertextHandle, owner: u128, value: impl Into<EncryptedValue>) -> bool {
+        let value = value.into();
+        if value.fhe_type() != key.fhe_type {
+            println!("update attempt failed: {:?} value for a {:?} handle", value.fhe_type(), key.fhe_type);
+            return false;
+        }
+        let mut ciphertexts = self.shard(&key).write().unwrap();
+        if let Some(existing) = ciphertexts.get_mut(&key) {
+            existing.owner = owner;
+            existing.ciphertext = value;
             println!("update attempt successful: Ciphertext updated");
             true // Ciphertext updated successfully
+        } else {
+            println!("update attempt failed: Ciphertext does not exist");
+            false // Ciphertext does not exist
         }
     }
 
-    pub fn get_ciphertext(&self, key: [u8;32]) -> Option<&Ciphertext> {
-        self.ciphertexts.get(&key)
+    pub fn get_ciphertext(&self, key: CiphertextHandle) -> Option<Ciphertext> {
+        self.shard(&key).read().unwrap().get(&key).cloned()
+    }
+
+    pub fn get_u8(&self, key: CiphertextHandle) -> Option<FheUint8> {
+        self.get_ciphertext(key)?.ciphertext.into_u8()
+    }
+
+    pub fn get_u64(&self, key: CiphertextHandle) -> Option<FheUint64> {
+        self.get_ciphertext(key)?.ciphertext.into_u64()
+    }
+
+    // sweep: frees every ciphertext nothing references that is either an expired ephemeral or a persistent one
+    // past the grace period. reclaimed memory is measured as the serialized size of what we dropped
+    pub fn collect_garbage(&self, referenced: &HashSet<CiphertextHandle>) -> GcReport {
+        let now = Instant::now();
+        let mut report = GcReport::default();
+        for shard in self.shards.iter() {
+            let mut ciphertexts = shard.write().unwrap();
+            report.scanned += ciphertexts.len();
+            let collectable: Vec<CiphertextHandle> = ciphertexts.values()
+                .filter(|ciphertext| !referenced.contains(&ciphertext.key))
+                .filter(|ciphertext| match ciphertext.expires_at {
+                    Some(expires_at) => expires_at <= now,
+                    None => now.duration_since(ciphertext.created_at) >= GC_GRACE_PERIOD,
+                })
+                .map(|ciphertext| ciphertext.key)
+                .collect();
+            for key in collectable {
+                if let Some(ciphertext) = ciphertexts.remove(&key) {
+                    report.freed += 1;
+                    report.reclaimed_bytes += bincode::serialized_size(&ciphertext.ciphertext).unwrap_or(0);
+                }
+            }
+            report.remaining += ciphertexts.len();
+        }
+        report.referenced = referenced.len();
+        report
     }
        
 }
@@ -116,8 +252,13 @@ impl PositionCache {
         }
     }
 
-    pub fn add_position(&mut self, position: Position) {
+    pub fn next_id(&mut self) -> u128 {
+        let id = self.n;
         self.n += 1;
+        id
+    }
+
+    pub fn add_position(&mut self, position: Position) {
         if position.direction {
             self.long_positions.push(position);
         } else {
@@ -133,4 +274,25 @@ impl PositionCache {
         }
     }
 
+    pub fn get_positions(&self, direction: bool) -> &Vec<Position> {
+        if direction {
+            &self.long_positions
+        } else {
+            &self.short_positions
+        }
+    }
+
+    pub fn update_position(&mut self, position: Position) {
+        let positions = if position.direction { &mut self.long_positions } else { &mut self.short_positions };
+        if let Some(existing) = positions.iter_mut().find(|p| p.id == position.id) {
+            *existing = position;
+        }
+    }
+
+    pub fn remove_position(&mut self, id: u128, direction: bool) -> Option<Position> {
+        let positions = if direction { &mut self.long_positions } else { &mut self.short_positions };
+        let index = positions.iter().position(|position| position.id == id)?;
+        Some(positions.remove(index))
+    }
+
 }
\ No newline at end of file
@@ -1,14 +1,29 @@
 use crate::liqudation::users::User;
 use serde::{Deserialize, Serialize};
-use axum::{Json, http::StatusCode, extract::{State, Path}};
+use axum::{Json, http::{StatusCode, HeaderMap, header}, extract::{State, Path, Query}, body::Bytes, response::{IntoResponse, Response}};
 use crate::AppState;
 use rand::Rng;
-use crate::fhe::circuits::{health_check_long_circuit, funding_rate_long_pay_short_circuit};
+use crate::fhe::circuits::{health_check_long_circuit, funding_rate_long_pay_short_circuit, partial_liquidation_circuit, open_interest_circuit, skew_circuit, LiquidationOutcome};
 use tfhe::{
+    FheUint8,
     FheUint64,
     CompressedCiphertextListBuilder,
-    set_server_key,
 };
+use crate::fhe::pool::{PoolError, PoolMetricsSnapshot};
+#[cfg(feature = "bench-endpoints")]
+use crate::fhe::bench::{circuit_timings, CircuitTiming, dark_book_timings, DarkBookTiming, MAX_CIRCUIT_BENCH_ITERATIONS, MAX_DARK_BOOK_BENCH_ITERATIONS, MAX_DARK_BOOK_BENCH_DEPTH, MAX_BENCH_DEPTHS};
+#[cfg(feature = "bench-endpoints")]
+use crate::orderbook::bench::{clob_timings, ClobTiming, MAX_CLOB_BENCH_DEPTH, MAX_CLOB_BENCH_OPERATIONS, MAX_CLOB_BENCH_DEPTHS};
+use crate::liqudation::cache::{GcReport, EncryptedValue, EPHEMERAL_CIPHERTEXT_TTL};
+use crate::liqudation::internal::collect_ciphertexts;
+use crate::fhe::handle::{CiphertextHandle, FheType, HandleError};
+use crate::fhe::serialization::{export_ciphertext, import_ciphertext, MAX_IMPORT_BYTES};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use crate::market::decimal::{Decimal, mul_div, Rounding};
+use crate::market::registry::MarketConfig;
+use crate::market::mark::PREMIUM_SCALE;
+use crate::market::open_interest::Skew;
+use crate::orderbook::clob::now_millis;
 use tfhe::prelude::*;
 
 
@@ -20,29 +35,30 @@ pub struct EncryptRequest {
 
 #[derive(Serialize)]
 pub struct EncryptResponse {
-    pub ciphertext: [u8;32],
+    pub ciphertext: CiphertextHandle,
 }
 
 #[derive(Serialize)]
 pub struct GetCiphertextResponse {
-    pub ciphertext: FheUint64,
+    pub ciphertext: EncryptedValue,
 }
 
 #[derive(Deserialize)]
 pub struct HealthCheckRequest {
     pub position_id: u128,
-    pub mark_price: u64,
+    pub mark_price: Decimal,
 }
 
 #[derive(Serialize)]
 pub struct HealthCheckResponse {
     pub status: String,
+    pub result: Option<CiphertextHandle>, // the encrypted FheBool behind status
 }
 
 #[derive(Deserialize)]
 pub struct FundingRateLPSRequest {
     pub position_id: u128,
-    pub delta_percent: u64,
+    pub delta_percent: Option<u64>, // left out to charge the markets premium index
 }
 
 #[derive(Serialize)]
@@ -50,6 +66,129 @@ pub struct FundingRateLPSResponse {
     pub status: String,
 }
 
+#[derive(Deserialize)]
+pub struct LiquidationRequest {
+    pub position_id: u128,
+    pub mark_price: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct LiquidationResponse {
+    pub status: String,
+    pub closed_notional: Decimal,
+    pub remaining_notional: Decimal,
+    pub deleveraged_positions: Vec<u128>,
+}
+
+impl LiquidationResponse {
+    fn status(status: &str) -> Self {
+        Self { status: status.to_string(), closed_notional: Decimal::from_raw(0, 0), remaining_notional: Decimal::from_raw(0, 0), deleveraged_positions: vec![] }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MarkPriceRequest {
+    pub market: u32,
+    pub price: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct MarkPriceResponse {
+    pub status: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenInterestReveal {
+    Totals,
+    #[default]
+    Skew,
+}
+
+#[derive(Deserialize)]
+pub struct OpenInterestQuery {
+    #[serde(default)]
+    pub reveal: OpenInterestReveal,
+}
+
+#[derive(Serialize)]
+pub struct OpenInterestResponse {
+    pub market: u32,
+    pub long: Option<Decimal>, // only for a totals reveal
+    pub short: Option<Decimal>,
+    pub skew: Skew,
+    pub revealed_at: u64, // unix millis, reveals are reused for a minute
+}
+
+#[derive(Deserialize)]
+pub struct IndexComponentRequest {
+    pub source: String,
+    pub price: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct IndexPriceRequest {
+    pub market: u32,
+    pub components: Vec<IndexComponentRequest>,
+}
+
+#[derive(Serialize)]
+pub struct IndexComponentResponse {
+    pub source: String,
+    pub price: Decimal,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize)]
+pub struct PricesResponse {
+    pub market: u32,
+    pub index: Option<Decimal>,
+    pub mark: Option<Decimal>,
+    pub impact_bid: Option<Decimal>,
+    pub impact_ask: Option<Decimal>,
+    pub last_trade: Option<Decimal>,
+    pub premium: Option<Decimal>, // fraction of the index, 0.0001 is 1bp
+    pub premium_index: Option<Decimal>,
+    pub components: Vec<IndexComponentResponse>,
+    pub updated_at: u64,
+}
+
+#[cfg(feature = "bench-endpoints")]
+#[derive(Deserialize)]
+pub struct BenchCircuitsRequest {
+    pub iterations: u32,
+}
+
+#[cfg(feature = "bench-endpoints")]
+#[derive(Deserialize)]
+pub struct BenchDarkBookRequest {
+    pub depths: Vec<usize>,
+    pub iterations: u32,
+}
+
+#[cfg(feature = "bench-endpoints")]
+#[derive(Deserialize)]
+pub struct BenchClobRequest {
+    pub depths: Vec<usize>,
+    pub operations: usize,
+}
+
+#[derive(Serialize)]
+pub struct InsuranceFundResponse {
+    pub plaintext: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct ImportCiphertextQuery {
+    pub user_id: u128,
+}
+
+#[derive(Serialize)]
+pub struct ImportCiphertextResponse {
+    pub ciphertext: Option<CiphertextHandle>,
+    pub message: String,
+}
+
 
 
 //////////////////////////////////////////////////////////// Handlers ////////////////////////////////////////////////////////////
@@ -57,46 +196,214 @@ pub struct FundingRateLPSResponse {
 pub async fn encrypt_handler(
     State(state): State<AppState>,
     Json(payload): Json<EncryptRequest>
-) -> (StatusCode, Json<EncryptResponse>) {
-    let random_bytes: [u8; 32] = rand::random();
-    let hold_ciphertext = FheUint64::encrypt(payload.amount, &*state.client_key);
-    state.ciphertext_cache.lock().await.add_ciphertext(random_bytes, payload.user_id, hold_ciphertext);
-    (StatusCode::OK, Json(EncryptResponse { ciphertext: random_bytes }))
+) -> Result<(StatusCode, Json<EncryptResponse>), (StatusCode, String)> {
+    let handle = CiphertextHandle::new(FheType::U64, state.key_set);
+    let client_key = state.client_key.clone();
+    let hold_ciphertext = state.fhe_pool.run(move || FheUint64::encrypt(payload.amount, &*client_key)).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    state.ciphertext_cache.add_ephemeral_ciphertext(handle, payload.user_id, hold_ciphertext, EPHEMERAL_CIPHERTEXT_TTL);
+    Ok((StatusCode::OK, Json(EncryptResponse { ciphertext: handle })))
 }
 
-pub async fn _encrypt_helper(State(state): State<AppState>, amount: u64, user_id: u128) -> [u8;32] {
-    let random_bytes: [u8; 32] = rand::random();
-    let hold_ciphertext = FheUint64::encrypt(amount, &*state.client_key);
-    state.ciphertext_cache.lock().await.add_ciphertext(random_bytes, user_id, hold_ciphertext);
-    random_bytes
+pub async fn _encrypt_helper(State(state): State<AppState>, amount: u64, user_id: u128) -> Result<CiphertextHandle, PoolError> {
+    let handle = CiphertextHandle::new(FheType::U64, state.key_set);
+    let client_key = state.client_key.clone();
+    let hold_ciphertext = state.fhe_pool.run(move || FheUint64::encrypt(amount, &*client_key)).await?;
+    state.ciphertext_cache.add_ciphertext(handle, user_id, hold_ciphertext);
+    Ok(handle)
 }
 
-pub async fn _encrypt_from_FheUint64(State(state): State<AppState>, amount: FheUint64, user_id: u128) -> [u8;32] {
-    let random_bytes: [u8; 32] = rand::random();
+pub async fn _encrypt_u8_helper(State(state): State<AppState>, amount: u8, user_id: u128) -> Result<CiphertextHandle, PoolError> {
+    let handle = CiphertextHandle::new(FheType::U8, state.key_set);
+    let client_key = state.client_key.clone();
+    let hold_ciphertext = state.fhe_pool.run(move || FheUint8::encrypt(amount, &*client_key)).await?;
+    state.ciphertext_cache.add_ciphertext(handle, user_id, hold_ciphertext);
+    Ok(handle)
+}
+
+pub async fn _encrypt_from_FheUint64(State(state): State<AppState>, amount: FheUint64, user_id: u128) -> CiphertextHandle {
+    let handle = CiphertextHandle::new(FheType::U64, state.key_set);
     let hold_ciphertext = amount;
-    state.ciphertext_cache.lock().await.add_ciphertext(random_bytes, user_id, hold_ciphertext);
-    random_bytes
+    state.ciphertext_cache.add_ciphertext(handle, user_id, hold_ciphertext);
+    handle
+}
+
+// for results handed back to the client (pnl, equity, health checks) that no account keeps a reference to
+pub async fn _ephemeral_from_value(State(state): State<AppState>, value: impl Into<EncryptedValue>, user_id: u128) -> CiphertextHandle {
+    let value = value.into();
+    let handle = CiphertextHandle::new(value.fhe_type(), state.key_set);
+    state.ciphertext_cache.add_ephemeral_ciphertext(handle, user_id, value, EPHEMERAL_CIPHERTEXT_TTL);
+    handle
 }
 
 pub async fn get_ciphertext_handler(
     State(state): State<AppState>,
-    Path(ciphertext_key): Path<[u8;32]>
-) -> (StatusCode, Json<GetCiphertextResponse>) {
-    let ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(ciphertext_key).unwrap().clone();
-    (StatusCode::OK, Json(GetCiphertextResponse { ciphertext: ciphertext.ciphertext.clone() }))
+    Path(ciphertext_key): Path<String>
+) -> Result<(StatusCode, Json<GetCiphertextResponse>), HandleError> {
+    // parsed here rather than by the extractor so a bad handle comes back as a HandleError
+    let ciphertext_key: CiphertextHandle = ciphertext_key.parse()?;
+    ciphertext_key.expect_key_set(state.key_set)?;
+    let ciphertext = state.ciphertext_cache.get_ciphertext(ciphertext_key).ok_or(HandleError::NotFound)?;
+    Ok((StatusCode::OK, Json(GetCiphertextResponse { ciphertext: ciphertext.ciphertext.clone() })))
+}
+
+// client price -> raw units of the positions market
+fn market_price(state: &AppState, market: u32, price: &Decimal) -> Result<u64, String> {
+    let market = state.markets.get(market).ok_or("Unknown market")?;
+    market.price(price).map_err(|e| e.to_string())
+}
+
+pub async fn markets_handler(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<Vec<MarketConfig>>) {
+    (StatusCode::OK, Json(state.markets.get_all()))
+}
+
+// oracle push, the price loop picks the new mark up on its next tick. the mark drives triggers, closes and
+// liquidations, so only the operator gets to set it
+pub async fn set_mark_price_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<MarkPriceRequest>
+) -> (StatusCode, Json<MarkPriceResponse>) {
+    if let Err((status, message)) = state.check_admin(&headers) {
+        return (status, Json(MarkPriceResponse { status: message }));
+    }
+    match market_price(&state, payload.market, &payload.price) {
+        Ok(price) => {
+            state.mark_prices.set(payload.market, price);
+            (StatusCode::OK, Json(MarkPriceResponse { status: "Mark price updated".to_string() }))
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(MarkPriceResponse { status: e })),
+    }
+}
+
+// oracle push of some or all index sources, same as /mark_price the price loop works the new index in on its next tick.
+// operator only, and only the markets configured sources count, so nobody can stuff the median with made up ones
+pub async fn set_index_price_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<IndexPriceRequest>
+) -> (StatusCode, Json<MarkPriceResponse>) {
+    if let Err((status, message)) = state.check_admin(&headers) {
+        return (status, Json(MarkPriceResponse { status: message }));
+    }
+    let Some(market) = state.markets.get(payload.market) else {
+        return (StatusCode::BAD_REQUEST, Json(MarkPriceResponse { status: "Unknown market".to_string() }));
+    };
+    if let Some(component) = payload.components.iter().find(|component| !market.index_sources.contains(&component.source)) {
+        return (StatusCode::BAD_REQUEST, Json(MarkPriceResponse { status: format!("Unknown index source {} for market {}", component.source, payload.market) }));
+    }
+    let mut components = Vec::new();
+    for component in payload.components.iter() {
+        match market_price(&state, payload.market, &component.price) {
+            Ok(price) => components.push((component.source.clone(), price)),
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(MarkPriceResponse { status: e })),
+        }
+    }
+    state.mark_prices.set_components(payload.market, components, now_millis());
+    (StatusCode::OK, Json(MarkPriceResponse { status: "Index components updated".to_string() }))
+}
+
+pub async fn prices_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>
+) -> Result<(StatusCode, Json<PricesResponse>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let prices = state.mark_prices.snapshot(market_id).unwrap_or_default();
+    let price = |raw: Option<u64>| raw.map(|raw| market.price_decimal(raw));
+    let premium = |raw: Option<i64>| raw.map(|raw| Decimal::from_signed_raw(raw as i128, PREMIUM_SCALE.ilog10()));
+    Ok((StatusCode::OK, Json(PricesResponse {
+        market: market_id,
+        index: price(prices.index),
+        mark: price(prices.mark),
+        impact_bid: price(prices.impact_bid),
+        impact_ask: price(prices.impact_ask),
+        last_trade: price(prices.last_trade),
+        premium: premium(prices.premium),
+        premium_index: premium(prices.premium_index),
+        components: prices.components.iter().map(|component| IndexComponentResponse {
+            source: component.source.clone(),
+            price: market.price_decimal(component.price),
+            timestamp: component.timestamp,
+        }).collect(),
+        updated_at: prices.updated_at,
+    })))
+}
+
+// the controlled way to read open interest, decrypts either the two totals or just which side is bigger. within a
+// minute of the last reveal the same answer is handed out again without decrypting anything
+pub async fn open_interest_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<OpenInterestQuery>
+) -> Result<(StatusCode, Json<OpenInterestResponse>), (StatusCode, String)> {
+    state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let now = now_millis();
+    let response = |long: Option<u64>, short: Option<u64>, skew: Skew, revealed_at: u64| (StatusCode::OK, Json(OpenInterestResponse {
+        market: market_id,
+        long: long.map(|long| state.markets.collateral_decimal(long)),
+        short: short.map(|short| state.markets.collateral_decimal(short)),
+        skew,
+        revealed_at,
+    }));
+    match query.reveal {
+        OpenInterestReveal::Totals => {
+            let cached = state.open_interest.lock().await.revealed_totals(market_id, now);
+            let (long, short, revealed_at) = match cached {
+                Some(totals) => totals,
+                None => {
+                    let (long, short) = open_interest_circuit(&state, market_id).await
+                        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                    println!("Revealed open interest on market {}: {} long, {} short", market_id, long, short);
+                    state.open_interest.lock().await.record_totals(market_id, long, short, now);
+                    (long, short, now)
+                }
+            };
+            let skew = match long.cmp(&short) {
+                std::cmp::Ordering::Greater => Skew::Long,
+                std::cmp::Ordering::Less => Skew::Short,
+                std::cmp::Ordering::Equal => Skew::Balanced,
+            };
+            Ok(response(Some(long), Some(short), skew, revealed_at))
+        }
+        OpenInterestReveal::Skew => {
+            let cached = state.open_interest.lock().await.revealed_skew(market_id, now);
+            let (skew, revealed_at) = match cached {
+                Some(skew) => skew,
+                None => {
+                    let skew = skew_circuit(&state, market_id).await
+                        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                    println!("Revealed open interest skew on market {}: {:?}", market_id, skew);
+                    state.open_interest.lock().await.record_skew(market_id, skew, now);
+                    (skew, now)
+                }
+            };
+            Ok(response(None, None, skew, revealed_at))
+        }
+    }
 }
 
 pub async fn health_check_long_handler(
     State(state): State<AppState>,
     Json(payload): Json<HealthCheckRequest>
 ) -> (StatusCode, Json<HealthCheckResponse>) { //for now lets just check the first long position in the array
-    let position = state.position_cache.lock().await.get_position(0, true).unwrap().clone(); // just get first for now
-    let liqdation_price_ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(position.liqudation_price).unwrap().ciphertext.clone();
-    let result = health_check_long_circuit(&state, liqdation_price_ciphertext, payload.mark_price).await;
+    let position = state.position_cache.read().await.get_position(0, true).unwrap().clone(); // just get first for now
+    let mark_price = match market_price(&state, position.market, &payload.mark_price) {
+        Ok(mark_price) => mark_price,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(HealthCheckResponse { status: e, result: None })),
+    };
+    let liqdation_price_ciphertext = state.ciphertext_cache.get_u64(position.liqudation_price).unwrap();
+    let healthy = match health_check_long_circuit(&state, liqdation_price_ciphertext, mark_price).await {
+        Ok(healthy) => healthy,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(HealthCheckResponse { status: e.to_string(), result: None })),
+    };
+    let result: bool = healthy.decrypt(&state.client_key);
+    let result_key = _ephemeral_from_value(State(state.clone()), healthy, position.owner).await;
     if result { 
-        (StatusCode::OK, Json(HealthCheckResponse { status: "Solvent".to_string() }))
+        (StatusCode::OK, Json(HealthCheckResponse { status: "Solvent".to_string(), result: Some(result_key) }))
     } else {
-        (StatusCode::BAD_REQUEST, Json(HealthCheckResponse { status: "Insolvent".to_string() }))
+        (StatusCode::BAD_REQUEST, Json(HealthCheckResponse { status: "Insolvent".to_string(), result: Some(result_key) }))
     }
 } 
 
@@ -105,13 +412,20 @@ pub async fn funding_rate_long_pay_short_handler(
     Json(payload): Json<FundingRateLPSRequest>
 ) -> (StatusCode, Json<FundingRateLPSResponse>) {
     println!("Funding rate long pay short handler called");
-    let position = state.position_cache.lock().await.get_position(payload.position_id, true).unwrap().clone();
+    let position = state.position_cache.read().await.get_position(payload.position_id, true).unwrap().clone();
     println!("Position liquidation price key: {:?}", position.liqudation_price);
     
-    let ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(position.liqudation_price).unwrap().clone();
+    let ciphertext = state.ciphertext_cache.get_ciphertext(position.liqudation_price).unwrap();
     println!("Ciphertext found with key: {:?}", ciphertext.key);
     
-    let delta = position.notional * payload.delta_percent / 100; 
+    // the liquidation price is in price units. longs only pay while the premium is positive
+    let delta = match payload.delta_percent {
+        Some(delta_percent) => mul_div(position.entry_price, delta_percent, 100, Rounding::Down),
+        None => match state.mark_prices.premium_index(position.market) {
+            Some(premium) => mul_div(position.entry_price, premium.max(0) as u64, PREMIUM_SCALE as u64, Rounding::Down),
+            None => return (StatusCode::SERVICE_UNAVAILABLE, Json(FundingRateLPSResponse { status: "No premium index for this market yet".to_string() })),
+        },
+    };
     println!("Calculated delta: {}", delta);
     
     funding_rate_long_pay_short_circuit(
@@ -122,5 +436,158 @@ pub async fn funding_rate_long_pay_short_handler(
     (StatusCode::OK, Json(FundingRateLPSResponse { status: "Success".to_string() }))
 }
 
+pub async fn liquidate_long_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<LiquidationRequest>
+) -> (StatusCode, Json<LiquidationResponse>) {
+    let position = match state.position_cache.read().await.get_position(payload.position_id, true) {
+        Some(position) => position.clone(),
+        None => return (StatusCode::NOT_FOUND, Json(LiquidationResponse::status("Position not found"))),
+    };
+    let mark_price = match market_price(&state, position.market, &payload.mark_price) {
+        Ok(mark_price) => mark_price,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(LiquidationResponse::status(&e))),
+    };
+    let notional = position.notional;
+    match partial_liquidation_circuit(&state, position, mark_price).await {
+        Ok(LiquidationOutcome::Healthy) => (StatusCode::BAD_REQUEST, Json(LiquidationResponse::status("Solvent"))),
+        Ok(LiquidationOutcome::Partial { closed_notional, remaining_notional }) => (StatusCode::OK, Json(LiquidationResponse {
+            closed_notional: state.markets.collateral_decimal(closed_notional),
+            remaining_notional: state.markets.collateral_decimal(remaining_notional),
+            ..LiquidationResponse::status("Partially liquidated")
+        })),
+        Ok(LiquidationOutcome::Full { deleveraged_positions }) => (StatusCode::OK, Json(LiquidationResponse {
+            closed_notional: state.markets.collateral_decimal(notional),
+            deleveraged_positions,
+            ..LiquidationResponse::status("Liquidated")
+        })),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(LiquidationResponse::status(&e.to_string()))),
+    }
+}
+
+pub async fn insurance_fund_handler(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<InsuranceFundResponse>) {
+    let Some(balance) = state.user_cache.get_insurance_fund().lock().await.balance else {
+        return (StatusCode::OK, Json(InsuranceFundResponse { plaintext: state.markets.collateral_decimal(0) }));
+    };
+    let decrypted: u64 = state.ciphertext_cache.get_u64(balance).unwrap().decrypt(&state.client_key);
+    (StatusCode::OK, Json(InsuranceFundResponse { plaintext: state.markets.collateral_decimal(decrypted) }))
+}
+
+pub async fn fhe_pool_metrics_handler(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<PoolMetricsSnapshot>) {
+    (StatusCode::OK, Json(state.fhe_pool.metrics()))
+}
+
+#[cfg(feature = "bench-endpoints")]
+pub async fn bench_circuits_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<BenchCircuitsRequest>
+) -> Result<(StatusCode, Json<Vec<CircuitTiming>>), (StatusCode, String)> {
+    if payload.iterations == 0 || payload.iterations > MAX_CIRCUIT_BENCH_ITERATIONS {
+        return Err((StatusCode::BAD_REQUEST, format!("iterations has to be between 1 and {}", MAX_CIRCUIT_BENCH_ITERATIONS)));
+    }
+    let timings = circuit_timings(&state, payload.iterations).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(timings)))
+}
+
+#[cfg(feature = "bench-endpoints")]
+pub async fn bench_dark_book_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<BenchDarkBookRequest>
+) -> Result<(StatusCode, Json<Vec<DarkBookTiming>>), (StatusCode, String)> {
+    if payload.iterations == 0 || payload.iterations > MAX_DARK_BOOK_BENCH_ITERATIONS {
+        return Err((StatusCode::BAD_REQUEST, format!("iterations has to be between 1 and {}", MAX_DARK_BOOK_BENCH_ITERATIONS)));
+    }
+    if payload.depths.len() > MAX_BENCH_DEPTHS || payload.depths.iter().any(|depth| *depth > MAX_DARK_BOOK_BENCH_DEPTH) {
+        return Err((StatusCode::BAD_REQUEST, format!("at most {} depths of up to {} orders", MAX_BENCH_DEPTHS, MAX_DARK_BOOK_BENCH_DEPTH)));
+    }
+    let timings = dark_book_timings(&state, payload.depths, payload.iterations).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(timings)))
+}
+
+// plaintext work, but enough of it that it shouldnt sit on the async runtime
+#[cfg(feature = "bench-endpoints")]
+pub async fn bench_clob_handler(
+    Json(payload): Json<BenchClobRequest>
+) -> Result<(StatusCode, Json<Vec<ClobTiming>>), (StatusCode, String)> {
+    if payload.operations == 0 || payload.operations > MAX_CLOB_BENCH_OPERATIONS {
+        return Err((StatusCode::BAD_REQUEST, format!("operations has to be between 1 and {}", MAX_CLOB_BENCH_OPERATIONS)));
+    }
+    if payload.depths.len() > MAX_CLOB_BENCH_DEPTHS || payload.depths.iter().any(|depth| *depth > MAX_CLOB_BENCH_DEPTH) {
+        return Err((StatusCode::BAD_REQUEST, format!("at most {} depths of up to {} orders", MAX_CLOB_BENCH_DEPTHS, MAX_CLOB_BENCH_DEPTH)));
+    }
+    let timings = tokio::task::spawn_blocking(move || clob_timings(payload.depths, payload.operations)).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("CLOB bench failed: {}", e)))?;
+    Ok((StatusCode::OK, Json(timings)))
+}
+
+// the loop collects every 30s anyway, an on demand sweep locks every cache and book so its operator only
+pub async fn collect_garbage_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<GcReport>), (StatusCode, String)> {
+    state.check_admin(&headers)?;
+    Ok((StatusCode::OK, Json(collect_ciphertexts(&state).await)))
+}
 
+// raw bytes unless the client asks for base64 with text/plain
+fn wants_base64(headers: &HeaderMap, name: header::HeaderName) -> bool {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("text/plain") || value.contains("application/base64"))
+        .unwrap_or(false)
+}
 
+pub async fn export_ciphertext_handler(
+    State(state): State<AppState>,
+    Path(ciphertext_key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, HandleError> {
+    let ciphertext_key: CiphertextHandle = ciphertext_key.parse()?;
+    ciphertext_key.expect_key_set(state.key_set)?;
+    let ciphertext = state.ciphertext_cache.get_ciphertext(ciphertext_key).ok_or(HandleError::NotFound)?;
+    let bytes = match export_ciphertext(&state, ciphertext.ciphertext).await {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok((StatusCode::INTERNAL_SERVER_ERROR, e).into_response()),
+    };
+    if wants_base64(&headers, header::ACCEPT) {
+        Ok((StatusCode::OK, [(header::CONTENT_TYPE, "text/plain")], BASE64.encode(bytes)).into_response())
+    } else {
+        Ok((StatusCode::OK, [(header::CONTENT_TYPE, "application/octet-stream")], bytes).into_response())
+    }
+}
+
+// stored as an ephemeral ciphertext owned by user_id, it only sticks around if something picks up the handle
+pub async fn import_ciphertext_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ImportCiphertextQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<ImportCiphertextResponse>) {
+    let reject = |status: StatusCode, message: String| (status, Json(ImportCiphertextResponse { ciphertext: None, message }));
+    if body.len() > MAX_IMPORT_BYTES {
+        return reject(StatusCode::PAYLOAD_TOO_LARGE, format!("Body is {} bytes, limit is {}", body.len(), MAX_IMPORT_BYTES));
+    }
+    let bytes = if wants_base64(&headers, header::CONTENT_TYPE) {
+        match BASE64.decode(body.trim_ascii()) {
+            Ok(bytes) => bytes,
+            Err(e) => return reject(StatusCode::BAD_REQUEST, format!("Invalid base64: {}", e)),
+        }
+    } else {
+        body.to_vec()
+    };
+    if !state.user_cache.user_exists(query.user_id) {
+        return reject(StatusCode::NOT_FOUND, "User not found".to_string());
+    }
+    let ciphertext = match import_ciphertext(&state, bytes).await {
+        Ok(ciphertext) => ciphertext,
+        Err(e) => return reject(StatusCode::UNPROCESSABLE_ENTITY, e),
+    };
+    let handle = _ephemeral_from_value(State(state.clone()), ciphertext, query.user_id).await;
+    (StatusCode::CREATED, Json(ImportCiphertextResponse { ciphertext: Some(handle), message: "Ciphertext imported".to_string() }))
+}
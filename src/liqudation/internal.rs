@@ -1,2 +1,91 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use crate::AppState;
+use crate::liqudation::cache::GcReport;
+use crate::liqudation::users::Position;
+use crate::orderbook::engine::OrderBooks;
+use crate::fhe::circuits::{trigger_circuit, close_position_circuit};
+use crate::fhe::handle::CiphertextHandle;
+use crate::orderbook::clob::now_millis;
 
+const GC_INTERVAL: Duration = Duration::from_secs(30);
+const PRICE_LOOP_INTERVAL: Duration = Duration::from_secs(1);
 
+// mark: every key held by an account, an open position, a dark order (resting, or parked while its match settles)
+// or a sealed order waiting for its auction. sweep: let the cache drop whatever is left that has expired or outlived
+// the grace period
+pub async fn collect_ciphertexts(state: &AppState) -> GcReport {
+    let mut referenced: HashSet<CiphertextHandle> = state.user_cache.referenced_ciphertexts().await;
+    {
+        let position_cache = state.position_cache.read().await;
+        for direction in [true, false] {
+            for position in position_cache.get_positions(direction) {
+                referenced.extend(position.ciphertext_keys());
+            }
+        }
+    }
+    referenced.extend(state.dark_book.read().await.ciphertext_keys());
+    referenced.extend(state.sealed_auctions.lock().await.ciphertext_keys());
+    state.ciphertext_cache.collect_garbage(&referenced)
+}
+
+pub async fn ciphertext_gc_loop(state: AppState) {
+    let mut interval = tokio::time::interval(GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        let report = collect_ciphertexts(&state).await;
+        if report.freed > 0 {
+            println!("ciphertext gc: freed {} of {} ciphertexts, reclaimed {} bytes", report.freed, report.scanned, report.reclaimed_bytes);
+        }
+    }
+}
+
+// feeds each markets impact prices and last trade into the mark prices, run before anything reads a mark
+fn refresh_prices(state: &AppState, books: &OrderBooks) {
+    let now = now_millis();
+    for market in state.markets.get_all() {
+        let (impact_bid, impact_ask) = match books.get(market.id) {
+            Some(book) => (book.impact_price(true, market.impact_notional), book.impact_price(false, market.impact_notional)),
+            None => (None, None),
+        };
+        let last_trade = books.recent_trades(market.id, 1).first().map(|fill| (fill.price, fill.timestamp));
+        state.mark_prices.update(market.id, impact_bid, impact_ask, last_trade, now);
+    }
+}
+
+// checks every position with a stop loss or take profit against its markets mark and closes the ones that fired
+async fn evaluate_triggers(state: &AppState) {
+    let positions: Vec<Position> = {
+        let position_cache = state.position_cache.read().await;
+        [true, false].iter()
+            .flat_map(|direction| position_cache.get_positions(*direction).iter())
+            .filter(|position| position.has_triggers())
+            .cloned()
+            .collect()
+    };
+    for position in positions {
+        let Some(mark_price) = state.mark_prices.get(position.market) else { continue };
+        let fired = match trigger_circuit(state, &position, mark_price).await {
+            Ok(fired) => fired,
+            Err(e) => {
+                println!("Failed to check triggers for position {}: {}", position.id, e);
+                continue;
+            }
+        };
+        if fired {
+            println!("Trigger fired for position {}", position.id);
+            if let Err(e) = close_position_circuit(state, position.owner, position.id, mark_price).await {
+                println!("Failed to close position {}: {}", position.id, e);
+            }
+        }
+    }
+}
+
+pub async fn price_loop(state: AppState) {
+    let mut interval = tokio::time::interval(PRICE_LOOP_INTERVAL);
+    loop {
+        interval.tick().await;
+        refresh_prices(&state, &*state.order_books.lock().await);
+        evaluate_triggers(&state).await;
+    }
+}
@@ -1,3 +1,4 @@
 pub mod users;
 pub mod handlers;
-pub mod cache;
\ No newline at end of file
+pub mod cache;
+pub mod internal;
\ No newline at end of file
@@ -1,29 +1,87 @@
-use axum::{Json, http::StatusCode, extract::State};
+use axum::{Json, http::{StatusCode, HeaderMap}, extract::State};
 use serde::{Deserialize, Serialize};
 use crate::liqudation::cache::{AccountCache, SharedAccountCache, CiphertextCache};
-use crate::fhe::circuits::{deposit_circuit, open_position_circuit};
+use crate::fhe::circuits::{deposit_circuit, account_equity_circuit, close_position_circuit};
+use crate::orderbook::engine::{place_order, NewOrder};
+use crate::orderbook::clob::OrderOptions;
+use crate::orderbook::handlers::validate_order;
 use tfhe::prelude::FheDecrypt;
-use tfhe::set_server_key;
 use axum::extract::Path;
 use crate::AppState;
-use crate::liqudation::handlers::{_encrypt_helper};
+use crate::liqudation::handlers::{_encrypt_helper, _ephemeral_from_value};
+use crate::fhe::handle::CiphertextHandle;
+use crate::market::decimal::Decimal;
+
+const USER_TOKEN_HEADER: &str = "x-user-token";
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Position {
     pub id: u128,
+    pub owner: u128,
+    pub market: u32,
     pub direction: bool, // true is long 
-    pub notional: u64,
-    pub entry_price: u64,
-    pub leverage: [u8;32],
-    pub initial_margin: [u8;32],
-    pub liqudation_price: [u8;32],
+    pub notional: u64, // raw collateral units
+    pub entry_price: u64, // raw units of the market's price scale
+    pub leverage: CiphertextHandle,
+    pub initial_margin: CiphertextHandle,
+    pub liqudation_price: CiphertextHandle,
+    pub stop_loss: Option<CiphertextHandle>, // trigger prices, encrypted so nobody else can see where the stops sit
+    pub take_profit: Option<CiphertextHandle>,
+}
+
+impl Position {
+    pub fn ciphertext_keys(&self) -> Vec<CiphertextHandle> {
+        let mut keys = vec![self.leverage, self.initial_margin, self.liqudation_price];
+        keys.extend(self.stop_loss);
+        keys.extend(self.take_profit);
+        keys
+    }
+
+    pub fn has_triggers(&self) -> bool {
+        self.stop_loss.is_some() || self.take_profit.is_some()
+    }
 }
 
 #[derive(Clone)]    
 pub struct User {
     pub id: u128,
     pub positions: Vec<Position>,
-    pub balance: [u8;32],
+    pub balance: Option<CiphertextHandle>, // None until the first deposit
+    pub token: String, // handed out once by /create_user, account only routes need it in the x-user-token header
+}
+
+impl User {
+    pub fn update_balance(&mut self, key: CiphertextHandle) {
+        self.balance = Some(key);
+    }
+
+    pub fn add_position(&mut self, position: Position) {
+        self.positions.push(position);
+    }
+
+    pub fn get_position(&self, position_id: u128) -> Option<Position> {
+        self.positions.iter().find(|position| position.id == position_id).cloned()
+    }
+
+    pub fn update_position(&mut self, position: Position) {
+        if let Some(existing) = self.positions.iter_mut().find(|p| p.id == position.id) {
+            *existing = position;
+        }
+    }
+
+    pub fn remove_position(&mut self, position_id: u128) -> Option<Position> {
+        let index = self.positions.iter().position(|position| position.id == position_id)?;
+        Some(self.positions.remove(index))
+    }
+
+    // the balance plus everything held by open positions, used by the ciphertext collector
+    pub fn ciphertext_keys(&self) -> Vec<CiphertextHandle> {
+        let mut keys: Vec<CiphertextHandle> = self.positions.iter().flat_map(|position| position.ciphertext_keys()).collect();
+        if let Some(balance) = self.balance {
+            keys.push(balance);
+        }
+        keys
+    }
 }
 
 #[derive(Deserialize)]
@@ -39,6 +97,7 @@ pub struct GetUserRequest {
 #[derive(Serialize)]
 pub struct CreateUserResponse {
     user_id: u128,
+    token: Option<String>, // only on the request that created the account
     message: String,
 }
 
@@ -46,14 +105,13 @@ pub struct CreateUserResponse {
 pub struct GetUserResponse {
     user_id: u128,
     positions: Vec<Position>,
-    balance: [u8;32],
+    balance: Option<CiphertextHandle>,
 }
 
 #[derive(Deserialize)]
 pub struct DepositRequest {
     user_id: u128,
-    amount: u64,
-    key: [u8;32],
+    amount: Decimal,
 }
 
 #[derive(Serialize)]
@@ -66,7 +124,8 @@ pub fn create_user(id: u128) -> User {
     User {
         id,
         positions: Vec::new(),
-        balance: [0;32],
+        balance: None,
+        token: format!("{:032x}", rand::random::<u128>()),
     }
 }
 
@@ -77,30 +136,83 @@ pub struct ViewBalanceRequest {
 
 #[derive(Serialize)]
 pub struct ViewBalanceResponse {
-    plaintext: u64,
+    plaintext: Decimal,
 }
 
 #[derive(Deserialize)]
 pub struct OpenPositionRequest {
     user_id: u128,
     direction: bool,
-    notional: u64,
-    leverage: [u8;32],
-    initial_margin: [u8;32]
+    notional: Decimal,
+    leverage: CiphertextHandle,
+    initial_margin: CiphertextHandle
 }
 
 #[derive(Deserialize)]
 pub struct OpenPositionRequestTEST {
     user_id: u128,
+    #[serde(default)]
+    market: u32,
     direction: bool,
-    entry_price: u64,
-    notional: u64,
-    leverage: u64,
-    initial_margin: u64
+    price: Option<Decimal>, // limit price, left out for a market order. the entry comes from the fills
+    notional: Decimal,
+    leverage: u64, // margin is notional / leverage
 }
 #[derive(Serialize)]
 pub struct OpenPositionResponse {
     message: String,
+    order_id: Option<u64>,
+    position_id: Option<u128>, // None until the order trades
+    entry_price: Option<Decimal>, // average over the fills
+    filled_notional: Option<Decimal>,
+    resting_notional: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+pub struct SetTriggersRequest {
+    user_id: u128,
+    position_id: u128,
+    stop_loss: Option<Decimal>, // in the positions market price scale, left out keeps the current one
+    take_profit: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct SetTriggersResponse {
+    message: String,
+}
+
+#[derive(Deserialize)]
+pub struct ClosePositionRequest {
+    user_id: u128,
+    position_id: u128,
+}
+
+#[derive(Serialize)]
+pub struct ClosePositionResponse {
+    message: String,
+    deleveraged_positions: Vec<u128>,
+}
+
+#[derive(Deserialize)]
+pub struct AccountEquityRequest {
+    user_id: u128,
+}
+
+#[derive(Serialize)]
+pub struct PositionPnlResponse {
+    position_id: u128,
+    market: u32,
+    mark_price: Decimal, // the positions own market
+    profit_key: CiphertextHandle,
+    loss_key: CiphertextHandle,
+}
+
+#[derive(Serialize)]
+pub struct AccountEquityResponse {
+    user_id: u128,
+    positions: Vec<PositionPnlResponse>,
+    equity_key: Option<CiphertextHandle>,
+    message: String,
 }
 
 ////////////////////////////// Handlers //////////////////////////////
@@ -111,19 +223,21 @@ pub async fn create_user_handler(
     Json(payload): Json<CreateUserRequest>
 ) -> (StatusCode, Json<CreateUserResponse>) {
     let user = create_user(payload.user_id);
+    let token = user.token.clone();
     // TODO: Store user in database/state
     
-    let mut cache_guard = state.user_cache.lock().await;
-    let success = cache_guard.add_user(user);
+    let success = state.user_cache.add_user(user);
 
     let response = if success {
         CreateUserResponse {
             user_id: payload.user_id,
+            token: Some(token),
             message: "User created successfully".to_string(),
         }
     } else {
         CreateUserResponse {
             user_id: payload.user_id,
+            token: None,
             message: "User already exists".to_string(),
         }
     };
@@ -138,10 +252,10 @@ pub async fn get_user_handler(
     State(state): State<AppState>,
     Path(user_id): Path<u128>
 ) -> (StatusCode, Json<GetUserResponse>) {
-    let mut cache_guard = state.user_cache.lock().await;
-    let user = cache_guard.get_user(user_id);
+    let user = state.user_cache.get_user(user_id);
 
     let response = if let Some(user) = user {
+        let user = user.lock().await;
         GetUserResponse {
             user_id,
             positions: user.positions.clone(),
@@ -151,7 +265,7 @@ pub async fn get_user_handler(
         GetUserResponse {
             user_id,
             positions: vec![],
-            balance: [0; 32],
+            balance: None,
         }
     };
 
@@ -163,45 +277,196 @@ pub async fn deposit_handler(
     State(state): State<AppState>,
     Json(payload): Json<DepositRequest>
 ) -> (StatusCode, Json<DepositResponse>) {
-
-    deposit_circuit(&state, payload.user_id, payload.amount, payload.key).await;
-    (StatusCode::OK, Json(DepositResponse { message: "Deposit successful".to_string() }))
+    let respond = |status: StatusCode, message: String| (status, Json(DepositResponse { message }));
+    let amount = match state.markets.collateral(&payload.amount) {
+        Ok(amount) => amount,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    if !state.user_cache.user_exists(payload.user_id) {
+        return respond(StatusCode::NOT_FOUND, "User not found".to_string());
+    }
+    match deposit_circuit(&state, payload.user_id, amount).await {
+        Ok(()) => respond(StatusCode::OK, "Deposit successful".to_string()),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
 }
 
 pub async fn view_balance_handler(
     State(state): State<AppState>,
     Path(user_id): Path<u128>
-) -> (StatusCode, Json<ViewBalanceResponse>) {
-    set_server_key((*state.server_key).clone());
-    let balance = state.user_cache.lock().await.get_balance(user_id).unwrap().clone();
-    let decrypted: u64 = state.ciphertext_cache.lock().await.get_ciphertext(balance).unwrap().ciphertext.decrypt(&state.client_key);
+) -> Result<(StatusCode, Json<ViewBalanceResponse>), (StatusCode, String)> {
+    let user = state.user_cache.get_user(user_id).ok_or((StatusCode::NOT_FOUND, "User not found".to_string()))?;
+    let balance = user.lock().await.balance;
+    let decrypted: u64 = match balance {
+        Some(balance) => state.ciphertext_cache.get_u64(balance)
+            .ok_or((StatusCode::NOT_FOUND, "Balance ciphertext not found".to_string()))?
+            .decrypt(&state.client_key),
+        None => 0,
+    };
     let response = ViewBalanceResponse {
-        plaintext: decrypted,
+        plaintext: state.markets.collateral_decimal(decrypted),
     };
-    (StatusCode::OK, Json(response))
+    Ok((StatusCode::OK, Json(response)))
 }
 
-pub async fn open_position_handler( // for now we are going to happy path the transfer check 
+fn open_position_error(message: String) -> OpenPositionResponse {
+    OpenPositionResponse { message, order_id: None, position_id: None, entry_price: None, filled_notional: None, resting_notional: None }
+}
+
+// positions only come out of the order book now, the order is placed and its fills open the position
+pub async fn open_position_handler(
     State(state): State<AppState>,
     Json(payload): Json<OpenPositionRequestTEST>
 ) -> (StatusCode, Json<OpenPositionResponse>) {
-    // TODO user check 
-    
-    // for now lets just use a helper to simulate the encryption process
-    let leverage_key = _encrypt_helper(State(state.clone()), payload.leverage, payload.user_id).await;
-    let initial_margin_key = _encrypt_helper(State(state.clone()), payload.initial_margin, payload.user_id).await;
-    let leverage_ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(leverage_key).unwrap().ciphertext.clone();
-    let initial_margin_ciphertext = state.ciphertext_cache.lock().await.get_ciphertext(initial_margin_key).unwrap().ciphertext.clone();
-    let position = open_position_circuit(
-        &state, payload.user_id, 
-        payload.entry_price, 
-        payload.direction,
-        payload.notional,
-        leverage_ciphertext, 
-        initial_margin_ciphertext,
-        initial_margin_key,
-        leverage_key,
-    ).await;
-    (StatusCode::OK, Json(OpenPositionResponse { message: "Position opened successfully".to_string() }))
+    let (price, notional, leverage) = match validate_order(&state, payload.user_id, payload.market, payload.price.as_ref(), &payload.notional, payload.leverage, false).await {
+        Ok(order) => order,
+        Err((status, message)) => return (status, Json(open_position_error(message))),
+    };
+    let market = state.markets.get(payload.market).unwrap();
+    let order = NewOrder {
+        user_id: payload.user_id,
+        market: payload.market,
+        price,
+        size: notional,
+        is_buy: payload.direction,
+        leverage,
+        options: OrderOptions::default(),
+        reduce_only: None,
+    };
+    match place_order(&state, order).await {
+        Ok((result, position_id)) => {
+            let filled: u64 = result.fills.iter().map(|fill| fill.size).sum();
+            let entry_price = match position_id {
+                Some(position_id) => state.position_cache.read().await.get_position(position_id, payload.direction).map(|position| position.entry_price),
+                None => None,
+            };
+            let message = if filled == 0 && result.resting_size == 0 {
+                "Nothing to match against, order dropped".to_string()
+            } else {
+                format!("Order placed, {} fills", result.fills.len())
+            };
+            (StatusCode::OK, Json(OpenPositionResponse {
+                message,
+                order_id: Some(result.order_id),
+                position_id,
+                entry_price: entry_price.map(|entry_price| market.price_decimal(entry_price)),
+                filled_notional: Some(state.markets.collateral_decimal(filled)),
+                resting_notional: Some(state.markets.collateral_decimal(result.resting_size)),
+            }))
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(open_position_error(e))),
+    }
 }
 
+pub async fn account_equity_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<AccountEquityRequest>
+) -> (StatusCode, Json<AccountEquityResponse>) {
+    let mut response = AccountEquityResponse {
+        user_id: payload.user_id,
+        positions: vec![],
+        equity_key: None,
+        message: String::new(),
+    };
+    let Some(user) = state.user_cache.get_user(payload.user_id) else {
+        response.message = "User not found".to_string();
+        return (StatusCode::NOT_FOUND, Json(response));
+    };
+    // the equity handles are only handed to the account itself. nothing gets decrypted, what they hold stays with
+    // whoever has the key
+    let markets: Vec<u32> = {
+        let user = user.lock().await;
+        let given = headers.get(USER_TOKEN_HEADER).map(|value| value.as_bytes());
+        if given != Some(user.token.as_bytes()) {
+            response.message = "Missing or wrong user token".to_string();
+            return (StatusCode::UNAUTHORIZED, Json(response));
+        }
+        user.positions.iter().map(|position| position.market).collect()
+    };
+    if let Some(market) = markets.iter().find(|market| state.mark_prices.get(**market).is_none()) {
+        response.message = format!("No mark price for market {} yet", market);
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(response));
+    }
+    let (pnls, equity) = match account_equity_circuit(&state, payload.user_id).await {
+        Ok(result) => result,
+        Err(e) => {
+            response.message = e.to_string();
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(response));
+        }
+    };
+    for pnl in pnls.into_iter() {
+        let market = state.markets.get(pnl.market).unwrap();
+        response.positions.push(PositionPnlResponse {
+            position_id: pnl.position_id,
+            market: pnl.market,
+            mark_price: market.price_decimal(pnl.mark_price),
+            profit_key: _ephemeral_from_value(State(state.clone()), pnl.profit, payload.user_id).await,
+            loss_key: _ephemeral_from_value(State(state.clone()), pnl.loss, payload.user_id).await,
+        });
+    }
+    response.equity_key = Some(_ephemeral_from_value(State(state.clone()), equity, payload.user_id).await);
+    response.message = "Equity computed".to_string();
+    (StatusCode::OK, Json(response))
+}
+
+// attaches a stop loss and/or take profit. like open_position the prices are encrypted here to stand in for the
+// client doing it, from then on they only exist as ciphertexts
+pub async fn set_triggers_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SetTriggersRequest>
+) -> (StatusCode, Json<SetTriggersResponse>) {
+    let respond = |status: StatusCode, message: &str| (status, Json(SetTriggersResponse { message: message.to_string() }));
+    let Some(user) = state.user_cache.get_user(payload.user_id) else {
+        return respond(StatusCode::NOT_FOUND, "User not found");
+    };
+    let mut user = user.lock().await;
+    let Some(mut position) = user.get_position(payload.position_id) else {
+        return respond(StatusCode::NOT_FOUND, "Position not found");
+    };
+    let market = state.markets.get(position.market).unwrap();
+    let stop_loss = match payload.stop_loss.as_ref().map(|price| market.price(price)).transpose() {
+        Ok(stop_loss) => stop_loss,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let take_profit = match payload.take_profit.as_ref().map(|price| market.price(price)).transpose() {
+        Ok(take_profit) => take_profit,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    if let Some(stop_loss) = stop_loss {
+        match _encrypt_helper(State(state.clone()), stop_loss, payload.user_id).await {
+            Ok(handle) => position.stop_loss = Some(handle),
+            Err(e) => return respond(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        }
+    }
+    if let Some(take_profit) = take_profit {
+        match _encrypt_helper(State(state.clone()), take_profit, payload.user_id).await {
+            Ok(handle) => position.take_profit = Some(handle),
+            Err(e) => return respond(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        }
+    }
+    user.update_position(position.clone());
+    state.position_cache.write().await.update_position(position);
+    respond(StatusCode::OK, "Triggers set")
+}
+
+pub async fn close_position_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<ClosePositionRequest>
+) -> (StatusCode, Json<ClosePositionResponse>) {
+    let respond = |status: StatusCode, message: String, deleveraged_positions: Vec<u128>| (status, Json(ClosePositionResponse { message, deleveraged_positions }));
+    let position = match state.user_cache.get_user(payload.user_id) {
+        Some(user) => user.lock().await.get_position(payload.position_id),
+        None => return respond(StatusCode::NOT_FOUND, "User not found".to_string(), vec![]),
+    };
+    let Some(position) = position else {
+        return respond(StatusCode::NOT_FOUND, "Position not found".to_string(), vec![]);
+    };
+    let Some(mark_price) = state.mark_prices.get(position.market) else {
+        return respond(StatusCode::SERVICE_UNAVAILABLE, "No mark price for this market yet".to_string(), vec![]);
+    };
+    match close_position_circuit(&state, payload.user_id, payload.position_id, mark_price).await {
+        Ok(deleveraged_positions) => respond(StatusCode::OK, "Position closed".to_string(), deleveraged_positions),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string(), vec![]),
+    }
+}
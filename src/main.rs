@@ -1,25 +1,67 @@
 use axum::{
-    routing::{get, post}, Router, Json, extract::State,
-    http::StatusCode,
+    routing::{get, post}, Router, Json, extract::{State, DefaultBodyLimit},
+    http::{StatusCode, HeaderMap},
 };
 use serde::{Deserialize, Serialize};
 mod fhe;
 mod liqudation;
-use crate::liqudation::users::{create_user_handler, get_user_handler, deposit_handler, view_balance_handler, open_position_handler};
+mod market;
+mod orderbook;
+use crate::liqudation::users::{create_user_handler, get_user_handler, deposit_handler, view_balance_handler, open_position_handler, account_equity_handler, set_triggers_handler, close_position_handler};
 use crate::liqudation::cache::{AccountCache, SharedAccountCache, CiphertextCache, PositionCache};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use tfhe::{ServerKey, ClientKey};
-use crate::liqudation::handlers::{encrypt_handler, get_ciphertext_handler, health_check_long_handler, funding_rate_long_pay_short_handler};
+use crate::fhe::pool::FhePool;
+use crate::liqudation::handlers::{encrypt_handler, get_ciphertext_handler, health_check_long_handler, funding_rate_long_pay_short_handler, liquidate_long_handler, insurance_fund_handler, fhe_pool_metrics_handler, collect_garbage_handler, export_ciphertext_handler, import_ciphertext_handler, markets_handler, set_mark_price_handler, set_index_price_handler, prices_handler, open_interest_handler};
+#[cfg(feature = "bench-endpoints")]
+use crate::liqudation::handlers::{bench_circuits_handler, bench_dark_book_handler, bench_clob_handler};
+use crate::market::registry::MarketRegistry;
+use crate::market::mark::MarkPrices;
+use crate::market::open_interest::OpenInterest;
+use crate::fhe::serialization::MAX_IMPORT_BYTES;
+use crate::orderbook::dark::DarkBook;
+use crate::orderbook::engine::OrderBooks;
+use crate::orderbook::candles::{CandleStore, CANDLES_PATH};
+use crate::orderbook::sealed::SealedAuctions;
+use crate::orderbook::clob::now_millis;
+use crate::orderbook::handlers::{dark_order_handler, cancel_dark_order_handler, place_order_handler, cancel_order_handler, amend_order_handler, open_orders_handler, depth_handler, top_of_book_handler, trades_handler, self_trades_handler, candles_handler, get_matching_mode_handler, set_matching_mode_handler, auctions_handler, sealed_order_handler, cancel_sealed_order_handler, sealed_auctions_handler};
 
 
 #[derive(Clone)]
 struct AppState {
-    user_cache: Arc<Mutex<AccountCache>>,
-    ciphertext_cache: Arc<Mutex<CiphertextCache>>,
-    position_cache: Arc<Mutex<PositionCache>>,
+    user_cache: SharedAccountCache,
+    ciphertext_cache: Arc<CiphertextCache>,
+    position_cache: Arc<RwLock<PositionCache>>,
     server_key: Arc<ServerKey>,
     client_key: Arc<ClientKey>,
+    fhe_pool: Arc<FhePool>,
+    key_set: u32, // id of the key set above, stamped into every ciphertext handle
+    markets: Arc<MarketRegistry>,
+    mark_prices: Arc<MarkPrices>,
+    dark_book: Arc<RwLock<DarkBook>>,
+    dark_matching: Arc<Mutex<()>>, // held through a whole dark match so matches dont interleave, the book lock isnt
+    order_books: Arc<Mutex<OrderBooks>>, // one CLOB per market, positions are opened from their fills
+    sealed_auctions: Arc<Mutex<SealedAuctions>>,
+    open_interest: Arc<Mutex<OpenInterest>>, // encrypted long and short totals per market
+    admin_token: Option<Arc<String>>, // ADMIN_TOKEN, operator only routes are closed without it
+}
+
+const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+impl AppState {
+    // operator only routes need an x-admin-token header matching ADMIN_TOKEN. without ADMIN_TOKEN they are turned
+    // off (403), a missing or wrong header is a 401
+    fn check_admin(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let Some(admin_token) = self.admin_token.as_deref() else {
+            return Err((StatusCode::FORBIDDEN, "Operator routes are disabled, the server has no ADMIN_TOKEN".to_string()));
+        };
+        let given = headers.get(ADMIN_TOKEN_HEADER).map(|value| value.as_bytes());
+        if given != Some(admin_token.as_bytes()) {
+            return Err((StatusCode::UNAUTHORIZED, "Missing or wrong admin token".to_string()));
+        }
+        Ok(())
+    }
 }
 
 pub trait KeyAccess {
@@ -43,28 +85,85 @@ async fn main() {
         return;
     }
 
-    let user_cache = Arc::new(Mutex::new(AccountCache::new()));
-    let ciphertext_cache = Arc::new(Mutex::new(CiphertextCache::new()));
-    let position_cache = Arc::new(Mutex::new(PositionCache::new()));
+    let user_cache = Arc::new(AccountCache::new());
+    let ciphertext_cache = Arc::new(CiphertextCache::new());
+    let position_cache = Arc::new(RwLock::new(PositionCache::new()));
+    let server_key = fhe::key_gen::load_server_key().unwrap();
+    let fhe_pool = Arc::new(FhePool::from_env(&server_key));
+    let markets = Arc::new(MarketRegistry::with_defaults());
+    let order_books = Arc::new(Mutex::new(OrderBooks::with_markets(&markets, CandleStore::load(CANDLES_PATH))));
     let state = AppState { 
         user_cache: user_cache.clone(),
         ciphertext_cache: ciphertext_cache.clone(),
         position_cache: position_cache.clone(),
-        server_key: Arc::new(fhe::key_gen::load_server_key().unwrap()),
+        server_key: Arc::new(server_key),
         client_key: Arc::new(fhe::key_gen::load_client_key().unwrap()),
+        fhe_pool,
+        key_set: fhe::key_gen::load_key_set_id().unwrap(),
+        markets,
+        mark_prices: Arc::new(MarkPrices::new()),
+        dark_book: Arc::new(RwLock::new(DarkBook::new(true))), // fills report the makers price
+        dark_matching: Arc::new(Mutex::new(())),
+        order_books,
+        sealed_auctions: Arc::new(Mutex::new(SealedAuctions::new(now_millis()))),
+        open_interest: Arc::new(Mutex::new(OpenInterest::new())),
+        admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()).map(Arc::new),
     };
+    tokio::spawn(liqudation::internal::ciphertext_gc_loop(state.clone()));
+    tokio::spawn(liqudation::internal::price_loop(state.clone()));
+    tokio::spawn(orderbook::engine::order_expiry_loop(state.clone()));
+    tokio::spawn(orderbook::engine::auction_loop(state.clone()));
+    tokio::spawn(orderbook::engine::sealed_auction_loop(state.clone()));
+    tokio::spawn(orderbook::engine::candle_persist_loop(state.clone()));
     
     let app = Router::new()
         .route("/create_user", post(create_user_handler))
+        .route("/markets", get(markets_handler))
         .route("/get_user/:user_id", get(get_user_handler))
         .route("/encrypt", post(encrypt_handler))
         .route("/deposit", post(deposit_handler))
         .route("/view_balance/:user_id", get(view_balance_handler))
+        .route("/account_equity", post(account_equity_handler))
         .route("/get_ciphertext/:ciphertext_key", get(get_ciphertext_handler))
+        .route("/export_ciphertext/:ciphertext_key", get(export_ciphertext_handler))
+        .route("/import_ciphertext", post(import_ciphertext_handler).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES * 2))) // base64 is bigger than the bytes it carries
         .route("/open_position", post(open_position_handler)) // maybe i make a seperate one for long/short
+        .route("/set_triggers", post(set_triggers_handler))
+        .route("/close_position", post(close_position_handler))
+        .route("/mark_price", post(set_mark_price_handler))
+        .route("/index_price", post(set_index_price_handler))
+        .route("/prices/:market", get(prices_handler))
+        .route("/open_interest/:market", get(open_interest_handler))
         .route("/health_check_long", post(health_check_long_handler))
         .route("/funding_rate_long_pay_short", post(funding_rate_long_pay_short_handler))
-        .with_state(state);
+        .route("/liquidate_long", post(liquidate_long_handler))
+        .route("/insurance_fund", get(insurance_fund_handler))
+        .route("/metrics/fhe_pool", get(fhe_pool_metrics_handler))
+        .route("/orders", post(place_order_handler))
+        .route("/orders/cancel", post(cancel_order_handler))
+        .route("/orders/amend", post(amend_order_handler))
+        .route("/orders/:user_id", get(open_orders_handler))
+        .route("/book/:market/depth", get(depth_handler))
+        .route("/book/:market/top", get(top_of_book_handler))
+        .route("/book/:market/trades", get(trades_handler))
+        .route("/book/:market/self_trades", get(self_trades_handler))
+        .route("/book/:market/candles", get(candles_handler))
+        .route("/book/:market/mode", get(get_matching_mode_handler).post(set_matching_mode_handler))
+        .route("/book/:market/auctions", get(auctions_handler))
+        .route("/dark/order", post(dark_order_handler))
+        .route("/dark/cancel", post(cancel_dark_order_handler))
+        .route("/sealed/order", post(sealed_order_handler))
+        .route("/sealed/cancel", post(cancel_sealed_order_handler))
+        .route("/sealed/:market/auctions", get(sealed_auctions_handler))
+        .route("/gc", post(collect_garbage_handler));
+    // the bench routes keep fhe workers busy for as long as the caller asks, theyre only built with the
+    // bench-endpoints feature
+    #[cfg(feature = "bench-endpoints")]
+    let app = app
+        .route("/bench/circuits", post(bench_circuits_handler))
+        .route("/bench/dark_book", post(bench_dark_book_handler))
+        .route("/bench/clob", post(bench_clob_handler));
+    let app = app.with_state(state);
 
 
     println!("Server running on http://localhost:3000");
@@ -0,0 +1,266 @@
+use std::fmt;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+// prices and amounts live in the engine as u64 counts of the smallest unit of their scale ("raw"), so with 6
+// collateral decimals 1.5 is 1_500_000. Decimal is only the wire format, a decimal string (plain JSON integers are
+// accepted too) that gets turned into raw units against the scale of whatever it describes
+
+const MAX_DIGITS: u32 = 38; // what fits in the u128 accumulator
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecimalError {
+    Invalid(String),
+    TooPrecise { decimals: u32, max: u32 },
+    Negative,
+    Overflow,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decimal {
+    negative: bool,
+    digits: u128,
+    decimals: u32,
+}
+
+impl Decimal {
+    pub fn from_raw(raw: u64, scale: u32) -> Self {
+        Self { negative: false, digits: raw as u128, decimals: scale }
+    }
+
+    pub fn from_signed_raw(raw: i128, scale: u32) -> Self {
+        Self { negative: raw < 0, digits: raw.unsigned_abs(), decimals: scale }
+    }
+
+    // exact conversion, input with more decimals than the scale is rejected rather than silently rounded.
+    // trailing zeros past the scale are fine ("1.50" at scale 1)
+    pub fn to_raw(&self, scale: u32) -> Result<u64, DecimalError> {
+        if self.negative && self.digits != 0 {
+            return Err(DecimalError::Negative);
+        }
+        let raw = if self.decimals > scale {
+            let divisor = 10u128.pow(self.decimals - scale);
+            if self.digits % divisor != 0 {
+                return Err(DecimalError::TooPrecise { decimals: self.decimals, max: scale });
+            }
+            self.digits / divisor
+        } else {
+            self.digits.checked_mul(10u128.pow(scale - self.decimals)).ok_or(DecimalError::Overflow)?
+        };
+        u64::try_from(raw).map_err(|_| DecimalError::Overflow)
+    }
+
+    fn parse(s: &str) -> Result<Self, DecimalError> {
+        let invalid = || DecimalError::Invalid(s.to_string());
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if whole.len() as u32 + fraction.len() as u32 > MAX_DIGITS {
+            return Err(DecimalError::Overflow);
+        }
+        let mut digits: u128 = 0;
+        for c in whole.chars().chain(fraction.chars()) {
+            let digit = c.to_digit(10).ok_or_else(invalid)?;
+            digits = digits * 10 + digit as u128;
+        }
+        Ok(Self { negative, digits, decimals: fraction.len() as u32 })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.negative && self.digits != 0 { "-" } else { "" };
+        if self.decimals == 0 {
+            return write!(f, "{}{}", sign, self.digits);
+        }
+        let unit = 10u128.pow(self.decimals);
+        write!(f, "{}{}.{:0width$}", sign, self.digits / unit, self.digits % unit, width = self.decimals as usize)
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DecimalVisitor;
+
+        impl de::Visitor<'_> for DecimalVisitor {
+            type Value = Decimal;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a decimal string or an integer")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Decimal, E> {
+                Decimal::parse(s).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Decimal, E> {
+                Ok(Decimal { negative: false, digits: v as u128, decimals: 0 })
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Decimal, E> {
+                Ok(Decimal { negative: v < 0, digits: v.unsigned_abs() as u128, decimals: 0 })
+            }
+
+            // floats cant hold most decimal fractions exactly, make the client send a string instead
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Decimal, E> {
+                Err(E::custom(format!("{} is a float, send fractional values as strings", v)))
+            }
+        }
+
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+impl fmt::Display for DecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecimalError::Invalid(s) => write!(f, "{:?} is not a decimal number", s),
+            DecimalError::TooPrecise { decimals, max } => write!(f, "{} decimal places given, at most {} allowed", decimals, max),
+            DecimalError::Negative => write!(f, "value cant be negative"),
+            DecimalError::Overflow => write!(f, "value is too large"),
+        }
+    }
+}
+
+impl std::error::Error for DecimalError {}
+
+// value * numerator / denominator in 128 bits, rounded the way the caller asks. callers pick the direction that
+// favours the protocol: fees, losses and penalties round up, profits and payouts round down
+pub fn mul_div(value: u64, numerator: u64, denominator: u64, rounding: Rounding) -> u64 {
+    let product = value as u128 * numerator as u128;
+    let denominator = denominator.max(1) as u128;
+    let result = match rounding {
+        Rounding::Down => product / denominator,
+        Rounding::Up => product.div_ceil(denominator),
+    };
+    u64::try_from(result).unwrap_or(u64::MAX)
+}
+
+pub fn bps(value: u64, bps: u64, rounding: Rounding) -> u64 {
+    mul_div(value, bps, 10_000, rounding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(s: &str, scale: u32) -> Result<u64, DecimalError> {
+        Decimal::parse(s)?.to_raw(scale)
+    }
+
+    #[test]
+    fn to_raw_scales_up() {
+        assert_eq!(raw("1.5", 6), Ok(1_500_000));
+        assert_eq!(raw("42", 2), Ok(4_200));
+        assert_eq!(raw(".5", 1), Ok(5));
+        assert_eq!(raw("1.", 2), Ok(100));
+    }
+
+    #[test]
+    fn to_raw_rejects_too_precise() {
+        assert_eq!(raw("1.234", 2), Err(DecimalError::TooPrecise { decimals: 3, max: 2 }));
+        assert_eq!(raw("0.0000001", 6), Err(DecimalError::TooPrecise { decimals: 7, max: 6 }));
+    }
+
+    #[test]
+    fn to_raw_accepts_trailing_zeros_past_the_scale() {
+        assert_eq!(raw("1.50", 1), Ok(15));
+        assert_eq!(raw("1.2300", 2), Ok(123));
+        assert_eq!(raw("7.000000000", 0), Ok(7));
+    }
+
+    #[test]
+    fn to_raw_overflows_past_u64() {
+        assert_eq!(raw("18446744073709551615", 0), Ok(u64::MAX));
+        assert_eq!(raw("18446744073709551616", 0), Err(DecimalError::Overflow));
+        assert_eq!(raw("18446744073709551.615", 3), Ok(u64::MAX));
+        assert_eq!(raw("18446744073709551.616", 3), Err(DecimalError::Overflow));
+        assert_eq!(raw("18446744073709551615", 1), Err(DecimalError::Overflow));
+        // fits in the u128 accumulator but not once it is scaled
+        assert_eq!(raw(&"9".repeat(38), 6), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn parse_stops_at_38_digits() {
+        assert!(Decimal::parse(&"9".repeat(38)).is_ok());
+        assert!(Decimal::parse(&format!("0.{}1", "0".repeat(36))).is_ok());
+        assert_eq!(Decimal::parse(&"9".repeat(39)), Err(DecimalError::Overflow));
+        assert_eq!(Decimal::parse(&format!("1.{}", "0".repeat(38))), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn negative_zero_is_zero() {
+        assert_eq!(raw("-0", 2), Ok(0));
+        assert_eq!(raw("-0.00", 2), Ok(0));
+        assert_eq!(Decimal::parse("-0").unwrap().to_string(), "0");
+        assert_eq!(raw("-1", 2), Err(DecimalError::Negative));
+        assert_eq!(raw("-0.01", 2), Err(DecimalError::Negative));
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        for s in [".", "", "-", "-.", "1.2.3", "1e5", "+1", " 1", "1,5", "0x10"] {
+            assert!(matches!(Decimal::parse(s), Err(DecimalError::Invalid(_))), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        assert_eq!(Decimal::from_raw(1_500_000, 6).to_string(), "1.500000");
+        assert_eq!(Decimal::from_raw(5, 2).to_string(), "0.05");
+        assert_eq!(Decimal::from_signed_raw(-5, 2).to_string(), "-0.05");
+        assert_eq!(Decimal::from_raw(7, 0).to_string(), "7");
+        assert_eq!(raw(&Decimal::from_raw(123_456, 3).to_string(), 3), Ok(123_456));
+    }
+
+    #[test]
+    fn mul_div_rounds_the_way_it_is_asked() {
+        // exact, both directions agree
+        assert_eq!(mul_div(10_100, 1, 101, Rounding::Down), 100);
+        assert_eq!(mul_div(10_100, 1, 101, Rounding::Up), 100);
+        assert_eq!(mul_div(100, 3, 3, Rounding::Up), 100);
+        // one over and one under an exact multiple
+        assert_eq!(mul_div(10_101, 1, 101, Rounding::Down), 100);
+        assert_eq!(mul_div(10_101, 1, 101, Rounding::Up), 101);
+        assert_eq!(mul_div(10_099, 1, 101, Rounding::Down), 99);
+        assert_eq!(mul_div(10_099, 1, 101, Rounding::Up), 100);
+        assert_eq!(mul_div(100, 1, 3, Rounding::Down), 33);
+        assert_eq!(mul_div(100, 1, 3, Rounding::Up), 34);
+    }
+
+    #[test]
+    fn mul_div_edges() {
+        assert_eq!(mul_div(7, 1, 0, Rounding::Down), 7); // a zero denominator counts as 1
+        assert_eq!(mul_div(0, 5, 3, Rounding::Up), 0);
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX, Rounding::Down), u64::MAX); // 128 bit product, no overflow
+        assert_eq!(mul_div(u64::MAX, 2, 1, Rounding::Down), u64::MAX); // saturates
+    }
+
+    #[test]
+    fn bps_rounds_the_way_it_is_asked() {
+        assert_eq!(bps(10_000, 5, Rounding::Down), 5);
+        assert_eq!(bps(10_000, 5, Rounding::Up), 5);
+        assert_eq!(bps(9_999, 5, Rounding::Down), 4);
+        assert_eq!(bps(9_999, 5, Rounding::Up), 5);
+        assert_eq!(bps(10_001, 5, Rounding::Down), 5);
+        assert_eq!(bps(10_001, 5, Rounding::Up), 6);
+        assert_eq!(bps(1, 1, Rounding::Down), 0);
+        assert_eq!(bps(1, 1, Rounding::Up), 1);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+pub const INDEX_STALE_MS: u64 = 60_000; // index components and the last trade older than this are left out
+pub const PREMIUM_SAMPLE_MS: u64 = 5_000;
+pub const PREMIUM_WINDOW_MS: u64 = 8 * 3_600_000; // one funding interval
+pub const PREMIUM_SCALE: i64 = 1_000_000; // premiums are parts per million of the index
+
+// one oracle source for a markets index, price in raw units of the markets price scale
+#[derive(Clone)]
+pub struct IndexComponent {
+    pub source: String,
+    pub price: u64,
+    pub timestamp: u64, // unix millis, when the oracle pushed it
+}
+
+// everything the price loop last worked out for a market, prices in raw units
+#[derive(Clone, Default)]
+pub struct MarketPrices {
+    pub index: Option<u64>,
+    pub mark: Option<u64>,
+    pub impact_bid: Option<u64>,
+    pub impact_ask: Option<u64>,
+    pub last_trade: Option<u64>,
+    pub premium: Option<i64>, // latest sample, PREMIUM_SCALE
+    pub premium_index: Option<i64>, // average of the samples in the window, PREMIUM_SCALE
+    pub components: Vec<IndexComponent>,
+    pub updated_at: u64,
+}
+
+#[derive(Default)]
+struct PriceState {
+    prices: MarketPrices,
+    components: HashMap<String, IndexComponent>,
+    samples: VecDeque<(u64, i64)>, // (timestamp, premium), oldest at the front
+    sample_sum: i128,
+}
+
+// index, mark and premium per market. the oracle pushes index components, the price loop feeds in the books impact
+// prices and last trade every tick and derives the rest. a market without a fresh index keeps whatever mark was
+// pushed through /mark_price
+pub struct MarkPrices {
+    markets: RwLock<HashMap<u32, PriceState>>,
+}
+
+impl MarkPrices {
+    pub fn new() -> Self {
+        Self { markets: RwLock::new(HashMap::new()) }
+    }
+
+    // manual mark, the next tick replaces it if the market has an index
+    pub fn set(&self, market: u32, price: u64) {
+        self.markets.write().unwrap().entry(market).or_default().prices.mark = Some(price);
+    }
+
+    pub fn get(&self, market: u32) -> Option<u64> {
+        self.markets.read().unwrap().get(&market)?.prices.mark
+    }
+
+    // what funding should charge per interval, positive when longs pay shorts
+    pub fn premium_index(&self, market: u32) -> Option<i64> {
+        self.markets.read().unwrap().get(&market)?.prices.premium_index
+    }
+
+    pub fn snapshot(&self, market: u32) -> Option<MarketPrices> {
+        let markets = self.markets.read().unwrap();
+        let state = markets.get(&market)?;
+        let mut prices = state.prices.clone();
+        prices.components = state.components.values().cloned().collect();
+        prices.components.sort_by(|a, b| a.source.cmp(&b.source));
+        Some(prices)
+    }
+
+    // a source that isnt pushed again goes stale and drops out of the index on its own
+    pub fn set_components(&self, market: u32, components: Vec<(String, u64)>, now: u64) {
+        let mut markets = self.markets.write().unwrap();
+        let state = markets.entry(market).or_default();
+        for (source, price) in components {
+            state.components.insert(source.clone(), IndexComponent { source, price, timestamp: now });
+        }
+    }
+
+    // one price loop tick for a market. last_trade is (price, timestamp). returns the mark
+    pub fn update(&self, market: u32, impact_bid: Option<u64>, impact_ask: Option<u64>, last_trade: Option<(u64, u64)>, now: u64) -> Option<u64> {
+        let mut markets = self.markets.write().unwrap();
+        let state = markets.entry(market).or_default();
+        let fresh = |timestamp: u64| timestamp + INDEX_STALE_MS >= now;
+        state.prices.impact_bid = impact_bid;
+        state.prices.impact_ask = impact_ask;
+        state.prices.last_trade = last_trade.filter(|(_, timestamp)| fresh(*timestamp)).map(|(price, _)| price);
+        state.prices.updated_at = now;
+        state.prices.index = median(state.components.values().filter(|component| fresh(component.timestamp)).map(|component| component.price).collect());
+        let Some(index) = state.prices.index else { return state.prices.mark };
+
+        state.prices.premium = premium(index, impact_bid, impact_ask);
+        if let Some(premium) = state.prices.premium {
+            if state.samples.back().is_none_or(|(timestamp, _)| timestamp + PREMIUM_SAMPLE_MS <= now) {
+                state.samples.push_back((now, premium));
+                state.sample_sum += premium as i128;
+            }
+        }
+        while state.samples.front().is_some_and(|(timestamp, _)| timestamp + PREMIUM_WINDOW_MS <= now) {
+            let (_, premium) = state.samples.pop_front().unwrap();
+            state.sample_sum -= premium as i128;
+        }
+        state.prices.premium_index = (!state.samples.is_empty()).then(|| (state.sample_sum / state.samples.len() as i128) as i64);
+
+        // index + basis carries the averaged premium, so one odd print or a thin book on its own cant move the mark
+        let basis = index as i128 * state.prices.premium_index.unwrap_or(0) as i128 / PREMIUM_SCALE as i128;
+        let mut inputs = vec![(index as i128 + basis).max(0) as u64];
+        if let (Some(bid), Some(ask)) = (impact_bid, impact_ask) {
+            inputs.push(((bid as u128 + ask as u128) / 2) as u64);
+        }
+        inputs.extend(state.prices.last_trade);
+        state.prices.mark = median(inputs);
+        state.prices.mark
+    }
+}
+
+// middle value, the two middle ones averaged (rounded down) for an even count
+fn median(mut values: Vec<u64>) -> Option<u64> {
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(values[mid]),
+        _ => Some(((values[mid - 1] as u128 + values[mid] as u128) / 2) as u64),
+    }
+}
+
+// how far the book trades away from the index: the impact bid above it counts positive, the impact ask below it
+// negative. a side with no impact price counts as 0, no sample without either
+fn premium(index: u64, impact_bid: Option<u64>, impact_ask: Option<u64>) -> Option<i64> {
+    if index == 0 || (impact_bid.is_none() && impact_ask.is_none()) {
+        return None;
+    }
+    let above = impact_bid.map(|bid| bid.saturating_sub(index)).unwrap_or(0) as i128;
+    let below = impact_ask.map(|ask| index.saturating_sub(ask)).unwrap_or(0) as i128;
+    Some(((above - below) * PREMIUM_SCALE as i128 / index as i128) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(prices: &MarkPrices, market: u32) -> Vec<(u64, i64)> {
+        prices.markets.read().unwrap()[&market].samples.iter().copied().collect()
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(median(vec![7]), Some(7));
+        assert_eq!(median(vec![9, 1, 5]), Some(5));
+        assert_eq!(median(vec![4, 1, 3, 2]), Some(2)); // 2.5 rounds down
+        assert_eq!(median(vec![u64::MAX, u64::MAX]), Some(u64::MAX));
+    }
+
+    #[test]
+    fn premium_is_how_far_the_impact_prices_sit_outside_the_index() {
+        assert_eq!(premium(10_000, Some(10_010), Some(10_030)), Some(1_000)); // bid 10bp above
+        assert_eq!(premium(10_000, Some(9_950), Some(9_980)), Some(-2_000)); // ask 20bp below
+        assert_eq!(premium(10_000, Some(9_990), Some(10_010)), Some(0)); // index inside the spread
+        assert_eq!(premium(10_000, Some(10_010), None), Some(1_000));
+        assert_eq!(premium(10_000, None, None), None);
+        assert_eq!(premium(0, Some(1), Some(2)), None);
+    }
+
+    #[test]
+    fn mark_is_the_median_of_index_plus_basis_impact_mid_and_last_trade() {
+        let prices = MarkPrices::new();
+        prices.set_components(0, vec![("a".to_string(), 10_000), ("b".to_string(), 10_100), ("c".to_string(), 9_900)], 0);
+        // index 10,000, premium 1,000 ppm so index + basis is 10,010, impact mid 10,020, last trade 10,050
+        let mark = prices.update(0, Some(10_010), Some(10_030), Some((10_050, 0)), 0);
+        assert_eq!(mark, Some(10_020));
+        let snapshot = prices.snapshot(0).unwrap();
+        assert_eq!((snapshot.index, snapshot.premium, snapshot.premium_index), (Some(10_000), Some(1_000), Some(1_000)));
+        let sources: Vec<&str> = snapshot.components.iter().map(|component| component.source.as_str()).collect();
+        assert_eq!(sources, vec!["a", "b", "c"]);
+
+        // without the book the mark is index + basis and the last trade, averaged
+        assert_eq!(prices.update(0, None, None, Some((10_050, 0)), 1_000), Some(10_030));
+    }
+
+    #[test]
+    fn stale_inputs_drop_out() {
+        let prices = MarkPrices::new();
+        prices.set_components(0, vec![("a".to_string(), 10_000)], 0);
+        prices.set_components(0, vec![("b".to_string(), 12_000)], 30_000);
+        assert_eq!(prices.update(0, None, None, Some((11_000, 0)), 60_000), Some(11_000));
+        // a is past INDEX_STALE_MS now, and so is the last trade
+        assert_eq!(prices.update(0, None, None, Some((11_000, 0)), 60_001), Some(12_000));
+        assert_eq!(prices.snapshot(0).unwrap().last_trade, None);
+    }
+
+    #[test]
+    fn without_an_index_the_manual_mark_stays() {
+        let prices = MarkPrices::new();
+        prices.set(0, 5_000);
+        assert_eq!(prices.update(0, Some(5_100), Some(5_200), Some((5_150, 0)), 0), Some(5_000));
+        assert_eq!(prices.get(0), Some(5_000));
+        assert_eq!(prices.snapshot(0).unwrap().index, None);
+        assert_eq!(prices.premium_index(0), None);
+    }
+
+    #[test]
+    fn premium_is_sampled_on_an_interval_and_averaged_over_the_window() {
+        let prices = MarkPrices::new();
+        let push = |now: u64| prices.set_components(0, vec![("a".to_string(), 10_000)], now);
+        push(0);
+        prices.update(0, Some(10_010), None, None, 0);
+        prices.update(0, Some(10_050), None, None, PREMIUM_SAMPLE_MS - 1);
+        assert_eq!(samples(&prices, 0), vec![(0, 1_000)]);
+        prices.update(0, Some(10_030), None, None, PREMIUM_SAMPLE_MS);
+        assert_eq!(samples(&prices, 0), vec![(0, 1_000), (PREMIUM_SAMPLE_MS, 3_000)]);
+        assert_eq!(prices.premium_index(0), Some(2_000));
+
+        // the first sample leaves the window
+        push(PREMIUM_WINDOW_MS);
+        prices.update(0, None, Some(9_990), None, PREMIUM_WINDOW_MS);
+        assert_eq!(samples(&prices, 0), vec![(PREMIUM_SAMPLE_MS, 3_000), (PREMIUM_WINDOW_MS, -1_000)]);
+        assert_eq!(prices.premium_index(0), Some(1_000));
+    }
+}
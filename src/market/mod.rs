@@ -0,0 +1,4 @@
+pub mod decimal;
+pub mod registry;
+pub mod mark;
+pub mod open_interest;
\ No newline at end of file
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use tfhe::FheUint64;
+
+pub const OPEN_INTEREST_REVEAL_MS: u64 = 60_000; // a market reveals its totals or skew at most this often
+
+// which side holds more notional, the only thing a skew reveal decrypts
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Skew {
+    Long,
+    Short,
+    Balanced,
+}
+
+#[derive(Default)]
+struct MarketOpenInterest {
+    long: Option<FheUint64>, // None until the first position on that side, counts as 0
+    short: Option<FheUint64>,
+    totals: Option<(u64, u64, u64)>, // last reveal, (long, short, unix millis)
+    skew: Option<(Skew, u64)>,
+}
+
+// encrypted running totals of open notional per market and side. every open, extend, reduce, close and liquidation
+// adds or takes its notional homomorphically, the totals are only decrypted through the reveals below. reveals are
+// cached for OPEN_INTEREST_REVEAL_MS so polling them cant be used to diff out a single position
+pub struct OpenInterest {
+    markets: HashMap<u32, MarketOpenInterest>,
+}
+
+impl OpenInterest {
+    pub fn new() -> Self {
+        Self { markets: HashMap::new() }
+    }
+
+    pub fn get(&self, market: u32, is_long: bool) -> Option<FheUint64> {
+        let totals = self.markets.get(&market)?;
+        if is_long { totals.long.clone() } else { totals.short.clone() }
+    }
+
+    pub fn set(&mut self, market: u32, is_long: bool, total: FheUint64) {
+        let totals = self.markets.entry(market).or_default();
+        if is_long { totals.long = Some(total) } else { totals.short = Some(total) }
+    }
+
+    // the last reveal if it is recent enough to hand out again
+    pub fn revealed_totals(&self, market: u32, now: u64) -> Option<(u64, u64, u64)> {
+        self.markets.get(&market)?.totals.filter(|(_, _, revealed_at)| revealed_at + OPEN_INTEREST_REVEAL_MS > now)
+    }
+
+    pub fn record_totals(&mut self, market: u32, long: u64, short: u64, now: u64) {
+        self.markets.entry(market).or_default().totals = Some((long, short, now));
+    }
+
+    pub fn revealed_skew(&self, market: u32, now: u64) -> Option<(Skew, u64)> {
+        self.markets.get(&market)?.skew.filter(|(_, revealed_at)| revealed_at + OPEN_INTEREST_REVEAL_MS > now)
+    }
+
+    pub fn record_skew(&mut self, market: u32, skew: Skew, now: u64) {
+        self.markets.entry(market).or_default().skew = Some((skew, now));
+    }
+}
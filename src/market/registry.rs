@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::market::decimal::{Decimal, DecimalError};
+
+pub const DEFAULT_MARKET_ID: u32 = 0;
+pub const COLLATERAL_DECIMALS: u32 = 6;
+
+#[derive(Clone, Serialize)]
+pub struct MarketConfig {
+    pub id: u32,
+    pub symbol: String,
+    pub price_decimals: u32,
+    pub min_notional: u64, // raw collateral units, partial liquidation stops stepping below this
+    pub impact_notional: u64, // raw collateral, the size the impact bid and ask are measured at
+    pub max_open_interest: u64, // raw collateral per side, orders that could push a side past it are turned away
+    pub index_sources: Vec<String>, // the oracle sources /index_price takes for this market, anything else is turned away
+}
+
+// every account has one margin balance shared by all markets, so the collateral scale belongs to the registry
+// while each market picks its own price scale
+pub struct MarketRegistry {
+    pub collateral_decimals: u32,
+    markets: HashMap<u32, MarketConfig>,
+}
+
+impl MarketRegistry {
+    pub fn new(collateral_decimals: u32) -> Self {
+        Self { collateral_decimals, markets: HashMap::new() }
+    }
+
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new(COLLATERAL_DECIMALS);
+        let min_notional = 100 * 10u64.pow(COLLATERAL_DECIMALS);
+        let impact_notional = 10_000 * 10u64.pow(COLLATERAL_DECIMALS);
+        let max_open_interest = 50_000_000 * 10u64.pow(COLLATERAL_DECIMALS);
+        let index_sources: Vec<String> = ["binance", "coinbase", "kraken"].iter().map(|source| source.to_string()).collect();
+        registry.register(MarketConfig { id: DEFAULT_MARKET_ID, symbol: "BTC-PERP".to_string(), price_decimals: 2, min_notional, impact_notional, max_open_interest, index_sources: index_sources.clone() });
+        registry.register(MarketConfig { id: 1, symbol: "ETH-PERP".to_string(), price_decimals: 2, min_notional, impact_notional, max_open_interest, index_sources });
+        registry
+    }
+
+    pub fn register(&mut self, market: MarketConfig) {
+        self.markets.insert(market.id, market);
+    }
+
+    pub fn get(&self, id: u32) -> Option<&MarketConfig> {
+        self.markets.get(&id)
+    }
+
+    pub fn get_all(&self) -> Vec<MarketConfig> {
+        let mut markets: Vec<MarketConfig> = self.markets.values().cloned().collect();
+        markets.sort_by_key(|market| market.id);
+        markets
+    }
+
+    pub fn collateral(&self, amount: &Decimal) -> Result<u64, DecimalError> {
+        amount.to_raw(self.collateral_decimals)
+    }
+
+    pub fn collateral_decimal(&self, raw: u64) -> Decimal {
+        Decimal::from_raw(raw, self.collateral_decimals)
+    }
+}
+
+impl MarketConfig {
+    pub fn price(&self, price: &Decimal) -> Result<u64, DecimalError> {
+        price.to_raw(self.price_decimals)
+    }
+
+    pub fn price_decimal(&self, raw: u64) -> Decimal {
+        Decimal::from_raw(raw, self.price_decimals)
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::clob::Order;
+
+pub const MIN_AUCTION_INTERVAL_MS: u64 = 100;
+
+// how a market matches. continuous trades every order on arrival, batch collects them for interval_ms and clears
+// them all at once at a single price, so being first inside a batch is worth nothing
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum MatchingMode {
+    #[default]
+    Continuous,
+    Batch { interval_ms: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct AuctionOrder {
+    pub order: Order,
+    pub market_order: bool, // takes any price, order.price is meaningless
+    pub carry_over: bool, // unfilled size waits for the next batch, false for market and ioc orders
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuctionFill {
+    pub order_id: u64,
+    pub owner: u128,
+    pub is_buy: bool,
+    pub size: u64,
+    pub remaining: u64, // still waiting in the next batch, 0 if it was filled or dropped
+}
+
+// the event every batch publishes, whether anything traded or not
+#[derive(Debug, Clone, Serialize)]
+pub struct AuctionResult {
+    pub auction_id: u64,
+    pub clearing_price: Option<u64>, // None when nothing crossed
+    pub volume: u64,
+    pub buy_orders: usize,
+    pub sell_orders: usize,
+    pub fills: Vec<AuctionFill>,
+    pub dropped: Vec<u64>, // market and ioc orders that didnt fill, they dont carry over
+    pub timestamp: u64,
+}
+
+pub struct BatchAuction {
+    pub interval_ms: u64,
+    pub next_clear_at: u64,
+    next_auction_id: u64,
+    orders: Vec<AuctionOrder>, // arrival order
+}
+
+impl BatchAuction {
+    pub fn new(interval_ms: u64, now: u64) -> Self {
+        Self {
+            interval_ms,
+            next_clear_at: now + interval_ms,
+            next_auction_id: 1,
+            orders: Vec::new(),
+        }
+    }
+
+    pub fn add_order(&mut self, order: AuctionOrder) {
+        self.orders.push(order);
+    }
+
+    pub fn get_order(&self, order_id: u64) -> Option<&Order> {
+        self.orders.iter().map(|pending| &pending.order).find(|order| order.id == order_id)
+    }
+
+    pub fn cancel_order(&mut self, order_id: u64) -> bool {
+        let Some(index) = self.orders.iter().position(|pending| pending.order.id == order_id) else { return false };
+        self.orders.remove(index);
+        true
+    }
+
+    pub fn get_orders_for(&self, owner: u128) -> Vec<&Order> {
+        self.orders.iter().map(|pending| &pending.order).filter(|order| order.owner == owner).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    // clears everything collected so far at the price that trades the most. the side with more eligible size gets
+    // rationed, see allocate
+    pub fn clear(&mut self, now: u64) -> AuctionResult {
+        let auction_id = self.next_auction_id;
+        self.next_auction_id += 1;
+        self.next_clear_at = now + self.interval_ms;
+
+        let buy_orders = self.orders.iter().filter(|pending| pending.order.is_buy).count();
+        let sell_orders = self.orders.len() - buy_orders;
+        let mut allocations: HashMap<u64, u64> = HashMap::new();
+        let clearing = clearing_price(&self.orders);
+        if let Some((price, volume)) = clearing {
+            for is_buy in [true, false] {
+                let eligible: Vec<&AuctionOrder> = self.orders.iter()
+                    .filter(|pending| pending.order.is_buy == is_buy && accepts(pending, price))
+                    .collect();
+                allocations.extend(allocate(eligible, volume));
+            }
+        }
+
+        let mut fills = Vec::new();
+        let mut dropped = Vec::new();
+        for pending in self.orders.iter_mut() {
+            let filled = allocations.get(&pending.order.id).copied().unwrap_or(0);
+            pending.order.size -= filled;
+            let remaining = if pending.carry_over { pending.order.size } else { 0 };
+            if filled > 0 {
+                fills.push(AuctionFill { order_id: pending.order.id, owner: pending.order.owner, is_buy: pending.order.is_buy, size: filled, remaining });
+            } else if remaining == 0 {
+                dropped.push(pending.order.id);
+            }
+        }
+        self.orders.retain(|pending| pending.carry_over && pending.order.size > 0);
+
+        AuctionResult {
+            auction_id,
+            clearing_price: clearing.map(|(price, _)| price),
+            volume: clearing.map(|(_, volume)| volume).unwrap_or(0),
+            buy_orders,
+            sell_orders,
+            fills,
+            dropped,
+            timestamp: now,
+        }
+    }
+}
+
+// would the order trade at this price
+fn accepts(pending: &AuctionOrder, price: u64) -> bool {
+    pending.market_order || if pending.order.is_buy { pending.order.price >= price } else { pending.order.price <= price }
+}
+
+// every limit price in the batch is a candidate. picks the one with the most volume, then the smallest imbalance
+// between the two sides, then the middle of whatever is still tied so neither side gets the edge
+pub fn clearing_price(orders: &[AuctionOrder]) -> Option<(u64, u64)> {
+    let mut candidates: Vec<u64> = orders.iter().filter(|pending| !pending.market_order).map(|pending| pending.order.price).collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let mut best: Vec<(u64, u64, u64)> = Vec::new(); // (price, volume, imbalance)
+    for price in candidates {
+        let (mut demand, mut supply) = (0u64, 0u64);
+        for pending in orders.iter().filter(|pending| accepts(pending, price)) {
+            if pending.order.is_buy { demand += pending.order.size } else { supply += pending.order.size }
+        }
+        let volume = demand.min(supply);
+        let imbalance = demand.abs_diff(supply);
+        match best.first() {
+            Some(&(_, best_volume, best_imbalance)) if (volume, std::cmp::Reverse(imbalance)) < (best_volume, std::cmp::Reverse(best_imbalance)) => continue,
+            Some(&(_, best_volume, best_imbalance)) if (volume, imbalance) == (best_volume, best_imbalance) => best.push((price, volume, imbalance)),
+            _ => best = vec![(price, volume, imbalance)],
+        }
+    }
+    let (price, volume, _) = *best.get(best.len().saturating_sub(1) / 2)?;
+    if volume == 0 { None } else { Some((price, volume)) }
+}
+
+// splits volume over one sides eligible orders. more aggressive prices fill first and completely, the price level
+// where the volume runs out is shared pro rata by size. rounding leftovers go one unit at a time in arrival order
+fn allocate(mut eligible: Vec<&AuctionOrder>, volume: u64) -> HashMap<u64, u64> {
+    // market orders are the most aggressive, then the best price. buys want high prices, sells low ones
+    let aggressiveness = |pending: &AuctionOrder| -> i128 {
+        match (pending.market_order, pending.order.is_buy) {
+            (true, _) => i128::MAX,
+            (false, true) => pending.order.price as i128,
+            (false, false) => -(pending.order.price as i128),
+        }
+    };
+    eligible.sort_by(|a, b| aggressiveness(b).cmp(&aggressiveness(a)).then(a.order.timestamp.cmp(&b.order.timestamp)).then(a.order.id.cmp(&b.order.id)));
+
+    let mut allocations = HashMap::new();
+    let mut remaining = volume;
+    let mut start = 0;
+    while start < eligible.len() && remaining > 0 {
+        let level = aggressiveness(eligible[start]);
+        let end = eligible[start..].iter().position(|pending| aggressiveness(pending) != level).map_or(eligible.len(), |offset| start + offset);
+        let orders = &eligible[start..end];
+        let level_size: u64 = orders.iter().map(|pending| pending.order.size).sum();
+        if level_size <= remaining {
+            for pending in orders {
+                allocations.insert(pending.order.id, pending.order.size);
+            }
+            remaining -= level_size;
+        } else {
+            let mut shared = 0;
+            for pending in orders {
+                let share = (pending.order.size as u128 * remaining as u128 / level_size as u128) as u64;
+                allocations.insert(pending.order.id, share);
+                shared += share;
+            }
+            let mut leftover = remaining - shared;
+            for pending in orders {
+                if leftover == 0 {
+                    break;
+                }
+                let allocation = allocations.get_mut(&pending.order.id).unwrap();
+                let extra = leftover.min(pending.order.size - *allocation);
+                *allocation += extra;
+                leftover -= extra;
+            }
+            remaining = 0;
+        }
+        start = end;
+    }
+    allocations.retain(|_, size| *size > 0);
+    allocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::clob::Visibility;
+
+    fn order(id: u64, is_buy: bool, price: Option<u64>, size: u64) -> AuctionOrder {
+        AuctionOrder {
+            order: Order {
+                id,
+                owner: id as u128,
+                price: price.unwrap_or(0),
+                size,
+                is_buy,
+                timestamp: id, // arrival order
+                expires_at: None,
+                visibility: Visibility::Displayed,
+                shown: 0,
+            },
+            market_order: price.is_none(),
+            carry_over: price.is_some(),
+        }
+    }
+
+    fn allocated(orders: &[AuctionOrder], volume: u64) -> Vec<(u64, u64)> {
+        let mut allocations: Vec<(u64, u64)> = allocate(orders.iter().collect(), volume).into_iter().collect();
+        allocations.sort();
+        allocations
+    }
+
+    #[test]
+    fn clears_at_the_price_that_trades_the_most() {
+        let orders = vec![
+            order(1, true, Some(102), 10),
+            order(2, true, Some(101), 10),
+            order(3, true, Some(100), 10),
+            order(4, false, Some(99), 5),
+            order(5, false, Some(101), 15),
+        ];
+        // 99: 30 vs 5, 100: 30 vs 5, 101: 20 vs 20, 102: 10 vs 20
+        assert_eq!(clearing_price(&orders), Some((101, 20)));
+    }
+
+    #[test]
+    fn ties_go_to_the_smallest_imbalance_then_the_middle_price() {
+        // 100 and 101 both trade 10, 100 leaves 5 more demand unfilled
+        let orders = vec![order(1, true, Some(101), 10), order(2, true, Some(100), 5), order(3, false, Some(100), 10)];
+        assert_eq!(clearing_price(&orders), Some((101, 10)));
+
+        // 100, 102 and 104 all trade 10 with nothing left over, the middle one wins
+        let orders = vec![order(1, true, Some(104), 10), order(2, false, Some(100), 10), order(3, true, Some(102), 0)];
+        assert_eq!(clearing_price(&orders), Some((102, 10)));
+    }
+
+    #[test]
+    fn nothing_clears_when_the_book_doesnt_cross() {
+        let orders = vec![order(1, true, Some(99), 10), order(2, false, Some(100), 10)];
+        assert_eq!(clearing_price(&orders), None);
+        assert_eq!(clearing_price(&[]), None);
+        // market orders dont add candidate prices, only limit prices can set it
+        assert_eq!(clearing_price(&[order(1, true, None, 10), order(2, false, None, 10)]), None);
+        assert_eq!(clearing_price(&[order(1, true, None, 10), order(2, false, Some(100), 4)]), Some((100, 4)));
+    }
+
+    #[test]
+    fn better_prices_fill_first_and_the_marginal_level_is_pro_rata() {
+        let orders = vec![
+            order(1, true, None, 5),
+            order(2, true, Some(105), 10),
+            order(3, true, Some(101), 30),
+            order(4, true, Some(101), 10),
+        ];
+        // 15 goes to the market order and 105, the 20 left is split 30:10 at 101
+        assert_eq!(allocated(&orders, 35), vec![(1, 5), (2, 10), (3, 15), (4, 5)]);
+        assert_eq!(allocated(&orders, 10), vec![(1, 5), (2, 5)]);
+    }
+
+    #[test]
+    fn rounding_leftovers_go_in_arrival_order() {
+        let orders = vec![order(3, false, Some(100), 10), order(1, false, Some(100), 10), order(2, false, Some(100), 10)];
+        // 10 over three equal orders is 3 each and one left over, which goes to the earliest
+        assert_eq!(allocated(&orders, 10), vec![(1, 4), (2, 3), (3, 3)]);
+        assert_eq!(allocated(&orders, 1), vec![(1, 1)]);
+        assert_eq!(allocated(&orders, 0), vec![]);
+    }
+
+    #[test]
+    fn clear_fills_both_sides_and_carries_limit_orders_over() {
+        let mut auction = BatchAuction::new(1_000, 0);
+        auction.add_order(order(1, true, Some(101), 20));
+        auction.add_order(order(2, false, Some(100), 5));
+        auction.add_order(order(3, false, None, 5));
+        auction.add_order(order(4, true, None, 0));
+        let result = auction.clear(1_000);
+        // 100 and 101 trade the same 10 with the same imbalance, of two tied prices the lower one is the middle
+        assert_eq!((result.auction_id, result.clearing_price, result.volume), (1, Some(100), 10));
+        assert_eq!((result.buy_orders, result.sell_orders), (2, 2));
+        let fills: Vec<(u64, u64, u64)> = result.fills.iter().map(|fill| (fill.order_id, fill.size, fill.remaining)).collect();
+        assert_eq!(fills, vec![(1, 10, 10), (2, 5, 0), (3, 5, 0)]);
+        assert_eq!(result.dropped, vec![4]);
+        assert_eq!(auction.next_clear_at, 2_000);
+        assert_eq!(auction.get_order(1).unwrap().size, 10);
+        assert!(auction.get_order(2).is_none());
+
+        let result = auction.clear(2_000);
+        assert_eq!((result.auction_id, result.clearing_price, result.volume), (2, None, 0));
+        assert!(result.fills.is_empty());
+        assert!(!auction.is_empty());
+    }
+}
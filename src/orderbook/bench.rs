@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use crate::orderbook::clob::{CLOB, OrderOptions, TimeInForce};
+
+const MID_PRICE: u64 = 100_000;
+const BOOK_LEVELS: u64 = 100; // ticks each side of the mid that resting orders spread over
+pub const MAX_CLOB_BENCH_DEPTH: usize = 100_000;
+pub const MAX_CLOB_BENCH_OPERATIONS: usize = 100_000;
+pub const MAX_CLOB_BENCH_DEPTHS: usize = 8;
+
+#[derive(Serialize)]
+pub struct ClobTiming {
+    pub depth: usize, // resting orders per side when a run starts
+    pub operations: usize,
+    pub add_per_sec: f64,
+    pub cancel_per_sec: f64,
+    pub match_per_sec: f64,
+}
+
+fn per_sec(operations: usize, start: Instant) -> f64 {
+    operations as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON)
+}
+
+// a book with depth orders per side spread over BOOK_LEVELS ticks, nothing crosses
+fn seeded_book(depth: usize, rng: &mut impl Rng) -> CLOB {
+    let mut book = CLOB::new();
+    for _ in 0..depth {
+        book.add_order(1, Some(MID_PRICE - rng.gen_range(1..=BOOK_LEVELS)), rng.gen_range(1..1_000), true, OrderOptions::default()).unwrap();
+        book.add_order(2, Some(MID_PRICE + rng.gen_range(1..=BOOK_LEVELS)), rng.gen_range(1..1_000), false, OrderOptions::default()).unwrap();
+    }
+    book
+}
+
+// plain throughput of the book, no engine, lock or fhe work. adds are passive limit orders, cancels hit those same
+// orders in random order and matches are ioc orders sweeping about one resting order each, alternating sides. every
+// maker a match uses up is put back afterwards, outside the timing, so the book stays at depth for the whole run.
+// every depth gets a fresh book so the runs dont affect each other
+pub fn clob_timings(depths: Vec<usize>, operations: usize) -> Vec<ClobTiming> {
+    let mut rng = rand::thread_rng();
+    let ioc = OrderOptions { time_in_force: TimeInForce::Ioc, ..Default::default() };
+    depths.into_iter().map(|depth| {
+        let mut book = seeded_book(depth, &mut rng);
+        let adds: Vec<(u64, u64, bool)> = (0..operations)
+            .map(|i| {
+                let is_buy = i % 2 == 0;
+                let offset = rng.gen_range(1..=BOOK_LEVELS);
+                (if is_buy { MID_PRICE - offset } else { MID_PRICE + offset }, rng.gen_range(1..1_000), is_buy)
+            })
+            .collect();
+
+        let start = Instant::now();
+        let mut ids: Vec<u64> = adds.iter()
+            .map(|(price, size, is_buy)| book.add_order(3, Some(*price), *size, *is_buy, OrderOptions::default()).unwrap().order_id)
+            .collect();
+        let add_per_sec = per_sec(operations, start);
+
+        ids.shuffle(&mut rng);
+        let start = Instant::now();
+        for id in ids {
+            book.cancel_order(id);
+        }
+        let cancel_per_sec = per_sec(operations, start);
+
+        let mut matching = Duration::ZERO;
+        for i in 0..operations {
+            let start = Instant::now();
+            let result = book.add_order(4, None, 500, i % 2 == 0, ioc).unwrap();
+            matching += start.elapsed();
+            for fill in result.fills.iter().filter(|fill| fill.maker_remaining == 0) {
+                book.add_order(fill.maker, Some(fill.price), rng.gen_range(1..1_000), !fill.taker_is_buy, OrderOptions::default()).unwrap();
+            }
+        }
+        let match_per_sec = operations as f64 / matching.as_secs_f64().max(f64::EPSILON);
+
+        ClobTiming { depth, operations, add_per_sec, cancel_per_sec, match_per_sec }
+    }).collect()
+}
@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_CANDLES: usize = 1_440; // per market and resolution, a day of 1m candles up to about 4 years of 1d ones
+pub const CANDLES_PATH: &str = "state/candles.bin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 4] = [Resolution::OneMinute, Resolution::FiveMinutes, Resolution::OneHour, Resolution::OneDay];
+
+    pub fn millis(&self) -> u64 {
+        match self {
+            Resolution::OneMinute => 60_000,
+            Resolution::FiveMinutes => 300_000,
+            Resolution::OneHour => 3_600_000,
+            Resolution::OneDay => 86_400_000,
+        }
+    }
+
+    // start of the bucket a timestamp falls in, buckets are aligned to the unix epoch so days start at 00:00 utc
+    pub fn open_time(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.millis()
+    }
+}
+
+// prices are raw units of the markets price scale, volume is raw collateral like trade sizes
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Candle {
+    pub open_time: u64, // unix millis
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub volume: u64,
+    pub trades: u32,
+}
+
+impl Candle {
+    fn new(open_time: u64, price: u64, size: u64) -> Self {
+        Self { open_time, open: price, high: price, low: price, close: price, volume: size, trades: 1 }
+    }
+
+    fn add(&mut self, price: u64, size: u64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume = self.volume.saturating_add(size);
+        self.trades += 1;
+    }
+}
+
+// candles per market and resolution, oldest at the front. only buckets that traded get a candle, charts fill gaps
+// with the previous close themselves. the store is written to disk by the persist loop and read back on startup,
+// the rest of the book state is in memory only for now
+#[derive(Deserialize, Serialize)]
+pub struct CandleStore {
+    candles: HashMap<(u32, Resolution), VecDeque<Candle>>,
+    #[serde(skip)]
+    changed: bool, // since the last snapshot
+}
+
+impl CandleStore {
+    pub fn new() -> Self {
+        Self { candles: HashMap::new(), changed: false }
+    }
+
+    // whatever was saved at path last. a missing file is a fresh start, one that cant be read is logged and
+    // replaced on the next save rather than keeping the server from starting
+    pub fn load(path: &str) -> Self {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return Self::new(),
+        };
+        match bincode::deserialize::<CandleStore>(&data) {
+            Ok(store) => {
+                println!("Loaded {} candle series from {}", store.candles.len(), path);
+                store
+            }
+            Err(e) => {
+                println!("Failed to read candles from {}, starting empty: {}", path, e);
+                Self::new()
+            }
+        }
+    }
+
+    // the serialized store if anything was recorded since the last call. cheap enough to take under the books
+    // lock, the write happens after it is released
+    pub fn snapshot(&mut self) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        bincode::serialize(self).ok()
+    }
+
+    // trades come in timestamp order, one that lands in an older bucket (an auction clearing late) is folded into
+    // that candle if it is still kept and dropped otherwise. it doesnt move open or close, those follow arrival
+    pub fn record(&mut self, market: u32, price: u64, size: u64, timestamp: u64) {
+        self.changed = true;
+        for resolution in Resolution::ALL {
+            let open_time = resolution.open_time(timestamp);
+            let candles = self.candles.entry((market, resolution)).or_default();
+            match candles.back_mut() {
+                Some(last) if last.open_time == open_time => last.add(price, size),
+                Some(last) if last.open_time > open_time => {
+                    if let Ok(index) = candles.binary_search_by_key(&open_time, |candle| candle.open_time) {
+                        let candle = &mut candles[index];
+                        candle.high = candle.high.max(price);
+                        candle.low = candle.low.min(price);
+                        candle.volume = candle.volume.saturating_add(size);
+                        candle.trades += 1;
+                    }
+                }
+                _ => {
+                    candles.push_back(Candle::new(open_time, price, size));
+                    while candles.len() > MAX_CANDLES {
+                        candles.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    // candles whose bucket opens in [from, to], oldest first. with more than limit in range the newest ones are kept
+    pub fn range(&self, market: u32, resolution: Resolution, from: u64, to: u64, limit: usize) -> Vec<Candle> {
+        let Some(candles) = self.candles.get(&(market, resolution)) else { return Vec::new() };
+        let start = candles.partition_point(|candle| candle.open_time < from);
+        let end = candles.partition_point(|candle| candle.open_time <= to);
+        if start >= end {
+            return Vec::new();
+        }
+        candles.range(start.max(end.saturating_sub(limit))..end).cloned().collect()
+    }
+}
+
+// writes a snapshot next to path and renames it over, so a crash mid write leaves the previous file in place
+pub fn save_snapshot(path: &str, snapshot: &[u8]) -> Result<(), String> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, snapshot).map_err(|e| format!("Failed to write candles: {}", e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to save candles: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+
+    fn ohlcv(candle: &Candle) -> (u64, u64, u64, u64, u64, u64, u32) {
+        (candle.open_time, candle.open, candle.high, candle.low, candle.close, candle.volume, candle.trades)
+    }
+
+    #[test]
+    fn buckets_are_aligned_to_the_epoch() {
+        assert_eq!(Resolution::OneMinute.open_time(0), 0);
+        assert_eq!(Resolution::OneMinute.open_time(MINUTE - 1), 0);
+        assert_eq!(Resolution::OneMinute.open_time(MINUTE), MINUTE);
+        assert_eq!(Resolution::FiveMinutes.open_time(7 * MINUTE + 5), 5 * MINUTE);
+        assert_eq!(Resolution::OneHour.open_time(61 * MINUTE), 60 * MINUTE);
+        assert_eq!(Resolution::OneDay.open_time(86_400_000 + 1), 86_400_000);
+    }
+
+    #[test]
+    fn trades_in_a_bucket_build_one_candle() {
+        let mut store = CandleStore::new();
+        store.record(0, 100, 5, 1_000);
+        store.record(0, 104, 1, 2_000);
+        store.record(0, 98, 2, 3_000);
+        store.record(0, 101, 4, 4_000);
+        store.record(0, 110, 3, MINUTE + 1);
+        let candles = store.range(0, Resolution::OneMinute, 0, u64::MAX, 10);
+        assert_eq!(candles.iter().map(ohlcv).collect::<Vec<_>>(), vec![
+            (0, 100, 104, 98, 101, 12, 4),
+            (MINUTE, 110, 110, 110, 110, 3, 1),
+        ]);
+        let candles = store.range(0, Resolution::FiveMinutes, 0, u64::MAX, 10);
+        assert_eq!(candles.iter().map(ohlcv).collect::<Vec<_>>(), vec![(0, 100, 110, 98, 110, 15, 5)]);
+        assert!(store.range(1, Resolution::OneMinute, 0, u64::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn late_trades_fold_into_their_bucket_without_moving_open_or_close() {
+        let mut store = CandleStore::new();
+        store.record(0, 100, 1, 0);
+        store.record(0, 105, 1, MINUTE);
+        store.record(0, 90, 2, 30_000); // lands in the first minute after the second one opened
+        let candles = store.range(0, Resolution::OneMinute, 0, u64::MAX, 10);
+        assert_eq!(candles.iter().map(ohlcv).collect::<Vec<_>>(), vec![
+            (0, 100, 100, 90, 100, 3, 2),
+            (MINUTE, 105, 105, 105, 105, 1, 1),
+        ]);
+    }
+
+    #[test]
+    fn only_the_newest_candles_are_kept() {
+        let mut store = CandleStore::new();
+        for i in 0..MAX_CANDLES as u64 + 5 {
+            store.record(0, 100 + i, 1, i * MINUTE);
+        }
+        let candles = store.range(0, Resolution::OneMinute, 0, u64::MAX, usize::MAX);
+        assert_eq!(candles.len(), MAX_CANDLES);
+        assert_eq!(candles[0].open_time, 5 * MINUTE);
+        // a late trade for a bucket that was already dropped goes nowhere
+        store.record(0, 1, 1, 0);
+        assert_eq!(store.range(0, Resolution::OneMinute, 0, u64::MAX, usize::MAX)[0].open_time, 5 * MINUTE);
+    }
+
+    #[test]
+    fn range_is_inclusive_and_keeps_the_newest_past_the_limit() {
+        let mut store = CandleStore::new();
+        for i in 0..10 {
+            store.record(0, 100 + i, 1, i * MINUTE);
+        }
+        let open_times = |from, to, limit| -> Vec<u64> {
+            store.range(0, Resolution::OneMinute, from, to, limit).iter().map(|candle| candle.open_time / MINUTE).collect()
+        };
+        assert_eq!(open_times(2 * MINUTE, 4 * MINUTE, 10), vec![2, 3, 4]);
+        assert_eq!(open_times(2 * MINUTE + 1, 4 * MINUTE, 10), vec![3, 4]);
+        assert_eq!(open_times(0, u64::MAX, 3), vec![7, 8, 9]);
+        assert_eq!(open_times(0, 5 * MINUTE, 2), vec![4, 5]);
+        assert!(open_times(4 * MINUTE, 2 * MINUTE, 10).is_empty());
+        assert!(open_times(20 * MINUTE, u64::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn snapshots_only_come_after_a_change_and_load_back() {
+        let mut store = CandleStore::new();
+        assert!(store.snapshot().is_none());
+        store.record(0, 100, 5, 1_000);
+        let snapshot = store.snapshot().unwrap();
+        assert!(store.snapshot().is_none());
+
+        let path = std::env::temp_dir().join(format!("candles-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_snapshot(path, &snapshot).unwrap();
+        let loaded = CandleStore::load(path);
+        fs::remove_file(path).unwrap();
+        let candles = loaded.range(0, Resolution::OneDay, 0, u64::MAX, 10);
+        assert_eq!(candles.iter().map(ohlcv).collect::<Vec<_>>(), vec![(0, 100, 100, 100, 100, 5, 1)]);
+        assert!(CandleStore::load(path).range(0, Resolution::OneDay, 0, u64::MAX, 10).is_empty());
+    }
+}
@@ -1,138 +1,1003 @@
-use std::collections::VecDeque;
-use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use serde::{Deserialize, Serialize};
 
-// Represents a single order in the book
+// Represents a single order in the book. price is in raw units of the markets price scale, size is notional in
+// raw collateral units like Position.notional
 #[derive(Debug, Clone)]
 pub struct Order {
     pub id: u64,
+    pub owner: u128,
     pub price: u64,
     pub size: u64,
     pub is_buy: bool,
     pub timestamp: u64,
+    pub expires_at: Option<u64>, // unix millis, good til time orders only
+    pub visibility: Visibility,
+    pub shown: u64, // what depth shows of it: all of size, the current iceberg slice, or 0 when hidden
 }
 
-// Represents a price level in the order book
-#[derive(Debug)]
-struct PriceLevel {
-    price: u64,
-    orders: VecDeque<Order>,
+// how much of a resting order the book shows. an iceberg shows up to display at a time, once that slice is filled
+// the next one comes out of the reserve at the back of the queue. hidden orders show nothing and only trade after
+// every displayed order at their price
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Visibility {
+    #[default]
+    Displayed,
+    Iceberg { display: u64 },
+    Hidden,
 }
 
-impl PriceLevel {
-    fn new(price: u64) -> Self {
-        Self {
-            price,
-            orders: VecDeque::new(),
+impl Visibility {
+    // the slice an order of this size shows when it goes to the back of a level
+    fn shown(&self, size: u64) -> u64 {
+        match self {
+            Visibility::Displayed => size,
+            Visibility::Iceberg { display } => size.min(*display),
+            Visibility::Hidden => 0,
         }
     }
 }
 
-// Node color for the red-black tree
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Color {
-    Red,
-    Black,
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    Gtc, // rests until filled or cancelled
+    Ioc, // fills what it can right away, the rest is cancelled
+    Fok, // fills completely right away or not at all
+    Gtt, // rests until expires_at, then the sweeper cancels it
+}
+
+// what happens when an incoming order would trade against a resting order of the same owner. the incoming
+// orders mode decides
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTradePrevention {
+    #[default]
+    CancelNewest, // the rest of the incoming order is cancelled, the resting one stays
+    CancelOldest, // the resting order is cancelled and matching carries on
+    CancelBoth,
+    DecrementAndCancel, // both shrink by the smaller of the two without trading, whichever hits 0 is cancelled
+}
+
+// reduce only isnt in here, whether an order shrinks a position is up to the engine since the book doesnt know them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrderOptions {
+    pub time_in_force: TimeInForce,
+    pub expires_at: Option<u64>,
+    pub post_only: bool, // rejected instead of taking displayed liquidity, hidden orders it reaches it trades with
+    pub visibility: Visibility,
+    pub self_trade: SelfTradePrevention,
 }
 
-// Node in the red-black tree
+#[derive(Debug, PartialEq)]
+pub enum OrderError {
+    WouldCross,
+    NotFillable,
+    MarketOrderNotAllowed,
+    InvalidExpiry,
+    NotFound,
+    InvalidSize,
+    InvalidDisplay,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::WouldCross => write!(f, "post only order would cross the book"),
+            OrderError::NotFillable => write!(f, "fill or kill order cant be filled completely"),
+            OrderError::MarketOrderNotAllowed => write!(f, "post only, good til time, iceberg and hidden orders need a price"),
+            OrderError::InvalidExpiry => write!(f, "good til time orders need an expires_at in the future"),
+            OrderError::NotFound => write!(f, "order not found"),
+            OrderError::InvalidSize => write!(f, "size has to be above 0"),
+            OrderError::InvalidDisplay => write!(f, "iceberg display size has to be above 0"),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+// the orders of one priority class at a level, linked through the arena oldest first
+#[derive(Debug, Clone, Copy, Default)]
+struct Queue {
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+// Represents a price level in the order book. the orders themselves live in the CLOB's arena, a level is the two
+// queues through it plus running totals so depth doesnt have to walk them. displayed orders and iceberg slices
+// fill first, hidden orders only once those are gone
 #[derive(Debug)]
-struct RBNode {
-    price_level: PriceLevel,
-    color: Color,
-    left: Option<Box<RBNode>>,
-    right: Option<Box<RBNode>>,
-    parent: Option<*mut RBNode>,
+pub struct PriceLevel {
+    pub price: u64,
+    displayed: Queue,
+    hidden: Queue,
+    order_count: usize,
+    displayed_orders: usize,
+    displayed_size: u64, // hidden orders and iceberg reserves arent in here
 }
 
-impl RBNode {
+impl PriceLevel {
     fn new(price: u64) -> Self {
         Self {
-            price_level: PriceLevel::new(price),
-            color: Color::Red,
-            left: None,
-            right: None,
-            parent: None,
+            price,
+            displayed: Queue::default(),
+            hidden: Queue::default(),
+            order_count: 0,
+            displayed_orders: 0,
+            displayed_size: 0,
         }
     }
+
+    fn queue_mut(&mut self, hidden: bool) -> &mut Queue {
+        if hidden { &mut self.hidden } else { &mut self.displayed }
+    }
+
+    // the next order to fill
+    fn head(&self) -> Option<usize> {
+        self.displayed.head.or(self.hidden.head)
+    }
+
+    // what the public book shows, hidden orders and iceberg reserves left out
+    pub fn displayed_size(&self) -> u64 {
+        self.displayed_size
+    }
+
+    pub fn displayed_orders(&self) -> usize {
+        self.displayed_orders
+    }
 }
 
-// The main CLOB structure
+// one maker order trading against one taker, always at the makers price
+#[derive(Debug, Clone, Serialize)]
+pub struct Fill {
+    pub maker_order_id: u64,
+    pub taker_order_id: u64,
+    pub maker: u128,
+    pub taker: u128,
+    pub price: u64,
+    pub size: u64,
+    pub taker_is_buy: bool,
+    pub maker_remaining: u64, // 0 once the maker order is used up
+    pub timestamp: u64,
+}
+
+// emitted for every order self trade prevention took size off, the incoming one included
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTradeCancel {
+    pub order_id: u64,
+    pub owner: u128,
+    pub cancelled_size: u64,
+    pub remaining: u64, // only above 0 for a resting order decrement and cancel shrank without cancelling
+    pub mode: SelfTradePrevention,
+    pub timestamp: u64,
+}
+
+pub struct OrderResult {
+    pub order_id: u64,
+    pub fills: Vec<Fill>,
+    pub resting_size: u64, // what was left on the book, always 0 for market, ioc and fok orders
+    pub expired: Vec<u64>, // good til time makers that were found expired while matching and dropped
+    pub self_trades: Vec<SelfTradeCancel>,
+}
+
+// an order in the arena, linked to its neighbours in its levels queue by slot
+#[derive(Debug)]
+struct OrderNode {
+    order: Order,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+// The main CLOB structure. levels are kept in a btree per side keyed by price, the orders in one arena shared by
+// both sides. each level is a linked queue through the arena and the index maps an order id to its slot, so a
+// cancel doesnt have to search for the order
 pub struct CLOB {
-    bids: Option<Box<RBNode>>,  // Red-black tree for bids (highest price first)
-    asks: Option<Box<RBNode>>,  // Red-black tree for asks (lowest price first)
+    bids: BTreeMap<u64, PriceLevel>, // best bid is the last key
+    asks: BTreeMap<u64, PriceLevel>, // best ask is the first key
+    arena: Vec<Option<OrderNode>>, // a slot is reused once its order leaves the book
+    free_slots: Vec<usize>,
     next_order_id: u64,
+    order_index: HashMap<u64, usize>, // order id -> arena slot
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 impl CLOB {
     pub fn new() -> Self {
         Self {
-            bids: None,
-            asks: None,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            arena: Vec::new(),
+            free_slots: Vec::new(),
             next_order_id: 1,
+            order_index: HashMap::new(),
         }
     }
 
-    pub fn add_order(&mut self, price: u64, size: u64, is_buy: bool) -> u64 {
-        let order = Order {
+    // price None is a market order, it takes whatever is on the other side and the rest is dropped.
+    // a limit order matches while it crosses and rests with whatever is left, unless its time in force says otherwise
+    pub fn add_order(&mut self, owner: u128, price: Option<u64>, size: u64, is_buy: bool, options: OrderOptions) -> Result<OrderResult, OrderError> {
+        self.check_order(owner, price, size, is_buy, options)?;
+        let now = now_millis();
+        let expires_at = match options.time_in_force {
+            TimeInForce::Gtt => options.expires_at,
+            _ => None,
+        };
+
+        let mut order = Order {
             id: self.next_order_id,
-            price,
+            owner,
+            price: price.unwrap_or(0),
             size,
             is_buy,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
+            timestamp: now,
+            expires_at,
+            visibility: options.visibility,
+            shown: 0, // set when it rests
+        };
+
+        let id = order.id;
+        self.next_order_id += 1;
+
+        let (fills, expired, self_trades) = self.match_order(&mut order, price, options.self_trade);
+        let rests = matches!(options.time_in_force, TimeInForce::Gtc | TimeInForce::Gtt);
+        let resting_size = match price {
+            Some(_) if rests && order.size > 0 => order.size,
+            _ => 0,
+        };
+
+        if resting_size > 0 {
+            self.rest_order(order);
+        }
+
+        Ok(OrderResult { order_id: id, fills, resting_size, expired, self_trades })
+    }
+
+    // everything add_order would reject the order for, without touching the book
+    pub fn check_order(&self, owner: u128, price: Option<u64>, size: u64, is_buy: bool, options: OrderOptions) -> Result<(), OrderError> {
+        let now = now_millis();
+        if price.is_none() && (options.post_only || options.time_in_force == TimeInForce::Gtt || options.visibility != Visibility::Displayed) {
+            return Err(OrderError::MarketOrderNotAllowed);
+        }
+        if options.visibility == (Visibility::Iceberg { display: 0 }) {
+            return Err(OrderError::InvalidDisplay);
+        }
+        if options.time_in_force == TimeInForce::Gtt && !options.expires_at.is_some_and(|expires_at| expires_at > now) {
+            return Err(OrderError::InvalidExpiry);
+        }
+        if let (true, Some(price)) = (options.post_only, price) {
+            if self.crosses_displayed(is_buy, price) {
+                return Err(OrderError::WouldCross);
+            }
+        }
+        if options.time_in_force == TimeInForce::Fok && !self.fills_completely(owner, is_buy, price, size, options.self_trade, now) {
+            return Err(OrderError::NotFillable);
+        }
+        Ok(())
+    }
+
+    // would an order at this price take displayed liquidity. levels with only hidden orders dont count: a post only
+    // order that only reaches those trades with them instead of being rejected, otherwise a rejection would tell the
+    // sender there is hidden size inside the spread
+    fn crosses_displayed(&self, is_buy: bool, price: u64) -> bool {
+        self.get_displayed_levels(!is_buy, 1).first().is_some_and(|level| {
+            if is_buy { level.price <= price } else { level.price >= price }
+        })
+    }
+
+    // whether an order could fill all of size right now. walks the other side the way matching would, skipping
+    // makers that have expired and applying self trade prevention to the owners own orders
+    fn fills_completely(&self, owner: u128, is_buy: bool, limit: Option<u64>, size: u64, self_trade: SelfTradePrevention, now: u64) -> bool {
+        let mut remaining = size;
+        for level in self.get_levels(!is_buy, usize::MAX) {
+            let crosses = match limit {
+                None => true,
+                Some(limit) => if is_buy { level.price <= limit } else { level.price >= limit },
+            };
+            if !crosses {
+                break;
+            }
+            for maker in self.level_orders(level) {
+                if maker.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    continue;
+                }
+                if maker.owner == owner {
+                    match self_trade {
+                        SelfTradePrevention::CancelOldest => continue,
+                        SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => return false,
+                        SelfTradePrevention::DecrementAndCancel => {
+                            remaining -= remaining.min(maker.size);
+                            if remaining == 0 {
+                                return false; // used up without trading
+                            }
+                            continue;
+                        }
+                    }
+                }
+                remaining -= remaining.min(maker.size);
+                if remaining == 0 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // cancels every good til time order past its expiry, returns their ids
+    pub fn expire_orders(&mut self, now: u64) -> Vec<u64> {
+        let expired: Vec<u64> = self.arena.iter()
+            .flatten()
+            .filter(|node| node.order.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|node| node.order.id)
+            .collect();
+        for order_id in expired.iter() {
+            self.cancel_order(*order_id);
+        }
+        expired
+    }
+
+    // walks the best levels of the other side in price-time priority until the taker is filled or stops crossing.
+    // at each price the displayed queue goes first, an iceberg only gives up its current slice before it goes to
+    // the back for the next one, hidden orders come last. expired makers the sweeper hasnt got to yet are dropped on
+    // the way instead of trading, and makers of the takers own owner go through self trade prevention
+    fn match_order(&mut self, taker: &mut Order, limit: Option<u64>, self_trade: SelfTradePrevention) -> (Vec<Fill>, Vec<u64>, Vec<SelfTradeCancel>) {
+        let mut fills = Vec::new();
+        let mut expired = Vec::new();
+        let mut self_trades = Vec::new();
+        while taker.size > 0 {
+            let Some(level) = (if taker.is_buy { self.get_best_ask() } else { self.get_best_bid() }) else { break };
+            let crosses = match limit {
+                None => true,
+                Some(limit) => if taker.is_buy { level.price <= limit } else { level.price >= limit },
+            };
+            if !crosses {
+                break;
+            }
+            let slot = level.head().unwrap();
+            let maker = &self.arena[slot].as_ref().unwrap().order;
+            if maker.expires_at.is_some_and(|expires_at| expires_at <= taker.timestamp) {
+                expired.push(maker.id);
+                self.remove_slot(slot);
+                continue;
+            }
+            if maker.owner == taker.owner {
+                self_trades.extend(self.prevent_self_trade(taker, slot, self_trade));
+                continue;
+            }
+            let hidden = maker.visibility == Visibility::Hidden;
+            let size = if hidden { maker.size } else { maker.shown }.min(taker.size);
+            taker.size -= size;
+            self.modify_order(slot, |maker| {
+                maker.size -= size;
+                if !hidden {
+                    maker.shown -= size;
+                }
+            });
+            let maker = &self.arena[slot].as_ref().unwrap().order;
+            fills.push(Fill {
+                maker_order_id: maker.id,
+                taker_order_id: taker.id,
+                maker: maker.owner,
+                taker: taker.owner,
+                price: maker.price,
+                size,
+                taker_is_buy: taker.is_buy,
+                maker_remaining: maker.size,
+                timestamp: taker.timestamp,
+            });
+            if maker.size == 0 {
+                self.remove_slot(slot);
+            } else if maker.shown == 0 && !hidden {
+                // iceberg slice used up, the next one queues behind everyone else at the price
+                self.unlink(slot);
+                let maker = &mut self.arena[slot].as_mut().unwrap().order;
+                maker.shown = maker.visibility.shown(maker.size);
+                maker.timestamp = taker.timestamp;
+                self.link_back(slot);
+            }
+        }
+        (fills, expired, self_trades)
+    }
+
+    // the taker ran into a resting order of its own owner. cancelling the taker sets its size to 0, which ends
+    // matching and keeps it off the book
+    fn prevent_self_trade(&mut self, taker: &mut Order, slot: usize, mode: SelfTradePrevention) -> Vec<SelfTradeCancel> {
+        let timestamp = taker.timestamp;
+        let event = |order: &Order, cancelled_size: u64, remaining: u64| SelfTradeCancel {
+            order_id: order.id,
+            owner: order.owner,
+            cancelled_size,
+            remaining,
+            mode,
+            timestamp,
+        };
+        let mut events = Vec::new();
+        let (cancel_taker, cancel_maker) = match mode {
+            SelfTradePrevention::CancelNewest => (true, false),
+            SelfTradePrevention::CancelOldest => (false, true),
+            SelfTradePrevention::CancelBoth => (true, true),
+            SelfTradePrevention::DecrementAndCancel => {
+                let maker_size = self.arena[slot].as_ref().unwrap().order.size;
+                let size = maker_size.min(taker.size);
+                taker.size -= size;
+                if size < maker_size {
+                    self.modify_order(slot, |maker| {
+                        maker.size -= size;
+                        maker.shown = maker.shown.min(maker.size);
+                    });
+                    events.push(event(&self.arena[slot].as_ref().unwrap().order, size, maker_size - size));
+                } else {
+                    events.push(event(&self.remove_slot(slot), size, 0));
+                }
+                if taker.size == 0 {
+                    events.push(event(taker, size, 0));
+                }
+                return events;
+            }
         };
+        if cancel_maker {
+            let maker = self.remove_slot(slot);
+            events.push(event(&maker, maker.size, 0));
+        }
+        if cancel_taker {
+            events.push(event(taker, taker.size, 0));
+            taker.size = 0;
+        }
+        events
+    }
+
+    // changes a resting orders price and/or size, the id stays the same. shrinking it at the same price keeps its
+    // place in the queue. a new price or a bigger size takes it out and sends it through like a new order: it trades
+    // if the new price crosses and whats left goes to the back of the level. options are the ones it was placed
+    // with, a post only order that would cross is rejected and stays where it was
+    pub fn amend_order(&mut self, order_id: u64, price: Option<u64>, size: Option<u64>, options: OrderOptions) -> Result<OrderResult, OrderError> {
+        let slot = *self.order_index.get(&order_id).ok_or(OrderError::NotFound)?;
+        let existing = &self.arena[slot].as_ref().unwrap().order;
+        let (owner, is_buy, old_price, old_size) = (existing.owner, existing.is_buy, existing.price, existing.size);
+        let (new_price, new_size) = (price.unwrap_or(old_price), size.unwrap_or(old_size));
+        if new_size == 0 {
+            return Err(OrderError::InvalidSize);
+        }
+
+        if new_price == old_price && new_size <= old_size {
+            // an iceberg keeps whats left of its slice, the reserve is what shrinks first
+            self.modify_order(slot, |order| {
+                order.size = new_size;
+                order.shown = match order.visibility {
+                    Visibility::Iceberg { .. } => order.shown.min(new_size),
+                    visibility => visibility.shown(new_size),
+                };
+            });
+            return Ok(OrderResult { order_id, fills: Vec::new(), resting_size: new_size, expired: Vec::new(), self_trades: Vec::new() });
+        }
+
+        // the order is on its own side so it cant get in the way of the check
+        self.check_order(owner, Some(new_price), new_size, is_buy, options)?;
+        let mut order = self.remove_slot(slot);
+        order.price = new_price;
+        order.size = new_size;
+        order.timestamp = now_millis();
+        let (fills, expired, self_trades) = self.match_order(&mut order, Some(new_price), options.self_trade);
+        let resting_size = order.size;
+        if resting_size > 0 {
+            self.rest_order(order);
+        }
+        Ok(OrderResult { order_id, fills, resting_size, expired, self_trades })
+    }
+
+    // puts the order at the back of its price level, showing a fresh slice
+    fn rest_order(&mut self, mut order: Order) {
+        order.shown = order.visibility.shown(order.size);
+        let id = order.id;
+        let node = OrderNode { order, prev: None, next: None };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.arena[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.arena.push(Some(node));
+                self.arena.len() - 1
+            }
+        };
+        self.link_back(slot);
+        self.order_index.insert(id, slot);
+    }
+
+    // unlinks the order in slot from its level and the book. constant time, apart from dropping the level from the
+    // btree when this was its last order
+    fn remove_slot(&mut self, slot: usize) -> Order {
+        self.unlink(slot);
+        let node = self.arena[slot].take().unwrap();
+        self.free_slots.push(slot);
+        self.order_index.remove(&node.order.id);
+        node.order
+    }
 
+    // appends the order in slot to the right queue of its level, creating the level if its new
+    fn link_back(&mut self, slot: usize) {
+        let order = &self.arena[slot].as_ref().unwrap().order;
+        let (price, shown, hidden) = (order.price, order.shown, order.visibility == Visibility::Hidden);
+        let side = if order.is_buy { &mut self.bids } else { &mut self.asks };
+        let level = side.entry(price).or_insert_with(|| PriceLevel::new(price));
+        let queue = level.queue_mut(hidden);
+        let tail = queue.tail.replace(slot);
+        match tail {
+            Some(tail) => self.arena[tail].as_mut().unwrap().next = Some(slot),
+            None => queue.head = Some(slot),
+        }
+        let node = self.arena[slot].as_mut().unwrap();
+        node.prev = tail;
+        node.next = None;
+        level.order_count += 1;
+        if !hidden {
+            level.displayed_orders += 1;
+            level.displayed_size += shown;
+        }
+    }
+
+    // takes the order in slot out of its levels queue, the slot itself stays
+    fn unlink(&mut self, slot: usize) {
+        let node = self.arena[slot].as_ref().unwrap();
+        let (prev, next) = (node.prev, node.next);
+        let (is_buy, price, shown, hidden) = (node.order.is_buy, node.order.price, node.order.shown, node.order.visibility == Visibility::Hidden);
+        if let Some(prev) = prev {
+            self.arena[prev].as_mut().unwrap().next = next;
+        }
+        if let Some(next) = next {
+            self.arena[next].as_mut().unwrap().prev = prev;
+        }
+        let side = if is_buy { &mut self.bids } else { &mut self.asks };
+        let level = side.get_mut(&price).unwrap();
+        let queue = level.queue_mut(hidden);
+        if queue.head == Some(slot) {
+            queue.head = next;
+        }
+        if queue.tail == Some(slot) {
+            queue.tail = prev;
+        }
+        level.order_count -= 1;
+        if !hidden {
+            level.displayed_orders -= 1;
+            level.displayed_size -= shown;
+        }
+        if level.order_count == 0 {
+            side.remove(&price);
+        }
+    }
+
+    // changes a resting orders size or slice where it sits, keeping its levels displayed size in step
+    fn modify_order(&mut self, slot: usize, change: impl FnOnce(&mut Order)) {
+        let order = &mut self.arena[slot].as_mut().unwrap().order;
+        let old_shown = order.shown;
+        change(order);
+        let side = if order.is_buy { &mut self.bids } else { &mut self.asks };
+        let level = side.get_mut(&order.price).unwrap();
+        level.displayed_size = level.displayed_size - old_shown + order.shown;
+    }
+
+    pub fn cancel_order(&mut self, order_id: u64) -> bool {
+        let Some(&slot) = self.order_index.get(&order_id) else { return false };
+        self.remove_slot(slot);
+        true
+    }
+
+    // for orders that live outside the book (batch auctions) but share its id space
+    pub fn reserve_order_id(&mut self) -> u64 {
+        let id = self.next_order_id;
         self.next_order_id += 1;
+        id
+    }
 
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    pub fn get_order(&self, order_id: u64) -> Option<&Order> {
+        let slot = self.order_index.get(&order_id)?;
+        self.arena[*slot].as_ref().map(|node| &node.order)
+    }
+
+    // best first, at most levels of them
+    pub fn get_levels(&self, is_buy: bool, levels: usize) -> Vec<&PriceLevel> {
         if is_buy {
-            self.insert_bid(order);
+            self.bids.values().rev().take(levels).collect()
         } else {
-            self.insert_ask(order);
+            self.asks.values().take(levels).collect()
         }
+    }
 
-        order.id
+    // best first like get_levels, skipping levels that only have hidden orders. this is the public book
+    pub fn get_displayed_levels(&self, is_buy: bool, levels: usize) -> Vec<&PriceLevel> {
+        let displayed = |level: &&PriceLevel| level.displayed_orders > 0;
+        if is_buy {
+            self.bids.values().rev().filter(displayed).take(levels).collect()
+        } else {
+            self.asks.values().filter(displayed).take(levels).collect()
+        }
     }
 
-    fn insert_bid(&mut self, order: Order) {
-        // TODO: Implement red-black tree insertion for bids
-        // This will maintain the tree property where higher prices are to the left
+    // a levels orders in the order they fill, displayed oldest first and then hidden oldest first
+    pub fn level_orders<'a>(&'a self, level: &'a PriceLevel) -> impl Iterator<Item = &'a Order> + 'a {
+        let queue = |mut next: Option<usize>| std::iter::from_fn(move || {
+            let node = self.arena[next?].as_ref().unwrap();
+            next = node.next;
+            Some(&node.order)
+        });
+        queue(level.displayed.head).chain(queue(level.hidden.head))
     }
 
-    fn insert_ask(&mut self, order: Order) {
-        // TODO: Implement red-black tree insertion for asks
-        // This will maintain the tree property where lower prices are to the left
+    pub fn get_orders_for(&self, owner: u128) -> Vec<&Order> {
+        [true, false].iter()
+            .flat_map(|is_buy| self.get_levels(*is_buy, usize::MAX))
+            .flat_map(|level| self.level_orders(level))
+            .filter(|order| order.owner == owner)
+            .collect()
     }
 
-    pub fn cancel_order(&mut self, order_id: u64) -> bool {
-        // TODO: Implement order cancellation
-        false
+    // average price a taker would get for notional against the displayed book, size weighted like fills are.
+    // rounds away from the mid, bids down and asks up. None when the side isnt that deep
+    pub fn impact_price(&self, is_buy: bool, notional: u64) -> Option<u64> {
+        if notional == 0 {
+            return None;
+        }
+        let mut left = notional as u128;
+        let mut weighted: u128 = 0;
+        for level in self.get_displayed_levels(is_buy, usize::MAX) {
+            let size = left.min(level.displayed_size as u128);
+            weighted += level.price as u128 * size;
+            left -= size;
+            if left == 0 {
+                let notional = notional as u128;
+                return Some(if is_buy { weighted / notional } else { weighted.div_ceil(notional) } as u64);
+            }
+        }
+        None
     }
 
     pub fn get_best_bid(&self) -> Option<&PriceLevel> {
-        // TODO: Implement getting the highest bid price level
-        None
+        self.bids.values().next_back()
     }
 
     pub fn get_best_ask(&self) -> Option<&PriceLevel> {
-        // TODO: Implement getting the lowest ask price level
-        None
+        self.asks.values().next()
     }
 }
 
-// Helper functions for red-black tree operations
-impl CLOB {
-    fn rotate_left(&mut self, node: &mut RBNode) {
-        // TODO: Implement left rotation
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(book: &mut CLOB, owner: u128, price: u64, size: u64, is_buy: bool) -> OrderResult {
+        book.add_order(owner, Some(price), size, is_buy, OrderOptions::default()).unwrap()
     }
 
-    fn rotate_right(&mut self, node: &mut RBNode) {
-        // TODO: Implement right rotation
+    // ids of a levels orders in fill order
+    fn queue(book: &CLOB, is_buy: bool, price: u64) -> Vec<u64> {
+        let level = book.get_levels(is_buy, usize::MAX).into_iter().find(|level| level.price == price).unwrap();
+        book.level_orders(level).map(|order| order.id).collect()
     }
 
-    fn fix_violations(&mut self, node: &mut RBNode) {
-        // TODO: Implement red-black tree violation fixes
+    #[test]
+    fn resting_orders_are_indexed_by_slot() {
+        let mut book = CLOB::new();
+        let ids: Vec<u64> = (0..3).map(|i| limit(&mut book, 1, 100 + i, 10, true).order_id).collect();
+        assert_eq!(book.order_index.len(), 3);
+        for id in ids.iter() {
+            let slot = book.order_index[id];
+            assert_eq!(book.arena[slot].as_ref().unwrap().order.id, *id);
+            assert_eq!(book.get_order(*id).unwrap().size, 10);
+        }
+    }
+
+    #[test]
+    fn cancel_frees_the_slot_and_the_next_order_reuses_it() {
+        let mut book = CLOB::new();
+        let first = limit(&mut book, 1, 100, 10, true).order_id;
+        let second = limit(&mut book, 1, 100, 20, true).order_id;
+        let third = limit(&mut book, 1, 100, 30, true).order_id;
+        let slot = book.order_index[&second];
+        assert!(book.cancel_order(second));
+        assert!(!book.cancel_order(second));
+        assert!(book.get_order(second).is_none());
+        assert_eq!(book.free_slots, vec![slot]);
+        assert_eq!(queue(&book, true, 100), vec![first, third]);
+
+        let fourth = limit(&mut book, 1, 100, 40, true).order_id;
+        assert_eq!(book.order_index[&fourth], slot);
+        assert!(book.free_slots.is_empty());
+        assert_eq!(book.arena.len(), 3);
+        assert_eq!(queue(&book, true, 100), vec![first, third, fourth]);
+        assert_eq!(book.get_levels(true, 1)[0].displayed_size(), 80);
+    }
+
+    #[test]
+    fn cancelling_the_last_order_drops_the_level() {
+        let mut book = CLOB::new();
+        let id = limit(&mut book, 1, 100, 10, false).order_id;
+        book.cancel_order(id);
+        assert!(book.is_empty());
+        assert!(book.order_index.is_empty());
+    }
+
+    #[test]
+    fn filled_makers_leave_the_arena_and_partial_ones_stay() {
+        let mut book = CLOB::new();
+        let filled = limit(&mut book, 1, 100, 10, false).order_id;
+        let partial = limit(&mut book, 1, 101, 10, false).order_id;
+        let result = limit(&mut book, 2, 101, 15, true);
+        assert_eq!(result.fills.len(), 2);
+        assert_eq!((result.fills[0].maker_order_id, result.fills[0].size, result.fills[0].maker_remaining), (filled, 10, 0));
+        assert_eq!((result.fills[1].maker_order_id, result.fills[1].size, result.fills[1].maker_remaining), (partial, 5, 5));
+        assert_eq!(result.resting_size, 0);
+        assert!(book.get_order(filled).is_none());
+        assert_eq!(book.get_order(partial).unwrap().size, 5);
+        assert_eq!(book.order_index.len(), 1);
+        assert_eq!(book.free_slots.len(), 1);
+        assert_eq!(book.get_best_ask().unwrap().price, 101);
+        assert_eq!(book.get_best_ask().unwrap().displayed_size(), 5);
+    }
+
+    #[test]
+    fn expire_orders_only_cancels_orders_past_their_expiry() {
+        let mut book = CLOB::new();
+        let options = OrderOptions { time_in_force: TimeInForce::Gtt, expires_at: Some(now_millis() + 60_000), ..Default::default() };
+        let expiring = book.add_order(1, Some(100), 10, false, options).unwrap().order_id;
+        let live = limit(&mut book, 1, 100, 10, false).order_id;
+        assert_eq!(book.expire_orders(now_millis() + 120_000), vec![expiring]);
+        assert!(book.get_order(expiring).is_none());
+        assert_eq!(queue(&book, false, 100), vec![live]);
     }
-}
 
+    #[test]
+    fn amend_down_at_the_same_price_keeps_queue_priority() {
+        let mut book = CLOB::new();
+        let first = limit(&mut book, 1, 100, 10, true).order_id;
+        let second = limit(&mut book, 2, 100, 10, true).order_id;
+        let result = book.amend_order(first, None, Some(4), OrderOptions::default()).unwrap();
+        assert_eq!(result.resting_size, 4);
+        assert!(result.fills.is_empty());
+        assert_eq!(queue(&book, true, 100), vec![first, second]);
+        assert_eq!(book.get_levels(true, 1)[0].displayed_size(), 14);
+
+        // whoever is at the front fills first
+        let fill = limit(&mut book, 3, 100, 4, false);
+        assert_eq!(fill.fills[0].maker_order_id, first);
+    }
+
+    #[test]
+    fn amend_up_goes_to_the_back_of_the_level() {
+        let mut book = CLOB::new();
+        let first = limit(&mut book, 1, 100, 10, true).order_id;
+        let second = limit(&mut book, 2, 100, 10, true).order_id;
+        book.amend_order(first, None, Some(11), OrderOptions::default()).unwrap();
+        assert_eq!(queue(&book, true, 100), vec![second, first]);
+        assert_eq!(book.get_order(first).unwrap().size, 11);
+        assert_eq!(book.get_levels(true, 1)[0].displayed_size(), 21);
+    }
+
+    #[test]
+    fn amend_to_a_new_price_keeps_the_id_and_goes_to_the_back() {
+        let mut book = CLOB::new();
+        let moved = limit(&mut book, 1, 100, 10, true).order_id;
+        let resting = limit(&mut book, 2, 101, 10, true).order_id;
+        let result = book.amend_order(moved, Some(101), None, OrderOptions::default()).unwrap();
+        assert_eq!(result.order_id, moved);
+        assert_eq!(queue(&book, true, 101), vec![resting, moved]);
+        assert!(book.get_levels(true, usize::MAX).iter().all(|level| level.price != 100));
+    }
+
+    #[test]
+    fn amend_that_crosses_trades_like_a_new_order() {
+        let mut book = CLOB::new();
+        let ask = limit(&mut book, 1, 105, 6, false).order_id;
+        let bid = limit(&mut book, 2, 100, 10, true).order_id;
+        let result = book.amend_order(bid, Some(105), None, OrderOptions::default()).unwrap();
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!((result.fills[0].maker_order_id, result.fills[0].taker_order_id, result.fills[0].size), (ask, bid, 6));
+        assert_eq!(result.resting_size, 4);
+        assert_eq!(book.get_best_bid().unwrap().price, 105);
+        assert!(book.get_best_ask().is_none());
+    }
+
+    #[test]
+    fn rejected_amend_leaves_the_order_where_it_was() {
+        let mut book = CLOB::new();
+        limit(&mut book, 1, 105, 10, false);
+        let options = OrderOptions { post_only: true, ..Default::default() };
+        let first = book.add_order(2, Some(100), 10, true, options).unwrap().order_id;
+        let second = limit(&mut book, 3, 100, 10, true).order_id;
+        assert_eq!(book.amend_order(first, Some(105), None, options).err(), Some(OrderError::WouldCross));
+        assert_eq!(book.amend_order(first, None, Some(0), options).err(), Some(OrderError::InvalidSize));
+        assert_eq!(book.amend_order(99, None, Some(1), options).err(), Some(OrderError::NotFound));
+        assert_eq!(queue(&book, true, 100), vec![first, second]);
+        assert_eq!(book.get_order(first).unwrap().size, 10);
+    }
+
+    fn resting(book: &mut CLOB, owner: u128, price: u64, size: u64, visibility: Visibility) -> u64 {
+        book.add_order(owner, Some(price), size, false, OrderOptions { visibility, ..Default::default() }).unwrap().order_id
+    }
+
+    #[test]
+    fn iceberg_shows_one_slice_and_refreshes_at_the_back() {
+        let mut book = CLOB::new();
+        let iceberg = resting(&mut book, 1, 100, 25, Visibility::Iceberg { display: 10 });
+        let plain = resting(&mut book, 2, 100, 10, Visibility::Displayed);
+        assert_eq!(book.get_levels(false, 1)[0].displayed_size(), 20);
+
+        // the slice goes first, then the iceberg queues behind the plain order with a fresh one
+        let result = limit(&mut book, 3, 100, 10, true);
+        assert_eq!((result.fills[0].maker_order_id, result.fills[0].size, result.fills[0].maker_remaining), (iceberg, 10, 15));
+        assert_eq!(queue(&book, false, 100), vec![plain, iceberg]);
+        assert_eq!(book.get_order(iceberg).unwrap().shown, 10);
+        assert_eq!(book.get_levels(false, 1)[0].displayed_size(), 20);
+
+        let result = limit(&mut book, 3, 100, 25, true);
+        let fills: Vec<(u64, u64)> = result.fills.iter().map(|fill| (fill.maker_order_id, fill.size)).collect();
+        assert_eq!(fills, vec![(plain, 10), (iceberg, 10), (iceberg, 5)]);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn hidden_orders_fill_after_every_displayed_order_at_their_price() {
+        let mut book = CLOB::new();
+        let hidden = resting(&mut book, 1, 100, 10, Visibility::Hidden);
+        let displayed = resting(&mut book, 2, 100, 10, Visibility::Displayed);
+        let iceberg = resting(&mut book, 3, 100, 10, Visibility::Iceberg { display: 5 });
+        assert_eq!(queue(&book, false, 100), vec![displayed, iceberg, hidden]);
+        assert_eq!(book.get_levels(false, 1)[0].displayed_size(), 15);
+        assert_eq!(book.get_levels(false, 1)[0].displayed_orders(), 2);
+
+        let result = limit(&mut book, 4, 100, 25, true);
+        let fills: Vec<(u64, u64)> = result.fills.iter().map(|fill| (fill.maker_order_id, fill.size)).collect();
+        assert_eq!(fills, vec![(displayed, 10), (iceberg, 5), (iceberg, 5), (hidden, 5)]);
+        assert_eq!(book.get_order(hidden).unwrap().size, 5);
+    }
+
+    #[test]
+    fn hidden_only_levels_stay_out_of_the_public_book() {
+        let mut book = CLOB::new();
+        resting(&mut book, 1, 100, 10, Visibility::Hidden);
+        resting(&mut book, 1, 101, 10, Visibility::Displayed);
+        let displayed: Vec<u64> = book.get_displayed_levels(false, 10).iter().map(|level| level.price).collect();
+        assert_eq!(displayed, vec![101]);
+        assert_eq!(book.get_levels(false, 10).len(), 2);
+        assert_eq!(book.impact_price(false, 10), Some(101));
+        assert_eq!(book.impact_price(false, 11), None);
+    }
+
+    #[test]
+    fn post_only_is_only_rejected_by_displayed_liquidity() {
+        let mut book = CLOB::new();
+        let hidden = resting(&mut book, 1, 100, 10, Visibility::Hidden);
+        let options = OrderOptions { post_only: true, ..Default::default() };
+        let result = book.add_order(2, Some(100), 15, true, options).unwrap();
+        assert_eq!(result.fills[0].maker_order_id, hidden);
+        assert_eq!(result.resting_size, 5);
+
+        resting(&mut book, 1, 101, 10, Visibility::Displayed);
+        assert_eq!(book.add_order(2, Some(101), 5, true, options).err(), Some(OrderError::WouldCross));
+    }
+
+    #[test]
+    fn icebergs_and_hidden_orders_need_a_price_and_a_slice() {
+        let mut book = CLOB::new();
+        let iceberg = OrderOptions { visibility: Visibility::Iceberg { display: 0 }, ..Default::default() };
+        assert_eq!(book.add_order(1, Some(100), 10, true, iceberg).err(), Some(OrderError::InvalidDisplay));
+        let hidden = OrderOptions { visibility: Visibility::Hidden, ..Default::default() };
+        assert_eq!(book.add_order(1, None, 10, true, hidden).err(), Some(OrderError::MarketOrderNotAllowed));
+    }
+
+    #[test]
+    fn shrinking_an_iceberg_takes_the_reserve_first() {
+        let mut book = CLOB::new();
+        let iceberg = resting(&mut book, 1, 100, 25, Visibility::Iceberg { display: 10 });
+        book.amend_order(iceberg, None, Some(12), OrderOptions::default()).unwrap();
+        assert_eq!(book.get_order(iceberg).unwrap().shown, 10);
+        book.amend_order(iceberg, None, Some(6), OrderOptions::default()).unwrap();
+        assert_eq!(book.get_order(iceberg).unwrap().shown, 6);
+        assert_eq!(book.get_levels(false, 1)[0].displayed_size(), 6);
+    }
+
+    fn self_trading(book: &mut CLOB, size: u64, mode: SelfTradePrevention) -> OrderResult {
+        book.add_order(1, Some(100), size, true, OrderOptions { self_trade: mode, ..Default::default() }).unwrap()
+    }
+
+    // (order id, cancelled size, remaining) of each self trade event
+    fn cancels(result: &OrderResult) -> Vec<(u64, u64, u64)> {
+        result.self_trades.iter().map(|event| (event.order_id, event.cancelled_size, event.remaining)).collect()
+    }
+
+    #[test]
+    fn cancel_newest_keeps_the_resting_order() {
+        let mut book = CLOB::new();
+        let own = limit(&mut book, 1, 100, 10, false).order_id;
+        let other = limit(&mut book, 2, 100, 10, false).order_id;
+        let result = self_trading(&mut book, 15, SelfTradePrevention::CancelNewest);
+        assert!(result.fills.is_empty());
+        assert_eq!(result.resting_size, 0);
+        assert_eq!(cancels(&result), vec![(result.order_id, 15, 0)]);
+        assert_eq!(queue(&book, false, 100), vec![own, other]);
+    }
+
+    #[test]
+    fn cancel_oldest_drops_the_resting_order_and_keeps_matching() {
+        let mut book = CLOB::new();
+        let own = limit(&mut book, 1, 100, 10, false).order_id;
+        let other = limit(&mut book, 2, 100, 10, false).order_id;
+        let result = self_trading(&mut book, 15, SelfTradePrevention::CancelOldest);
+        assert_eq!(cancels(&result), vec![(own, 10, 0)]);
+        assert_eq!((result.fills[0].maker_order_id, result.fills[0].size), (other, 10));
+        assert_eq!(result.resting_size, 5);
+        assert!(book.get_order(own).is_none());
+        assert!(book.get_best_ask().is_none());
+    }
+
+    #[test]
+    fn cancel_both_drops_both_orders() {
+        let mut book = CLOB::new();
+        let own = limit(&mut book, 1, 100, 10, false).order_id;
+        let other = limit(&mut book, 2, 100, 10, false).order_id;
+        let result = self_trading(&mut book, 15, SelfTradePrevention::CancelBoth);
+        assert!(result.fills.is_empty());
+        assert_eq!(result.resting_size, 0);
+        assert_eq!(cancels(&result), vec![(own, 10, 0), (result.order_id, 15, 0)]);
+        assert_eq!(queue(&book, false, 100), vec![other]);
+    }
+
+    #[test]
+    fn decrement_and_cancel_shrinks_both_without_trading() {
+        let mut book = CLOB::new();
+        let own = limit(&mut book, 1, 100, 10, false).order_id;
+        let other = limit(&mut book, 2, 100, 10, false).order_id;
+        // the resting order is the smaller one: it goes, the taker carries on with what is left
+        let result = self_trading(&mut book, 15, SelfTradePrevention::DecrementAndCancel);
+        assert_eq!(cancels(&result), vec![(own, 10, 0)]);
+        assert_eq!((result.fills[0].maker_order_id, result.fills[0].size), (other, 5));
+        assert_eq!(book.get_order(other).unwrap().size, 5);
+
+        // the taker is the smaller one: the resting order shrinks in place and the taker is used up
+        let own = limit(&mut book, 1, 99, 10, false).order_id;
+        let result = book.add_order(1, Some(99), 4, true, OrderOptions { self_trade: SelfTradePrevention::DecrementAndCancel, ..Default::default() }).unwrap();
+        assert!(result.fills.is_empty());
+        assert_eq!(cancels(&result), vec![(own, 4, 6), (result.order_id, 4, 0)]);
+        assert_eq!(book.get_order(own).unwrap().size, 6);
+        assert_eq!(book.get_best_ask().unwrap().displayed_size(), 6);
+    }
+
+    #[test]
+    fn fill_or_kill_counts_self_trade_prevention() {
+        let mut book = CLOB::new();
+        limit(&mut book, 1, 100, 10, false);
+        limit(&mut book, 2, 100, 10, false);
+        let fok = |self_trade| OrderOptions { time_in_force: TimeInForce::Fok, self_trade, ..Default::default() };
+        assert_eq!(book.add_order(1, Some(100), 10, true, fok(SelfTradePrevention::CancelNewest)).err(), Some(OrderError::NotFillable));
+        assert_eq!(book.add_order(1, Some(100), 15, true, fok(SelfTradePrevention::CancelOldest)).err(), Some(OrderError::NotFillable));
+        assert!(book.add_order(1, Some(100), 10, true, fok(SelfTradePrevention::CancelOldest)).is_ok());
+    }
+
+    #[test]
+    fn impact_price_is_the_average_fill_against_displayed_size() {
+        let mut book = CLOB::new();
+        limit(&mut book, 1, 100, 50, true);
+        limit(&mut book, 1, 98, 100, true);
+        resting(&mut book, 2, 103, 10, Visibility::Displayed);
+        resting(&mut book, 2, 101, 100, Visibility::Hidden);
+        resting(&mut book, 2, 104, 30, Visibility::Iceberg { display: 5 });
+        assert_eq!(book.impact_price(true, 50), Some(100));
+        assert_eq!(book.impact_price(true, 100), Some(99)); // bids round down
+        assert_eq!(book.impact_price(true, 151), None);
+        // hidden size and iceberg reserves dont count, asks round up
+        assert_eq!(book.impact_price(false, 10), Some(103));
+        assert_eq!(book.impact_price(false, 15), Some(104));
+        assert_eq!(book.impact_price(false, 16), None);
+        assert_eq!(book.impact_price(false, 0), None);
+    }
+}
@@ -0,0 +1,140 @@
+use serde::Serialize;
+use tfhe::FheUint64;
+use tfhe::prelude::*;
+use crate::fhe::handle::CiphertextHandle;
+
+// order in the dark book. side, market and owner are public, price and size only exist as ciphertexts
+#[derive(Debug, Clone, Serialize)]
+pub struct EncryptedOrder {
+    pub id: u64,
+    pub owner: u128,
+    pub market: u32,
+    pub is_buy: bool,
+    pub price: CiphertextHandle,
+    pub size: CiphertextHandle,
+    pub leverage: u8,
+    pub position: Option<u128>, // the position earlier fills of this order opened, later fills extend it
+    pub timestamp: u64,
+}
+
+// what a match reveals: who traded and how much, plus the price if the book is configured to reveal it
+#[derive(Debug, Clone, Serialize)]
+pub struct DarkFill {
+    pub maker_order_id: u64,
+    pub taker_order_id: u64,
+    pub maker: u128,
+    pub taker: u128,
+    pub size: u64,
+    pub price: Option<u64>,
+}
+
+pub struct MatchResult {
+    pub fills: Vec<FheUint64>,
+    pub maker_remaining: Vec<FheUint64>,
+    pub taker_remaining: FheUint64,
+}
+
+// resting orders per side in arrival order. prices cant be sorted without decrypting them, so the book keeps time
+// priority and leaves the price check to the matching circuit
+pub struct DarkBook {
+    bids: Vec<EncryptedOrder>,
+    asks: Vec<EncryptedOrder>,
+    pending: Vec<EncryptedOrder>, // makers the running match used up, held until its taker has settled
+    next_order_id: u64,
+    pub reveal_clearing_price: bool,
+}
+
+impl DarkBook {
+    pub fn new(reveal_clearing_price: bool) -> Self {
+        Self {
+            bids: Vec::new(),
+            asks: Vec::new(),
+            pending: Vec::new(),
+            next_order_id: 1,
+            reveal_clearing_price,
+        }
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        id
+    }
+
+    pub fn add_order(&mut self, order: EncryptedOrder) {
+        if order.is_buy {
+            self.bids.push(order);
+        } else {
+            self.asks.push(order);
+        }
+    }
+
+    // the orders an incoming order on the given side could trade against, oldest first. the takers own orders are
+    // left out so it never trades with itself
+    pub fn get_makers(&self, market: u32, is_buy: bool, taker: u128) -> Vec<EncryptedOrder> {
+        let side = if is_buy { &self.asks } else { &self.bids };
+        side.iter().filter(|order| order.market == market && order.owner != taker).cloned().collect()
+    }
+
+    pub fn get_order(&self, id: u64) -> Option<&EncryptedOrder> {
+        self.bids.iter().chain(self.asks.iter()).find(|order| order.id == id)
+    }
+
+    pub fn set_position(&mut self, id: u64, position: Option<u128>) {
+        if let Some(order) = self.bids.iter_mut().chain(self.asks.iter_mut()).find(|order| order.id == id) {
+            order.position = position;
+        }
+    }
+
+    pub fn remove_order(&mut self, id: u64) -> Option<EncryptedOrder> {
+        for side in [&mut self.bids, &mut self.asks] {
+            if let Some(index) = side.iter().position(|order| order.id == id) {
+                return Some(side.remove(index));
+            }
+        }
+        None
+    }
+
+    // a maker the match used up leaves the book but is parked until the taker has settled, so it can go back if the
+    // taker cant pay for the fill
+    pub fn take_filled(&mut self, id: u64) {
+        if let Some(order) = self.remove_order(id) {
+            self.pending.push(order);
+        }
+    }
+
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    // puts the parked makers back where they were. ids are handed out in arrival order so that is their old place
+    pub fn restore_pending(&mut self) {
+        for order in std::mem::take(&mut self.pending) {
+            let side = if order.is_buy { &mut self.bids } else { &mut self.asks };
+            let index = side.partition_point(|resting| resting.id < order.id);
+            side.insert(index, order);
+        }
+    }
+
+    // for the ciphertext collector, resting and parked orders keep their price and size alive
+    pub fn ciphertext_keys(&self) -> Vec<CiphertextHandle> {
+        self.bids.iter().chain(self.asks.iter()).chain(self.pending.iter()).flat_map(|order| [order.price, order.size]).collect()
+    }
+}
+
+// one matching pass over ciphertexts. every maker that crosses fills min(taker left, maker size), the rest fill 0,
+// and nothing secret is branched on so the work is the same whatever the prices are. runs on an fhe worker
+pub fn match_encrypted(is_buy: bool, taker_price: &FheUint64, taker_size: &FheUint64, makers: &[(FheUint64, FheUint64)]) -> MatchResult {
+    let zero = FheUint64::encrypt_trivial(0u64);
+    let mut taker_remaining = taker_size.clone();
+    let mut fills = Vec::with_capacity(makers.len());
+    let mut maker_remaining = Vec::with_capacity(makers.len());
+    for (maker_price, maker_size) in makers {
+        let crosses = if is_buy { taker_price.ge(maker_price) } else { taker_price.le(maker_price) };
+        let fill = crosses.if_then_else(&taker_remaining.min(maker_size), &zero);
+        taker_remaining = &taker_remaining - &fill;
+        maker_remaining.push(maker_size - &fill);
+        fills.push(fill);
+    }
+    MatchResult { fills, maker_remaining, taker_remaining }
+}
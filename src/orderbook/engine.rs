@@ -0,0 +1,655 @@
+use std::collections::{HashMap, VecDeque};
+use axum::extract::State;
+use crate::AppState;
+use std::time::Duration;
+use tfhe::FheUint64;
+use crate::fhe::circuits::{balance_covers_circuit, balance_covers_order_circuit, opening_fee, open_interest_cap_circuit, open_position_circuit, extend_position_circuit, reduce_position_circuit, close_position_circuit, sealed_auction_circuit, sealed_ciphertexts};
+use crate::liqudation::handlers::{_encrypt_helper, _encrypt_u8_helper};
+use crate::market::decimal::{mul_div, Rounding};
+use crate::market::registry::MarketRegistry;
+use crate::orderbook::clob::{CLOB, Fill, Order, OrderResult, OrderOptions, SelfTradeCancel, TimeInForce, Visibility, now_millis};
+use crate::orderbook::auction::{AuctionOrder, AuctionResult, BatchAuction, MatchingMode, MIN_AUCTION_INTERVAL_MS};
+use crate::orderbook::sealed::{SealedAuctionResult, SealedOrder};
+use crate::orderbook::dark::EncryptedOrder;
+use crate::orderbook::candles::{Candle, CandleStore, Resolution, CANDLES_PATH, save_snapshot};
+
+pub const RECENT_TRADES: usize = 1_000; // per market, older trades are dropped
+const ORDER_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const CANDLE_PERSIST_INTERVAL: Duration = Duration::from_secs(30); // a crash loses at most this much of the candles
+const AUCTION_TICK: Duration = Duration::from_millis(50); // how often batch markets are checked for a due auction
+pub const RECENT_AUCTIONS: usize = 100;
+pub const RECENT_SELF_TRADES: usize = 1_000; // per market
+
+// what the book needs to turn a resting orders fills into a position, the CLOB itself only knows price and size
+pub struct OrderMeta {
+    pub leverage: u8,
+    pub position: Option<u128>, // the position earlier fills of this order opened, later fills extend it
+    pub reduce_only: bool, // fills shrink position instead, it is set from the start
+    pub options: OrderOptions,
+}
+
+// an order as it comes in, prices and sizes already in raw units
+#[derive(Clone, Copy)]
+pub struct NewOrder {
+    pub user_id: u128,
+    pub market: u32,
+    pub price: Option<u64>,
+    pub size: u64,
+    pub is_buy: bool,
+    pub leverage: u8,
+    pub options: OrderOptions,
+    pub reduce_only: Option<u128>, // the position this order may only shrink
+}
+
+// one CLOB per market. order ids come from each CLOB so the meta is keyed by (market, order id)
+pub struct OrderBooks {
+    books: HashMap<u32, CLOB>,
+    orders: HashMap<(u32, u64), OrderMeta>,
+    trades: HashMap<u32, VecDeque<Fill>>, // newest at the back
+    auctions: HashMap<u32, BatchAuction>, // only for markets in batch mode
+    auction_results: HashMap<u32, VecDeque<AuctionResult>>, // newest at the back
+    self_trades: HashMap<u32, VecDeque<SelfTradeCancel>>, // newest at the back
+    candles: CandleStore, // from continuous trades and auction clears, sealed auctions have no public volume
+}
+
+impl OrderBooks {
+    pub fn with_markets(markets: &MarketRegistry, candles: CandleStore) -> Self {
+        Self {
+            books: markets.get_all().iter().map(|market| (market.id, CLOB::new())).collect(),
+            orders: HashMap::new(),
+            trades: HashMap::new(),
+            auctions: HashMap::new(),
+            auction_results: HashMap::new(),
+            self_trades: HashMap::new(),
+            candles,
+        }
+    }
+
+    pub fn get(&self, market: u32) -> Option<&CLOB> {
+        self.books.get(&market)
+    }
+
+    pub fn get_mut(&mut self, market: u32) -> Option<&mut CLOB> {
+        self.books.get_mut(&market)
+    }
+
+    pub fn get_mode(&self, market: u32) -> MatchingMode {
+        match self.auctions.get(&market) {
+            Some(auction) => MatchingMode::Batch { interval_ms: auction.interval_ms },
+            None => MatchingMode::Continuous,
+        }
+    }
+
+    // only while the market has nothing resting, orders dont move between the book and a batch
+    pub fn set_mode(&mut self, market: u32, mode: MatchingMode) -> Result<(), String> {
+        let book = self.books.get(&market).ok_or("Unknown market")?;
+        if !book.is_empty() || self.auctions.get(&market).is_some_and(|auction| !auction.is_empty()) {
+            return Err("Market has open orders, cancel them before switching modes".to_string());
+        }
+        match mode {
+            MatchingMode::Continuous => {
+                self.auctions.remove(&market);
+            }
+            MatchingMode::Batch { interval_ms } => {
+                if interval_ms < MIN_AUCTION_INTERVAL_MS {
+                    return Err(format!("Auction interval has to be at least {}ms", MIN_AUCTION_INTERVAL_MS));
+                }
+                self.auctions.insert(market, BatchAuction::new(interval_ms, now_millis()));
+            }
+        }
+        Ok(())
+    }
+
+    // resting on the book or waiting for the next auction
+    pub fn get_order(&self, market: u32, order_id: u64) -> Option<&Order> {
+        self.books.get(&market)?.get_order(order_id)
+            .or_else(|| self.auctions.get(&market)?.get_order(order_id))
+    }
+
+    pub fn get_orders_for(&self, market: u32, owner: u128) -> Vec<&Order> {
+        let mut orders = self.books.get(&market).map(|book| book.get_orders_for(owner)).unwrap_or_default();
+        if let Some(auction) = self.auctions.get(&market) {
+            orders.extend(auction.get_orders_for(owner));
+        }
+        orders
+    }
+
+    pub fn get_meta(&self, market: u32, order_id: u64) -> Option<&OrderMeta> {
+        self.orders.get(&(market, order_id))
+    }
+
+    pub fn cancel_order(&mut self, market: u32, order_id: u64) -> bool {
+        self.orders.remove(&(market, order_id));
+        self.books.get_mut(&market).is_some_and(|book| book.cancel_order(order_id))
+            || self.auctions.get_mut(&market).is_some_and(|auction| auction.cancel_order(order_id))
+    }
+
+    fn record_trades(&mut self, market: u32, fills: &[Fill]) {
+        for fill in fills.iter() {
+            self.candles.record(market, fill.price, fill.size, fill.timestamp);
+        }
+        let trades = self.trades.entry(market).or_default();
+        trades.extend(fills.iter().cloned());
+        while trades.len() > RECENT_TRADES {
+            trades.pop_front();
+        }
+    }
+
+    // newest first
+    pub fn recent_auctions(&self, market: u32, limit: usize) -> Vec<AuctionResult> {
+        self.auction_results.get(&market)
+            .map(|results| results.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // oldest first
+    pub fn candles(&self, market: u32, resolution: Resolution, from: u64, to: u64, limit: usize) -> Vec<Candle> {
+        self.candles.range(market, resolution, from, to, limit)
+    }
+
+    // newest first
+    pub fn recent_trades(&self, market: u32, limit: usize) -> Vec<Fill> {
+        self.trades.get(&market)
+            .map(|trades| trades.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // orders self trade prevention cancelled or shrank. a cancelled maker leaves the book without a fill, so its
+    // meta goes here too
+    fn record_self_trades(&mut self, market: u32, taker_order_id: u64, events: &[SelfTradeCancel]) {
+        for event in events.iter() {
+            println!("Self trade prevention ({:?}) took {} off order {} on market {}, {} left", event.mode, event.cancelled_size, event.order_id, market, event.remaining);
+            if event.remaining == 0 && event.order_id != taker_order_id {
+                self.orders.remove(&(market, event.order_id));
+            }
+        }
+        let self_trades = self.self_trades.entry(market).or_default();
+        self_trades.extend(events.iter().cloned());
+        while self_trades.len() > RECENT_SELF_TRADES {
+            self_trades.pop_front();
+        }
+    }
+
+    // newest first
+    pub fn recent_self_trades(&self, market: u32, limit: usize) -> Vec<SelfTradeCancel> {
+        self.self_trades.get(&market)
+            .map(|events| events.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// volume weighted average of (price, size) fills, rounded against the taker: buyers round up, sellers down
+fn average_price(fills: impl Iterator<Item = (u64, u64)> + Clone, is_buy: bool) -> u64 {
+    let total: u128 = fills.clone().map(|(_, size)| size as u128).sum();
+    let weighted: u128 = fills.map(|(price, size)| price as u128 * size as u128).sum();
+    if is_buy { weighted.div_ceil(total.max(1)) as u64 } else { (weighted / total.max(1)) as u64 }
+}
+
+// margin backing a fill, notional / leverage rounded up
+fn fill_margin(notional: u64, leverage: u8) -> u64 {
+    mul_div(notional, 1, leverage.max(1) as u64, Rounding::Up)
+}
+
+async fn open_from_fill(state: &AppState, user_id: u128, market: u32, is_buy: bool, price: u64, notional: u64, leverage: u8) -> Result<u128, String> {
+    // for now lets just use a helper to simulate the encryption process
+    let leverage_key = _encrypt_u8_helper(State(state.clone()), leverage, user_id).await.map_err(|e| e.to_string())?;
+    let initial_margin_key = _encrypt_helper(State(state.clone()), fill_margin(notional, leverage), user_id).await.map_err(|e| e.to_string())?;
+    let leverage_ciphertext = state.ciphertext_cache.get_u8(leverage_key).unwrap();
+    let initial_margin_ciphertext = state.ciphertext_cache.get_u64(initial_margin_key).unwrap();
+    open_position_circuit(
+        state,
+        user_id,
+        market,
+        price,
+        is_buy,
+        notional,
+        leverage_ciphertext,
+        initial_margin_ciphertext,
+        initial_margin_key,
+        leverage_key,
+    ).await.map_err(|e| e.to_string())
+}
+
+// a fill for an order that already has a position extends it, unless that position has been closed since
+async fn open_or_extend(state: &AppState, user_id: u128, position: Option<u128>, market: u32, is_buy: bool, price: u64, notional: u64, leverage: u8) -> Result<u128, String> {
+    if let Some(position_id) = position {
+        let position_open = state.user_cache.get_user(user_id)
+            .ok_or("User not found")?
+            .lock().await
+            .get_position(position_id)
+            .is_some();
+        if position_open {
+            extend_position_circuit(state, user_id, position_id, price, notional, leverage).await.map_err(|e| e.to_string())?;
+            return Ok(position_id);
+        }
+    }
+    open_from_fill(state, user_id, market, is_buy, price, notional, leverage).await
+}
+
+// a reduce only fill shrinks its position, or closes it at the fill price once it covers all of it. the order was
+// sized to the position when placed, if the position has shrunk or closed since then the extra fill is dropped
+async fn reduce_from_fill(state: &AppState, user_id: u128, position_id: u128, price: u64, notional: u64) -> Result<Option<u128>, String> {
+    let position = state.user_cache.get_user(user_id)
+        .ok_or("User not found")?
+        .lock().await
+        .get_position(position_id);
+    let Some(position) = position else {
+        println!("Reduce only fill of {} for position {} which is already closed", notional, position_id);
+        return Ok(None);
+    };
+    if notional < position.notional {
+        reduce_position_circuit(state, user_id, position_id, price, notional).await.map_err(|e| e.to_string())?;
+        return Ok(Some(position_id));
+    }
+    if notional > position.notional {
+        println!("Reduce only fill of {} is larger than position {}, closing it", notional, position_id);
+    }
+    close_position_circuit(state, user_id, position_id, price).await.map_err(|e| e.to_string())?;
+    Ok(None)
+}
+
+async fn settle_fill(state: &AppState, user_id: u128, market: u32, is_buy: bool, meta: &OrderMeta, price: u64, notional: u64) -> Result<Option<u128>, String> {
+    if meta.reduce_only {
+        let position_id = meta.position.ok_or("Reduce only order without a position")?;
+        return reduce_from_fill(state, user_id, position_id, price, notional).await;
+    }
+    Ok(Some(open_or_extend(state, user_id, meta.position, market, is_buy, price, notional, meta.leverage).await?))
+}
+
+// dark fills settle like CLOB fills: the taker gets one position for all of them at the average price, each maker
+// order opens a position on its first fill and extends it on later ones. fills are (maker order, price, size).
+// the taker goes first and if it fails nothing else settles, the caller puts the makers back. a maker that fails
+// after that is logged and the rest still go through. returns the takers position and each makers, in fill order
+pub async fn settle_dark_fills(state: &AppState, market: u32, taker: u128, is_buy: bool, leverage: u8, fills: &[(EncryptedOrder, u64, u64)]) -> Result<(Option<u128>, Vec<Option<u128>>), String> {
+    if fills.is_empty() {
+        return Ok((None, Vec::new()));
+    }
+    let filled: u64 = fills.iter().map(|(_, _, size)| size).sum();
+    let entry_price = average_price(fills.iter().map(|(_, price, size)| (*price, *size)), is_buy);
+    let taker_position = open_or_extend(state, taker, None, market, is_buy, entry_price, filled, leverage).await
+        .map_err(|e| format!("Failed to settle dark fills for user {} on market {}: {}", taker, market, e))?;
+    let mut maker_positions = Vec::with_capacity(fills.len());
+    for (maker, price, size) in fills {
+        match open_or_extend(state, maker.owner, maker.position, market, !is_buy, *price, *size, maker.leverage).await {
+            Ok(position) => maker_positions.push(Some(position)),
+            Err(e) => {
+                println!("Failed to settle dark fill for order {}: {}", maker.id, e);
+                maker_positions.push(maker.position);
+            }
+        }
+    }
+    Ok((Some(taker_position), maker_positions))
+}
+
+// a reduce only order has to be on the other side of a position the user holds in the same market and is cut down
+// to the positions size
+async fn check_reduce_only(state: &AppState, order: &mut NewOrder) -> Result<(), String> {
+    let Some(position_id) = order.reduce_only else { return Ok(()) };
+    let position = state.user_cache.get_user(order.user_id)
+        .ok_or("User not found")?
+        .lock().await
+        .get_position(position_id)
+        .ok_or("Position not found")?;
+    if position.market != order.market || position.direction == order.is_buy {
+        return Err("Reduce only order has to be on the other side of a position in the same market".to_string());
+    }
+    order.size = order.size.min(position.notional);
+    Ok(())
+}
+
+// places an order on the markets book and settles whatever it traded. the taker gets one position for all of its
+// fills at their average price, each maker order opens a position on its first fill and extends it on later ones.
+// the books lock is held until settlement is done so fills land in the order they happened
+pub async fn place_order(state: &AppState, mut order: NewOrder) -> Result<(OrderResult, Option<u128>), String> {
+    check_reduce_only(state, &mut order).await?;
+    check_balance(state, &order).await?;
+    // open interest only moves when fills settle, and they settle under the books lock, so checking under it too
+    // means nothing can move a side between the check and the fills
+    let mut books = state.order_books.lock().await;
+    if books.auctions.contains_key(&order.market) {
+        check_open_interest(state, order.market, order.is_buy, order.size, order.reduce_only.is_some(), false).await?;
+        return submit_to_auction(&mut books, order, order.reduce_only);
+    }
+    check_open_interest(state, order.market, order.is_buy, order.size, order.reduce_only.is_some(), !order.options.post_only).await?;
+    execute_order(state, &mut books, order, order.reduce_only).await
+}
+
+// the balance has to cover margin and opening fee for the whole order before it can trade, so a fill never takes
+// the balance below 0. only whether it does is decrypted. reduce only orders dont open anything
+async fn check_balance(state: &AppState, order: &NewOrder) -> Result<(), String> {
+    if order.reduce_only.is_some() {
+        return Ok(());
+    }
+    let cost = fill_margin(order.size, order.leverage).saturating_add(opening_fee(order.size));
+    if !balance_covers_circuit(state, order.user_id, cost).await.map_err(|e| e.to_string())? {
+        return Err("Insufficient balance for margin and opening fee".to_string());
+    }
+    Ok(())
+}
+
+// the whole order has to fit under the markets cap for its side, reduce only orders dont add to it. an order that
+// can take liquidity also opens or extends the positions of the makers it trades with, so the other side has to
+// have room for all of it too. which makers are only reducing isnt known until it matches, so every fill counts
+// as opening. that way a resting order never has its own size checked again when it fills later, the order that
+// fills it already checked its side
+async fn check_open_interest(state: &AppState, market: u32, is_buy: bool, size: u64, reduce_only: bool, takes: bool) -> Result<(), String> {
+    let cap = state.markets.get(market).ok_or("Unknown market")?.max_open_interest;
+    if !reduce_only && !open_interest_cap_circuit(state, market, is_buy, size, cap).await.map_err(|e| e.to_string())? {
+        return Err("Order would take open interest past the market cap".to_string());
+    }
+    if takes && !open_interest_cap_circuit(state, market, !is_buy, size, cap).await.map_err(|e| e.to_string())? {
+        return Err("Order would take the other side's open interest past the market cap".to_string());
+    }
+    Ok(())
+}
+
+// batch markets just collect the order, it trades when the auction clears. post only, fill or kill, good til time,
+// iceberg and hidden orders dont mean anything without a continuous book so theyre turned away
+fn submit_to_auction(books: &mut OrderBooks, order: NewOrder, position: Option<u128>) -> Result<(OrderResult, Option<u128>), String> {
+    let options = order.options;
+    if options.post_only || matches!(options.time_in_force, TimeInForce::Fok | TimeInForce::Gtt) || options.visibility != Visibility::Displayed {
+        return Err("Batch auction markets only take displayed gtc and ioc orders".to_string());
+    }
+    let market = order.market;
+    let id = books.get_mut(market).ok_or("Unknown market")?.reserve_order_id();
+    let pending = AuctionOrder {
+        order: Order {
+            id,
+            owner: order.user_id,
+            price: order.price.unwrap_or(0),
+            size: order.size,
+            is_buy: order.is_buy,
+            timestamp: now_millis(),
+            expires_at: None,
+            visibility: Visibility::Displayed,
+            shown: order.size,
+        },
+        market_order: order.price.is_none(),
+        carry_over: order.price.is_some() && options.time_in_force == TimeInForce::Gtc,
+    };
+    books.auctions.get_mut(&market).unwrap().add_order(pending);
+    books.orders.insert((market, id), OrderMeta { leverage: order.leverage, position, reduce_only: order.reduce_only.is_some(), options });
+    Ok((OrderResult { order_id: id, fills: Vec::new(), resting_size: order.size, expired: Vec::new(), self_trades: Vec::new() }, position))
+}
+
+// clears one batch and settles every allocation at the clearing price. a fill that fails to settle is logged and
+// the rest carry on, one bad account shouldnt hold up the whole batch
+async fn clear_auction(state: &AppState, books: &mut OrderBooks, market: u32, now: u64) {
+    let result = books.auctions.get_mut(&market).unwrap().clear(now);
+    if let Some(price) = result.clearing_price {
+        for fill in result.fills.iter() {
+            let key = (market, fill.order_id);
+            let Some(meta) = books.orders.get(&key) else { continue };
+            let position = match settle_fill(state, fill.owner, market, fill.is_buy, meta, price, fill.size).await {
+                Ok(position) => position,
+                Err(e) => {
+                    println!("Failed to settle auction fill for order {}: {}", fill.order_id, e);
+                    continue;
+                }
+            };
+            if fill.remaining == 0 {
+                books.orders.remove(&key);
+            } else if position.is_none() && meta.reduce_only {
+                books.cancel_order(market, fill.order_id);
+            } else if let Some(meta) = books.orders.get_mut(&key) {
+                meta.position = position;
+            }
+        }
+    }
+    for order_id in result.dropped.iter() {
+        books.orders.remove(&(market, *order_id));
+    }
+    if let Some(price) = result.clearing_price.filter(|_| result.volume > 0) {
+        books.candles.record(market, price, result.volume, now); // the whole clear is one print
+        println!("Auction {} on market {} cleared {} at {:?}", result.auction_id, market, result.volume, result.clearing_price);
+    }
+    let results = books.auction_results.entry(market).or_default();
+    results.push_back(result);
+    while results.len() > RECENT_AUCTIONS {
+        results.pop_front();
+    }
+}
+
+// runs every due batch auction. the books lock is held for the whole clear like it is for a continuous order
+pub async fn auction_loop(state: AppState) {
+    let mut interval = tokio::time::interval(AUCTION_TICK);
+    loop {
+        interval.tick().await;
+        let now = now_millis();
+        let mut books = state.order_books.lock().await;
+        let due: Vec<u32> = books.auctions.iter()
+            .filter(|(_, auction)| auction.next_clear_at <= now)
+            .map(|(market, _)| *market)
+            .collect();
+        for market in due {
+            clear_auction(&state, &mut books, market, now).await;
+        }
+    }
+}
+
+// sealed fills open positions on both sides like any other fill, so each sides filled total has to fit under the
+// markets cap. an auction that would take a side past it doesnt trade at all, scaling the fills down to fit would
+// mean decrypting more of the auction
+async fn check_sealed_open_interest(state: &AppState, market: u32, orders: &[SealedOrder], fills: &[u64]) -> Result<(), String> {
+    let cap = state.markets.get(market).ok_or("Unknown market")?.max_open_interest;
+    for is_long in [true, false] {
+        let filled: u64 = orders.iter().zip(fills)
+            .filter(|(order, _)| order.is_buy == is_long)
+            .map(|(_, size)| *size)
+            .sum();
+        if filled > 0 && !open_interest_cap_circuit(state, market, is_long, filled, cap).await.map_err(|e| e.to_string())? {
+            return Err(format!("fills would take the {} open interest past the market cap", if is_long { "long" } else { "short" }));
+        }
+    }
+    Ok(())
+}
+
+// bids are checked for margin and fee when they come in, but a balance can drop before the auction clears. a bid
+// that cant pay anymore sits the auction out, it doesnt count towards the price and gets no fill. returns the price
+// and one fill per order, in order
+async fn clear_sealed_batch(state: &AppState, orders: &[SealedOrder], ciphertexts: Vec<(bool, FheUint64, FheUint64)>) -> Result<(Option<u64>, Vec<u64>), String> {
+    let mut covered = Vec::with_capacity(orders.len());
+    let mut eligible = Vec::with_capacity(orders.len());
+    for (order, ciphertext) in orders.iter().zip(ciphertexts) {
+        let pays = balance_covers_order_circuit(state, order.owner, ciphertext.2.clone(), order.leverage).await.map_err(|e| e.to_string())?;
+        if pays {
+            eligible.push(ciphertext);
+        } else {
+            println!("Sealed order {} sits its auction out, the balance no longer covers it", order.id);
+        }
+        covered.push(pays);
+    }
+    let (clearing_price, eligible_fills) = sealed_auction_circuit(state, eligible).await.map_err(|e| e.to_string())?;
+    let mut eligible_fills = eligible_fills.into_iter();
+    let fills = covered.iter().map(|pays| if *pays { eligible_fills.next().unwrap_or(0) } else { 0 }).collect();
+    Ok((clearing_price, fills))
+}
+
+// sealed bid auctions. the orders are taken out under the lock and cleared without it, a clear runs the whole
+// curve over ciphertexts and new bids shouldnt wait on that. they go into the next auction instead. once taken out
+// nothing roots the orders handles for the collector, so every batchs ciphertexts are read before the lock goes
+// rather than when its turn to clear comes
+pub async fn sealed_auction_loop(state: AppState) {
+    let mut interval = tokio::time::interval(AUCTION_TICK);
+    loop {
+        interval.tick().await;
+        let now = now_millis();
+        let batches: Vec<_> = {
+            let mut auctions = state.sealed_auctions.lock().await;
+            if auctions.next_clear_at > now {
+                continue;
+            }
+            auctions.take_batches(now).into_iter()
+                .map(|(auction_id, market, orders)| {
+                    let ciphertexts = sealed_ciphertexts(&state, &orders);
+                    (auction_id, market, orders, ciphertexts)
+                })
+                .collect()
+        };
+        for (auction_id, market, orders, ciphertexts) in batches {
+            let outcome = match ciphertexts {
+                Ok(ciphertexts) => clear_sealed_batch(&state, &orders, ciphertexts).await,
+                Err(e) => Err(e),
+            };
+            let (clearing_price, fills) = match outcome {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    println!("Failed to clear sealed auction {} on market {}: {}", auction_id, market, e);
+                    (None, vec![0; orders.len()])
+                }
+            };
+            let mut filled_orders = 0;
+            let mut clearing_price = clearing_price;
+            if let Some(price) = clearing_price {
+                // the books lock keeps open interest still between the cap check and the fills, like for CLOB fills
+                let _books = state.order_books.lock().await;
+                match check_sealed_open_interest(&state, market, &orders, &fills).await {
+                    Ok(()) => {
+                        for (order, size) in orders.iter().zip(fills) {
+                            if size == 0 {
+                                continue;
+                            }
+                            filled_orders += 1;
+                            if let Err(e) = open_from_fill(&state, order.owner, market, order.is_buy, price, size, order.leverage).await {
+                                println!("Failed to settle sealed fill for order {}: {}", order.id, e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("Sealed auction {} on market {} doesnt trade: {}", auction_id, market, e);
+                        clearing_price = None;
+                    }
+                }
+            }
+            println!("Sealed auction {} on market {} cleared at {:?}, {} fills", auction_id, market, clearing_price, filled_orders);
+            let buy_orders = orders.iter().filter(|order| order.is_buy).count();
+            state.sealed_auctions.lock().await.record_result(market, SealedAuctionResult {
+                auction_id,
+                clearing_price,
+                buy_orders,
+                sell_orders: orders.len() - buy_orders,
+                filled_orders,
+                timestamp: now,
+            });
+        }
+    }
+}
+
+// changes a resting order in place under the books lock, it keeps its id, leverage, flags and position. a smaller
+// size at the same price keeps its queue position, anything else sends it to the back and it can trade on the way
+// (see CLOB::amend_order). if the book rejects the change the order stays as it was
+pub async fn amend_order(state: &AppState, user_id: u128, market: u32, order_id: u64, price: Option<u64>, notional: Option<u64>) -> Result<(OrderResult, Option<u128>), String> {
+    let mut books = state.order_books.lock().await;
+    if books.auctions.contains_key(&market) {
+        return Err("Orders in a batch auction cant be amended, cancel and place a new one".to_string());
+    }
+    let book = books.get(market).ok_or("Unknown market")?;
+    let existing = book.get_order(order_id).ok_or("Order not found")?;
+    if existing.owner != user_id {
+        return Err("Order not found".to_string());
+    }
+    let (is_buy, size) = (existing.is_buy, notional.unwrap_or(existing.size));
+    let meta = books.get_meta(market, order_id).ok_or("Order not found")?;
+    let (options, reduce_only) = (meta.options, meta.reduce_only);
+    // an amend can trade like a new order, so it is checked like one with the size it ends up with
+    check_open_interest(state, market, is_buy, size, reduce_only, !options.post_only).await?;
+    let result = books.get_mut(market).unwrap().amend_order(order_id, price, notional, options).map_err(|e| e.to_string())?;
+    println!("Order {} on market {} amended: {} fills, {} resting", order_id, market, result.fills.len(), result.resting_size);
+    let meta = books.orders.remove(&(market, order_id)).unwrap();
+    let position = settle_result(state, &mut books, market, user_id, is_buy, meta, &result).await;
+    Ok((result, position))
+}
+
+// writes the candles to CANDLES_PATH whenever something traded since the last write. the snapshot is taken under
+// the books lock and written without it
+pub async fn candle_persist_loop(state: AppState) {
+    let mut interval = tokio::time::interval(CANDLE_PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(snapshot) = state.order_books.lock().await.candles.snapshot() else { continue };
+        let result = tokio::task::spawn_blocking(move || save_snapshot(CANDLES_PATH, &snapshot)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => println!("{}", e),
+            Err(e) => println!("Candle persist task failed: {}", e),
+        }
+    }
+}
+
+// cancels good til time orders once they expire. matching skips expired makers too, this keeps them off the book
+// and out of depth in between
+pub async fn order_expiry_loop(state: AppState) {
+    let mut interval = tokio::time::interval(ORDER_EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = now_millis();
+        let mut books = state.order_books.lock().await;
+        let markets: Vec<u32> = books.books.keys().copied().collect();
+        for market in markets {
+            let expired = books.get_mut(market).unwrap().expire_orders(now);
+            for order_id in expired.iter() {
+                books.orders.remove(&(market, *order_id));
+            }
+            if !expired.is_empty() {
+                println!("Expired {} orders on market {}", expired.len(), market);
+            }
+        }
+    }
+}
+
+async fn execute_order(state: &AppState, books: &mut OrderBooks, order: NewOrder, position: Option<u128>) -> Result<(OrderResult, Option<u128>), String> {
+    let market = order.market;
+    let book = books.get_mut(market).ok_or("Unknown market")?;
+    let result = book.add_order(order.user_id, order.price, order.size, order.is_buy, order.options).map_err(|e| e.to_string())?;
+    println!("Order {} on market {}: {} fills, {} resting", result.order_id, market, result.fills.len(), result.resting_size);
+    let meta = OrderMeta { leverage: order.leverage, position, reduce_only: order.reduce_only.is_some(), options: order.options };
+    let taker_position = settle_result(state, books, market, order.user_id, order.is_buy, meta, &result).await;
+    Ok((result, taker_position))
+}
+
+// turns what an order did on the book into positions. the taker side of its fills is settled as one fill at the
+// average price, each maker is settled on its own. the orders meta goes back in if anything of it is still resting.
+// the book has already matched, so a fill that fails to settle is logged and every other fill still settles
+async fn settle_result(state: &AppState, books: &mut OrderBooks, market: u32, user_id: u128, is_buy: bool, mut meta: OrderMeta, result: &OrderResult) -> Option<u128> {
+    books.record_trades(market, &result.fills);
+    books.record_self_trades(market, result.order_id, &result.self_trades);
+    for order_id in result.expired.iter() {
+        books.orders.remove(&(market, *order_id));
+    }
+
+    if !result.fills.is_empty() {
+        let filled: u64 = result.fills.iter().map(|fill| fill.size).sum();
+        let entry_price = average_price(result.fills.iter().map(|fill| (fill.price, fill.size)), is_buy);
+        match settle_fill(state, user_id, market, is_buy, &meta, entry_price, filled).await {
+            Ok(position) => meta.position = position,
+            Err(e) => println!("Failed to settle fills for order {} on market {}: {}", result.order_id, market, e),
+        }
+    }
+    let taker_position = meta.position;
+    if result.resting_size > 0 && !(meta.reduce_only && meta.position.is_none()) {
+        books.orders.insert((market, result.order_id), meta);
+    } else if result.resting_size > 0 {
+        // the position it was reducing is gone, nothing left for the rest of the order to do
+        books.get_mut(market).unwrap().cancel_order(result.order_id);
+    }
+
+    for fill in result.fills.iter() {
+        let key = (market, fill.maker_order_id);
+        let Some(meta) = books.orders.get(&key) else { continue };
+        let position = match settle_fill(state, fill.maker, market, !is_buy, meta, fill.price, fill.size).await {
+            Ok(position) => position,
+            Err(e) => {
+                println!("Failed to settle fill for order {} on market {}: {}", fill.maker_order_id, market, e);
+                meta.position
+            }
+        };
+        if fill.maker_remaining == 0 {
+            books.orders.remove(&key);
+        } else if position.is_none() && meta.reduce_only {
+            books.cancel_order(market, fill.maker_order_id);
+        } else if let Some(meta) = books.orders.get_mut(&key) {
+            meta.position = position;
+        }
+    }
+    taker_position
+}
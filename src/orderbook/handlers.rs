@@ -0,0 +1,693 @@
+use serde::{Deserialize, Serialize};
+use axum::{Json, http::{StatusCode, HeaderMap}, extract::{State, Path, Query}};
+use crate::AppState;
+use crate::fhe::circuits::{balance_covers_order_circuit, dark_order_circuit, DarkOrderError};
+use crate::fhe::handle::{CiphertextHandle, FheType, HandleError};
+use crate::orderbook::dark::DarkFill;
+use crate::orderbook::clob::{Fill, PriceLevel, OrderResult, OrderOptions, SelfTradeCancel, SelfTradePrevention, TimeInForce, Visibility, now_millis};
+use crate::orderbook::sealed::{SealedOrder, RECENT_SEALED_AUCTIONS};
+use crate::liqudation::handlers::_encrypt_from_FheUint64;
+use crate::orderbook::engine::{place_order, amend_order, NewOrder, RECENT_TRADES, RECENT_AUCTIONS, RECENT_SELF_TRADES};
+use crate::orderbook::auction::{AuctionResult, MatchingMode};
+use crate::orderbook::candles::{Resolution, MAX_CANDLES};
+use crate::market::decimal::Decimal;
+use crate::market::registry::MarketConfig;
+
+const DEFAULT_DEPTH_LEVELS: usize = 20;
+const MAX_DEPTH_LEVELS: usize = 500;
+const DEFAULT_TRADES: usize = 50;
+const DEFAULT_CANDLES: usize = 500;
+
+// shapes are documented in the README under Order book API, keep them in sync
+
+#[derive(Deserialize)]
+pub struct PlaceOrderRequest {
+    pub user_id: u128,
+    #[serde(default)]
+    pub market: u32,
+    pub is_buy: bool,
+    pub price: Option<Decimal>, // left out for a market order
+    pub size: Decimal, // notional in collateral
+    pub leverage: u64,
+    #[serde(default)]
+    pub time_in_force: TimeInForce, // gtc, ioc, fok or gtt
+    pub expires_at: Option<u64>, // unix millis, gtt only
+    #[serde(default)]
+    pub post_only: bool,
+    pub reduce_only: Option<u128>, // id of a position this order may only shrink
+    pub display_size: Option<Decimal>, // makes it an iceberg showing this much at a time
+    #[serde(default)]
+    pub hidden: bool, // shows nothing in depth, fills after the displayed orders at its price
+    #[serde(default)]
+    pub self_trade: SelfTradePrevention, // cancel_newest, cancel_oldest, cancel_both or decrement_and_cancel
+}
+
+#[derive(Deserialize)]
+pub struct CancelOrderRequest {
+    pub user_id: u128,
+    #[serde(default)]
+    pub market: u32,
+    pub order_id: u64,
+}
+
+#[derive(Deserialize)]
+pub struct AmendOrderRequest {
+    pub user_id: u128,
+    #[serde(default)]
+    pub market: u32,
+    pub order_id: u64,
+    pub price: Option<Decimal>, // left out keeps the current one
+    pub size: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct TradeResponse {
+    pub maker_order_id: u64,
+    pub taker_order_id: u64,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub taker_is_buy: bool,
+    pub timestamp: u64, // unix millis
+}
+
+#[derive(Serialize)]
+pub struct SelfTradeResponse {
+    pub order_id: u64,
+    pub cancelled_size: Decimal,
+    pub remaining: Decimal,
+    pub mode: SelfTradePrevention,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize)]
+pub struct PlaceOrderResponse {
+    pub message: String,
+    pub order_id: Option<u64>,
+    pub position_id: Option<u128>, // the position this orders fills went into
+    pub fills: Vec<TradeResponse>,
+    pub resting_size: Option<Decimal>,
+    pub self_trades: Vec<SelfTradeResponse>, // orders self trade prevention cancelled or shrank, this one included
+}
+
+#[derive(Serialize)]
+pub struct CancelOrderResponse {
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct OpenOrderResponse {
+    pub market: u32,
+    pub order_id: u64,
+    pub is_buy: bool,
+    pub price: Decimal,
+    pub size: Decimal, // whats left of it
+    pub timestamp: u64,
+}
+
+#[derive(Serialize)]
+pub struct DepthLevelResponse {
+    pub price: Decimal,
+    pub size: Decimal, // summed over the level
+    pub orders: usize,
+}
+
+#[derive(Deserialize)]
+pub struct DepthQuery {
+    pub levels: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct DepthResponse {
+    pub market: u32,
+    pub bids: Vec<DepthLevelResponse>, // best first
+    pub asks: Vec<DepthLevelResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TopOfBookResponse {
+    pub market: u32,
+    pub best_bid: Option<DepthLevelResponse>,
+    pub best_ask: Option<DepthLevelResponse>,
+    pub spread: Option<Decimal>, // only when both sides have a level
+}
+
+#[derive(Deserialize)]
+pub struct TradesQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CandlesQuery {
+    pub resolution: Resolution,
+    pub from: Option<u64>, // unix millis, compared against each candles open time, both ends included
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct CandleResponse {
+    pub open_time: u64, // unix millis
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal, // notional in collateral
+    pub trades: u32,
+}
+
+#[derive(Serialize)]
+pub struct MatchingModeResponse {
+    pub market: u32,
+    pub mode: MatchingMode,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct AuctionFillResponse {
+    pub order_id: u64,
+    pub is_buy: bool,
+    pub size: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct AuctionResultResponse {
+    pub auction_id: u64,
+    pub clearing_price: Option<Decimal>,
+    pub volume: Decimal,
+    pub buy_orders: usize,
+    pub sell_orders: usize,
+    pub fills: Vec<AuctionFillResponse>,
+    pub dropped: Vec<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DarkOrderRequest {
+    pub user_id: u128,
+    #[serde(default)]
+    pub market: u32,
+    pub is_buy: bool,
+    pub price: CiphertextHandle, // FheUint64 in raw units of the markets price scale, from /encrypt or /import_ciphertext
+    pub size: CiphertextHandle,  // FheUint64 notional in raw collateral units
+    pub leverage: u64,
+}
+
+#[derive(Serialize)]
+pub struct DarkOrderResponse {
+    pub message: String,
+    pub order_id: Option<u64>,
+    pub position_id: Option<u128>, // the position the fills went into, None if nothing traded
+    pub fills: Vec<DarkFill>,
+    pub resting: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CancelDarkOrderRequest {
+    pub user_id: u128,
+    pub order_id: u64,
+}
+
+#[derive(Serialize)]
+pub struct CancelDarkOrderResponse {
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct SealedOrderRequest {
+    pub user_id: u128,
+    #[serde(default)]
+    pub market: u32,
+    pub is_buy: bool,
+    pub price: CiphertextHandle, // FheUint64 limit price in raw units of the markets price scale
+    pub size: CiphertextHandle,  // FheUint64 notional in raw collateral units
+    pub leverage: u64,
+}
+
+#[derive(Serialize)]
+pub struct SealedOrderResponse {
+    pub message: String,
+    pub order_id: Option<u64>,
+    pub clears_at: Option<u64>, // unix millis of the auction this order is in
+}
+
+#[derive(Serialize)]
+pub struct SealedAuctionResponse {
+    pub auction_id: u64,
+    pub clearing_price: Option<Decimal>,
+    pub buy_orders: usize,
+    pub sell_orders: usize,
+    pub filled_orders: usize,
+    pub timestamp: u64,
+}
+
+fn dark_order_error(message: String) -> DarkOrderResponse {
+    DarkOrderResponse { message, order_id: None, position_id: None, fills: Vec::new(), resting: false }
+}
+
+// the ciphertext has to be a FheUint64 under our key set that belongs to whoever is placing the order
+fn owned_u64(state: &AppState, key: CiphertextHandle, user_id: u128) -> Result<tfhe::FheUint64, String> {
+    key.expect(FheType::U64, state.key_set).map_err(|e| e.to_string())?;
+    let ciphertext = state.ciphertext_cache.get_ciphertext(key).ok_or(HandleError::NotFound.to_string())?;
+    if ciphertext.owner != user_id {
+        return Err("Ciphertext belongs to another user".to_string());
+    }
+    ciphertext.ciphertext.into_u64().ok_or("Not a FheUint64 ciphertext".to_string())
+}
+
+pub async fn dark_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<DarkOrderRequest>
+) -> (StatusCode, Json<DarkOrderResponse>) {
+    if !state.user_cache.user_exists(payload.user_id) {
+        return (StatusCode::NOT_FOUND, Json(dark_order_error("User not found".to_string())));
+    }
+    let leverage = match u8::try_from(payload.leverage) {
+        Ok(0) | Err(_) => return (StatusCode::BAD_REQUEST, Json(dark_order_error("Leverage must be between 1 and 255".to_string()))),
+        Ok(leverage) => leverage,
+    };
+    if state.markets.get(payload.market).is_none() {
+        return (StatusCode::BAD_REQUEST, Json(dark_order_error("Unknown market".to_string())));
+    }
+    let price = match owned_u64(&state, payload.price, payload.user_id) {
+        Ok(price) => price,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(dark_order_error(e))),
+    };
+    let size = match owned_u64(&state, payload.size, payload.user_id) {
+        Ok(size) => size,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(dark_order_error(e))),
+    };
+    // like check_balance for CLOB orders, the whole order has to be paid for before it can take anything off the book
+    match balance_covers_order_circuit(&state, payload.user_id, size.clone(), leverage).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::BAD_REQUEST, Json(dark_order_error("Insufficient balance for margin and opening fee".to_string()))),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(dark_order_error(e.to_string()))),
+    }
+    match dark_order_circuit(&state, payload.user_id, payload.market, payload.is_buy, leverage, price, size).await {
+        Ok(outcome) => (StatusCode::OK, Json(DarkOrderResponse {
+            message: format!("Order matched against {} orders", outcome.fills.len()),
+            order_id: Some(outcome.order_id),
+            position_id: outcome.position,
+            fills: outcome.fills,
+            resting: outcome.resting,
+        })),
+        Err(e) if e.downcast_ref::<DarkOrderError>().is_some() => (StatusCode::BAD_REQUEST, Json(dark_order_error(e.to_string()))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(dark_order_error(e.to_string()))),
+    }
+}
+
+pub async fn cancel_dark_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CancelDarkOrderRequest>
+) -> (StatusCode, Json<CancelDarkOrderResponse>) {
+    let mut book = state.dark_book.write().await;
+    match book.get_order(payload.order_id) {
+        Some(order) if order.owner == payload.user_id => {
+            book.remove_order(payload.order_id);
+            (StatusCode::OK, Json(CancelDarkOrderResponse { message: "Order cancelled".to_string() }))
+        }
+        _ => (StatusCode::NOT_FOUND, Json(CancelDarkOrderResponse { message: "Order not found".to_string() })),
+    }
+}
+
+fn sealed_order_error(message: String) -> SealedOrderResponse {
+    SealedOrderResponse { message, order_id: None, clears_at: None }
+}
+
+// the order waits for the next auction. its ciphertexts are copied under handles the auction owns, so nothing the
+// client does with its own handles changes a bid once its in. the balance has to cover margin and fee for the whole
+// encrypted size, the minimum notional cant be checked on it
+pub async fn sealed_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SealedOrderRequest>
+) -> (StatusCode, Json<SealedOrderResponse>) {
+    if !state.user_cache.user_exists(payload.user_id) {
+        return (StatusCode::NOT_FOUND, Json(sealed_order_error("User not found".to_string())));
+    }
+    let leverage = match u8::try_from(payload.leverage) {
+        Ok(0) | Err(_) => return (StatusCode::BAD_REQUEST, Json(sealed_order_error("Leverage must be between 1 and 255".to_string()))),
+        Ok(leverage) => leverage,
+    };
+    if state.markets.get(payload.market).is_none() {
+        return (StatusCode::BAD_REQUEST, Json(sealed_order_error("Unknown market".to_string())));
+    }
+    let price = match owned_u64(&state, payload.price, payload.user_id) {
+        Ok(price) => price,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(sealed_order_error(e))),
+    };
+    let size = match owned_u64(&state, payload.size, payload.user_id) {
+        Ok(size) => size,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(sealed_order_error(e))),
+    };
+    // a bid that couldnt pay for a full fill would still move the clearing price and take a share of the fills
+    match balance_covers_order_circuit(&state, payload.user_id, size.clone(), leverage).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::BAD_REQUEST, Json(sealed_order_error("Insufficient balance for margin and opening fee".to_string()))),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(sealed_order_error(e.to_string()))),
+    }
+    let mut auctions = state.sealed_auctions.lock().await;
+    if auctions.is_full(payload.market) {
+        return (StatusCode::CONFLICT, Json(sealed_order_error("Auction is full, try again after it clears".to_string())));
+    }
+    let order_id = auctions.next_id();
+    auctions.add_order(SealedOrder {
+        id: order_id,
+        owner: payload.user_id,
+        market: payload.market,
+        is_buy: payload.is_buy,
+        price: _encrypt_from_FheUint64(State(state.clone()), price, payload.user_id).await,
+        size: _encrypt_from_FheUint64(State(state.clone()), size, payload.user_id).await,
+        leverage,
+        timestamp: now_millis(),
+    });
+    (StatusCode::OK, Json(SealedOrderResponse {
+        message: "Order sealed".to_string(),
+        order_id: Some(order_id),
+        clears_at: Some(auctions.next_clear_at),
+    }))
+}
+
+pub async fn cancel_sealed_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CancelDarkOrderRequest>
+) -> (StatusCode, Json<CancelDarkOrderResponse>) {
+    let mut auctions = state.sealed_auctions.lock().await;
+    match auctions.get_order(payload.order_id) {
+        Some(order) if order.owner == payload.user_id => {
+            auctions.remove_order(payload.order_id);
+            (StatusCode::OK, Json(CancelDarkOrderResponse { message: "Order cancelled".to_string() }))
+        }
+        _ => (StatusCode::NOT_FOUND, Json(CancelDarkOrderResponse { message: "Order not found".to_string() })),
+    }
+}
+
+// newest first
+pub async fn sealed_auctions_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<TradesQuery>
+) -> Result<(StatusCode, Json<Vec<SealedAuctionResponse>>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES).min(RECENT_SEALED_AUCTIONS);
+    let results = state.sealed_auctions.lock().await.recent_results(market_id, limit);
+    Ok((StatusCode::OK, Json(results.iter().map(|result| SealedAuctionResponse {
+        auction_id: result.auction_id,
+        clearing_price: result.clearing_price.map(|price| market.price_decimal(price)),
+        buy_orders: result.buy_orders,
+        sell_orders: result.sell_orders,
+        filled_orders: result.filled_orders,
+        timestamp: result.timestamp,
+    }).collect())))
+}
+
+// checks everything about an order that doesnt need the book, returns (price, size, leverage) in raw units.
+// reduce only orders can be below the minimum so a position can always be closed out completely
+pub async fn validate_order(state: &AppState, user_id: u128, market: u32, price: Option<&Decimal>, size: &Decimal, leverage: u64, reduce_only: bool) -> Result<(Option<u64>, u64, u8), (StatusCode, String)> {
+    match state.user_cache.get_user(user_id) {
+        Some(user) if user.lock().await.balance.is_some() => {}
+        Some(_) => return Err((StatusCode::BAD_REQUEST, "No balance".to_string())),
+        None => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
+    }
+    // leverage fits in a byte, keeping it as FheUint8 makes every multiplication by it a lot cheaper
+    let leverage = match u8::try_from(leverage) {
+        Ok(0) | Err(_) => return Err((StatusCode::BAD_REQUEST, "Leverage must be between 1 and 255".to_string())),
+        Ok(leverage) => leverage,
+    };
+    let market = state.markets.get(market).ok_or((StatusCode::BAD_REQUEST, "Unknown market".to_string()))?;
+    let price = price.map(|price| market.price(price)).transpose().map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let size = state.markets.collateral(size).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if size < market.min_notional && !reduce_only {
+        return Err((StatusCode::BAD_REQUEST, format!("Notional is below the market minimum of {}", state.markets.collateral_decimal(market.min_notional))));
+    }
+    Ok((price, size, leverage))
+}
+
+fn trade_response(state: &AppState, market: &MarketConfig, fill: &Fill) -> TradeResponse {
+    TradeResponse {
+        maker_order_id: fill.maker_order_id,
+        taker_order_id: fill.taker_order_id,
+        price: market.price_decimal(fill.price),
+        size: state.markets.collateral_decimal(fill.size),
+        taker_is_buy: fill.taker_is_buy,
+        timestamp: fill.timestamp,
+    }
+}
+
+fn self_trade_response(state: &AppState, event: &SelfTradeCancel) -> SelfTradeResponse {
+    SelfTradeResponse {
+        order_id: event.order_id,
+        cancelled_size: state.markets.collateral_decimal(event.cancelled_size),
+        remaining: state.markets.collateral_decimal(event.remaining),
+        mode: event.mode,
+        timestamp: event.timestamp,
+    }
+}
+
+fn level_response(state: &AppState, market: &MarketConfig, level: &PriceLevel) -> DepthLevelResponse {
+    DepthLevelResponse {
+        price: market.price_decimal(level.price),
+        size: state.markets.collateral_decimal(level.displayed_size()),
+        orders: level.displayed_orders(),
+    }
+}
+
+fn order_error(message: String) -> PlaceOrderResponse {
+    PlaceOrderResponse { message, order_id: None, position_id: None, fills: Vec::new(), resting_size: None, self_trades: Vec::new() }
+}
+
+fn order_response(state: &AppState, market: &MarketConfig, result: OrderResult, position_id: Option<u128>) -> PlaceOrderResponse {
+    PlaceOrderResponse {
+        message: format!("Order placed, {} fills", result.fills.len()),
+        order_id: Some(result.order_id),
+        position_id,
+        fills: result.fills.iter().map(|fill| trade_response(state, market, fill)).collect(),
+        resting_size: Some(state.markets.collateral_decimal(result.resting_size)),
+        self_trades: result.self_trades.iter().map(|event| self_trade_response(state, event)).collect(),
+    }
+}
+
+pub async fn place_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<PlaceOrderRequest>
+) -> (StatusCode, Json<PlaceOrderResponse>) {
+    let (price, size, leverage) = match validate_order(&state, payload.user_id, payload.market, payload.price.as_ref(), &payload.size, payload.leverage, payload.reduce_only.is_some()).await {
+        Ok(order) => order,
+        Err((status, message)) => return (status, Json(order_error(message))),
+    };
+    let market = state.markets.get(payload.market).unwrap();
+    let visibility = match (payload.display_size.as_ref(), payload.hidden) {
+        (Some(_), true) => return (StatusCode::BAD_REQUEST, Json(order_error("An order is either an iceberg or hidden, not both".to_string()))),
+        (Some(display_size), false) => match state.markets.collateral(display_size) {
+            Ok(display) => Visibility::Iceberg { display },
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(order_error(e.to_string()))),
+        },
+        (None, true) => Visibility::Hidden,
+        (None, false) => Visibility::Displayed,
+    };
+    let order = NewOrder {
+        user_id: payload.user_id,
+        market: payload.market,
+        price,
+        size,
+        is_buy: payload.is_buy,
+        leverage,
+        options: OrderOptions { time_in_force: payload.time_in_force, expires_at: payload.expires_at, post_only: payload.post_only, visibility, self_trade: payload.self_trade },
+        reduce_only: payload.reduce_only,
+    };
+    match place_order(&state, order).await {
+        Ok((result, position_id)) => (StatusCode::OK, Json(order_response(&state, market, result, position_id))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(order_error(e))),
+    }
+}
+
+pub async fn cancel_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CancelOrderRequest>
+) -> (StatusCode, Json<CancelOrderResponse>) {
+    let mut books = state.order_books.lock().await;
+    let owned = books.get_order(payload.market, payload.order_id)
+        .is_some_and(|order| order.owner == payload.user_id);
+    if !owned {
+        return (StatusCode::NOT_FOUND, Json(CancelOrderResponse { message: "Order not found".to_string() }));
+    }
+    books.cancel_order(payload.market, payload.order_id);
+    (StatusCode::OK, Json(CancelOrderResponse { message: "Order cancelled".to_string() }))
+}
+
+pub async fn amend_order_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<AmendOrderRequest>
+) -> (StatusCode, Json<PlaceOrderResponse>) {
+    let Some(market) = state.markets.get(payload.market) else {
+        return (StatusCode::BAD_REQUEST, Json(order_error("Unknown market".to_string())));
+    };
+    let price = match payload.price.as_ref().map(|price| market.price(price)).transpose() {
+        Ok(price) => price,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(order_error(e.to_string()))),
+    };
+    let size = match payload.size.as_ref().map(|size| state.markets.collateral(size)).transpose() {
+        Ok(size) => size,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(order_error(e.to_string()))),
+    };
+    if size.is_some_and(|size| size < market.min_notional) {
+        return (StatusCode::BAD_REQUEST, Json(order_error(format!("Notional is below the market minimum of {}", state.markets.collateral_decimal(market.min_notional)))));
+    }
+    match amend_order(&state, payload.user_id, payload.market, payload.order_id, price, size).await {
+        Ok((result, position_id)) => (StatusCode::OK, Json(order_response(&state, market, result, position_id))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(order_error(e))),
+    }
+}
+
+pub async fn open_orders_handler(
+    State(state): State<AppState>,
+    Path(user_id): Path<u128>
+) -> (StatusCode, Json<Vec<OpenOrderResponse>>) {
+    let books = state.order_books.lock().await;
+    let mut orders = Vec::new();
+    for market in state.markets.get_all() {
+        orders.extend(books.get_orders_for(market.id, user_id).into_iter().map(|order| OpenOrderResponse {
+            market: market.id,
+            order_id: order.id,
+            is_buy: order.is_buy,
+            price: market.price_decimal(order.price),
+            size: state.markets.collateral_decimal(order.size),
+            timestamp: order.timestamp,
+        }));
+    }
+    (StatusCode::OK, Json(orders))
+}
+
+pub async fn depth_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<DepthQuery>
+) -> Result<(StatusCode, Json<DepthResponse>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let levels = query.levels.unwrap_or(DEFAULT_DEPTH_LEVELS).min(MAX_DEPTH_LEVELS);
+    let books = state.order_books.lock().await;
+    let book = books.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let side = |is_buy: bool| book.get_displayed_levels(is_buy, levels).into_iter().map(|level| level_response(&state, market, level)).collect();
+    Ok((StatusCode::OK, Json(DepthResponse { market: market_id, bids: side(true), asks: side(false) })))
+}
+
+pub async fn top_of_book_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>
+) -> Result<(StatusCode, Json<TopOfBookResponse>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let books = state.order_books.lock().await;
+    let book = books.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let best = |is_buy: bool| book.get_displayed_levels(is_buy, 1).into_iter().next();
+    let (best_bid, best_ask) = (best(true), best(false));
+    let spread = match (best_bid, best_ask) {
+        (Some(bid), Some(ask)) => Some(Decimal::from_signed_raw(ask.price as i128 - bid.price as i128, market.price_decimals)),
+        _ => None,
+    };
+    Ok((StatusCode::OK, Json(TopOfBookResponse {
+        market: market_id,
+        best_bid: best_bid.map(|level| level_response(&state, market, level)),
+        best_ask: best_ask.map(|level| level_response(&state, market, level)),
+        spread,
+    })))
+}
+
+pub async fn trades_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<TradesQuery>
+) -> Result<(StatusCode, Json<Vec<TradeResponse>>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES).min(RECENT_TRADES);
+    let trades = state.order_books.lock().await.recent_trades(market_id, limit);
+    Ok((StatusCode::OK, Json(trades.iter().map(|fill| trade_response(&state, market, fill)).collect())))
+}
+
+// oldest first, the way charts want them. buckets nothing traded in are left out
+pub async fn candles_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<CandlesQuery>
+) -> Result<(StatusCode, Json<Vec<CandleResponse>>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(u64::MAX);
+    if from > to {
+        return Err((StatusCode::BAD_REQUEST, "from has to be before to".to_string()));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_CANDLES).min(MAX_CANDLES);
+    let candles = state.order_books.lock().await.candles(market_id, query.resolution, from, to, limit);
+    Ok((StatusCode::OK, Json(candles.iter().map(|candle| CandleResponse {
+        open_time: candle.open_time,
+        open: market.price_decimal(candle.open),
+        high: market.price_decimal(candle.high),
+        low: market.price_decimal(candle.low),
+        close: market.price_decimal(candle.close),
+        volume: state.markets.collateral_decimal(candle.volume),
+        trades: candle.trades,
+    }).collect())))
+}
+
+// newest first. owners arent in here, traders find their own cancels by order id
+pub async fn self_trades_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<TradesQuery>
+) -> Result<(StatusCode, Json<Vec<SelfTradeResponse>>), (StatusCode, String)> {
+    state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES).min(RECENT_SELF_TRADES);
+    let events = state.order_books.lock().await.recent_self_trades(market_id, limit);
+    Ok((StatusCode::OK, Json(events.iter().map(|event| self_trade_response(&state, event)).collect())))
+}
+
+pub async fn get_matching_mode_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>
+) -> Result<(StatusCode, Json<MatchingModeResponse>), (StatusCode, String)> {
+    state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let mode = state.order_books.lock().await.get_mode(market_id);
+    Ok((StatusCode::OK, Json(MatchingModeResponse { market: market_id, mode, message: String::new() })))
+}
+
+// operator only. without ADMIN_TOKEN set at startup nobody can change a markets mode, it stays continuous
+pub async fn set_matching_mode_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    headers: HeaderMap,
+    Json(mode): Json<MatchingMode>
+) -> (StatusCode, Json<MatchingModeResponse>) {
+    let mut books = state.order_books.lock().await;
+    if let Err((status, message)) = state.check_admin(&headers) {
+        return (status, Json(MatchingModeResponse { market: market_id, mode: books.get_mode(market_id), message }));
+    }
+    match books.set_mode(market_id, mode) {
+        Ok(()) => (StatusCode::OK, Json(MatchingModeResponse { market: market_id, mode, message: "Matching mode updated".to_string() })),
+        Err(e) => (StatusCode::CONFLICT, Json(MatchingModeResponse { market: market_id, mode: books.get_mode(market_id), message: e })),
+    }
+}
+
+fn auction_response(state: &AppState, market: &MarketConfig, result: &AuctionResult) -> AuctionResultResponse {
+    AuctionResultResponse {
+        auction_id: result.auction_id,
+        clearing_price: result.clearing_price.map(|price| market.price_decimal(price)),
+        volume: state.markets.collateral_decimal(result.volume),
+        buy_orders: result.buy_orders,
+        sell_orders: result.sell_orders,
+        fills: result.fills.iter().map(|fill| AuctionFillResponse {
+            order_id: fill.order_id,
+            is_buy: fill.is_buy,
+            size: state.markets.collateral_decimal(fill.size),
+        }).collect(),
+        dropped: result.dropped.clone(),
+        timestamp: result.timestamp,
+    }
+}
+
+pub async fn auctions_handler(
+    State(state): State<AppState>,
+    Path(market_id): Path<u32>,
+    Query(query): Query<TradesQuery>
+) -> Result<(StatusCode, Json<Vec<AuctionResultResponse>>), (StatusCode, String)> {
+    let market = state.markets.get(market_id).ok_or((StatusCode::NOT_FOUND, "Unknown market".to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES).min(RECENT_AUCTIONS);
+    let results = state.order_books.lock().await.recent_auctions(market_id, limit);
+    Ok((StatusCode::OK, Json(results.iter().map(|result| auction_response(&state, market, result)).collect())))
+}
@@ -0,0 +1,9 @@
+pub mod clob;
+pub mod dark;
+pub mod engine;
+pub mod auction;
+pub mod sealed;
+pub mod candles;
+#[cfg(feature = "bench-endpoints")]
+pub mod bench;
+pub mod handlers;
\ No newline at end of file
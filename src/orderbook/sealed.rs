@@ -0,0 +1,174 @@
+use std::collections::{HashMap, VecDeque};
+use serde::Serialize;
+use tfhe::{FheBool, FheUint64, FheUint128};
+use tfhe::prelude::*;
+use crate::fhe::handle::CiphertextHandle;
+
+pub const SEALED_AUCTION_INTERVAL_MS: u64 = 5_000;
+pub const MAX_SEALED_ORDERS: usize = 32; // per market and auction, clearing is quadratic in the number of orders
+pub const RECENT_SEALED_AUCTIONS: usize = 100;
+
+// a sealed bid. side, market, owner and leverage are public, price and size are ciphertexts the book owns
+#[derive(Debug, Clone, Serialize)]
+pub struct SealedOrder {
+    pub id: u64,
+    pub owner: u128,
+    pub market: u32,
+    pub is_buy: bool,
+    pub price: CiphertextHandle,
+    pub size: CiphertextHandle,
+    pub leverage: u8,
+    pub timestamp: u64,
+}
+
+// the public record of an auction. only the clearing price is revealed, fill sizes go to whoever owns the order
+#[derive(Debug, Clone, Serialize)]
+pub struct SealedAuctionResult {
+    pub auction_id: u64,
+    pub clearing_price: Option<u64>, // None when nothing crossed
+    pub buy_orders: usize,
+    pub sell_orders: usize,
+    pub filled_orders: usize,
+    pub timestamp: u64,
+}
+
+// every market clears on the same cadence, an auction only runs for markets that collected orders.
+// orders are one shot, whatever doesnt fill is dropped when its auction clears
+pub struct SealedAuctions {
+    pub next_clear_at: u64,
+    next_order_id: u64,
+    next_auction_id: u64,
+    orders: HashMap<u32, Vec<SealedOrder>>, // arrival order
+    results: HashMap<u32, VecDeque<SealedAuctionResult>>,
+}
+
+impl SealedAuctions {
+    pub fn new(now: u64) -> Self {
+        Self {
+            next_clear_at: now + SEALED_AUCTION_INTERVAL_MS,
+            next_order_id: 1,
+            next_auction_id: 1,
+            orders: HashMap::new(),
+            results: HashMap::new(),
+        }
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        id
+    }
+
+    pub fn is_full(&self, market: u32) -> bool {
+        self.orders.get(&market).is_some_and(|orders| orders.len() >= MAX_SEALED_ORDERS)
+    }
+
+    pub fn add_order(&mut self, order: SealedOrder) {
+        self.orders.entry(order.market).or_default().push(order);
+    }
+
+    pub fn get_order(&self, id: u64) -> Option<&SealedOrder> {
+        self.orders.values().flatten().find(|order| order.id == id)
+    }
+
+    pub fn remove_order(&mut self, id: u64) -> Option<SealedOrder> {
+        for orders in self.orders.values_mut() {
+            if let Some(index) = orders.iter().position(|order| order.id == id) {
+                return Some(orders.remove(index));
+            }
+        }
+        None
+    }
+
+    // hands out everything collected so far, one (auction id, market, orders) per market that has orders
+    pub fn take_batches(&mut self, now: u64) -> Vec<(u64, u32, Vec<SealedOrder>)> {
+        self.next_clear_at = now + SEALED_AUCTION_INTERVAL_MS;
+        let mut batches = Vec::new();
+        for (market, orders) in self.orders.drain() {
+            if orders.is_empty() {
+                continue;
+            }
+            batches.push((self.next_auction_id, market, orders));
+            self.next_auction_id += 1;
+        }
+        batches
+    }
+
+    pub fn record_result(&mut self, market: u32, result: SealedAuctionResult) {
+        let results = self.results.entry(market).or_default();
+        results.push_back(result);
+        while results.len() > RECENT_SEALED_AUCTIONS {
+            results.pop_front();
+        }
+    }
+
+    pub fn recent_results(&self, market: u32, limit: usize) -> Vec<SealedAuctionResult> {
+        self.results.get(&market)
+            .map(|results| results.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // for the ciphertext collector, waiting orders keep their price and size alive
+    pub fn ciphertext_keys(&self) -> Vec<CiphertextHandle> {
+        self.orders.values().flatten().flat_map(|order| [order.price, order.size]).collect()
+    }
+}
+
+// demand and supply at a price, summed over ciphertexts. a buy counts if it bids at least the price, a sell if it
+// asks at most the price
+fn curves_at(orders: &[(bool, FheUint64, FheUint64)], accepts: impl Fn(bool, &FheUint64) -> FheBool) -> (FheUint64, FheUint64) {
+    let zero = FheUint64::encrypt_trivial(0u64);
+    let mut demand = zero.clone();
+    let mut supply = zero.clone();
+    for (is_buy, price, size) in orders {
+        let eligible = accepts(*is_buy, price).if_then_else(size, &zero);
+        if *is_buy { demand += &eligible } else { supply += &eligible }
+    }
+    (demand, supply)
+}
+
+// every limit price in the auction is a candidate. for each one the demand and supply curves are evaluated
+// homomorphically and the candidate with the most volume wins, then the one with the smallest imbalance, then the
+// earliest order. returns the clearing price and whether anything trades, both still encrypted. runs on an fhe worker
+pub fn sealed_clearing_price(orders: &[(bool, FheUint64, FheUint64)]) -> (FheUint64, FheBool) {
+    let mut best_price = FheUint64::encrypt_trivial(0u64);
+    let mut best_volume = FheUint64::encrypt_trivial(0u64);
+    let mut best_imbalance = FheUint64::encrypt_trivial(u64::MAX);
+    for (_, candidate, _) in orders {
+        let (demand, supply) = curves_at(orders, |is_buy, price| if is_buy { price.ge(candidate) } else { price.le(candidate) });
+        let volume = demand.min(&supply);
+        let imbalance = &demand.max(&supply) - &volume;
+        let is_better = volume.gt(&best_volume) | (volume.eq(&best_volume) & imbalance.lt(&best_imbalance));
+        best_price = is_better.if_then_else(candidate, &best_price);
+        best_volume = is_better.if_then_else(&volume, &best_volume);
+        best_imbalance = is_better.if_then_else(&imbalance, &best_imbalance);
+    }
+    let crossed = best_volume.gt(0u64);
+    (best_price, crossed)
+}
+
+// fills at a known clearing price. the volume is min(demand, supply) and every eligible order gets
+// size * volume / its sides eligible size, so the short side fills completely and the long side is shared pro rata
+// without revealing which side was which. shares round down, the rationed side can come up a few raw units short
+pub fn sealed_fills(orders: &[(bool, FheUint64, FheUint64)], clearing_price: u64) -> Vec<FheUint64> {
+    let zero = FheUint64::encrypt_trivial(0u64);
+    let eligible: Vec<FheUint64> = orders.iter()
+        .map(|(is_buy, price, size)| {
+            let accepts = if *is_buy { price.ge(clearing_price) } else { price.le(clearing_price) };
+            accepts.if_then_else(size, &zero)
+        })
+        .collect();
+    let (mut demand, mut supply) = (zero.clone(), zero.clone());
+    for ((is_buy, _, _), size) in orders.iter().zip(eligible.iter()) {
+        if *is_buy { demand += size } else { supply += size }
+    }
+    let volume: FheUint128 = demand.min(&supply).cast_into();
+    // an empty side only happens when nothing crossed, keep the division defined anyway
+    let demand: FheUint128 = demand.max(1u64).cast_into();
+    let supply: FheUint128 = supply.max(1u64).cast_into();
+    orders.iter().zip(eligible).map(|((is_buy, _, _), size)| {
+        let wide_size: FheUint128 = size.cast_into();
+        let side = if *is_buy { &demand } else { &supply };
+        ((wide_size * &volume) / side).cast_into()
+    }).collect()
+}